@@ -0,0 +1,65 @@
+#![allow(clippy::manual_div_ceil)]
+extern crate rand;
+#[macro_use]
+extern crate criterion;
+extern crate fragmentos;
+
+use std::time::{Duration, Instant};
+
+use rand::{StdRng, Rng};
+use criterion::Criterion;
+
+use fragmentos::{split_data, unite_data, Fragmenter, CompatRng, Reassembler, ReassemblerEvent};
+
+
+fn bench_unite_data(c: &mut Criterion) {
+    let seed: &[_] = &[1,2,3,4,5];
+    let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+    let mut my_data = vec![0; 2500];
+    rng.fill_bytes(&mut my_data);
+
+    let b: usize = 5;
+    let data_shares = split_data(&my_data, b as u8).unwrap();
+
+    // Use the last b shares, so that the missing data shares are reconstructed from parity:
+    c.bench_function("unite_data", move |bencher| {
+        bencher.iter(|| unite_data(&data_shares[b - 1 ..]).unwrap())
+    });
+}
+
+fn bench_unite_message(c: &mut Criterion) {
+    let seed: &[_] = &[1,2,3,4,5];
+    let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
+    let mut orig_message = vec![0; 4096];
+    rng.fill_bytes(&mut orig_message);
+
+    // The message fits inside a single block:
+    let mut fragmenter = Fragmenter::new(200, CompatRng(rng));
+    let dgrams = fragmenter.fragment(&orig_message).unwrap();
+    assert!(dgrams.len() > 1);
+
+    // Only b datagrams are needed to reconstruct the message. The last b datagrams are used, so
+    // that the missing data shares are reconstructed from parity:
+    let b = (dgrams.len() + 1) / 2;
+    let now = Instant::now();
+
+    c.bench_function("unite_message", move |bencher| {
+        bencher.iter(|| {
+            let mut reassembler = Reassembler::new(1 << 16, Duration::from_secs(1));
+            let mut opt_msg = None;
+            for dgram in &dgrams[dgrams.len() - b ..] {
+                for event in reassembler.received(dgram, now) {
+                    if let ReassemblerEvent::Message(msg) = event {
+                        opt_msg = Some(msg);
+                    }
+                }
+            }
+            let msg = opt_msg.unwrap();
+            assert_eq!(msg, orig_message);
+            msg
+        })
+    });
+}
+
+criterion_group!(benches, bench_unite_data, bench_unite_message);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+extern crate fragmentos;
+
+use std::env;
+
+use fragmentos::sim::LossModel;
+use fragmentos::delivery::{analytic_delivery, monte_carlo_delivery, Redundancy,
+    DeliveryEstimate, DeliveryError};
+
+const NUM_TRIALS: usize = 10000;
+
+
+fn usage() -> ! {
+    println!("Usage: delivery_calc <message_len> <max_dgram_len> <loss> [burst_len]");
+    println!("Datagrams are lost with probability loss. If burst_len is given, losses come in");
+    println!("bursts of that average length (Gilbert-Elliott model).");
+    ::std::process::exit(1);
+}
+
+fn print_estimate(name: &str, res_estimate: Result<DeliveryEstimate, DeliveryError>) {
+    match res_estimate {
+        Ok(estimate) => println!("{:>12} {:>10.6} {:>8} {:>10} {:>9.3} {:>8.4}",
+                                 name, estimate.delivery_rate, estimate.dgrams_sent,
+                                 estimate.bytes_sent, estimate.overhead, estimate.goodput),
+        Err(DeliveryError::NoAnalyticModel) => println!("{:>12} {:>10}", name, "-"),
+        Err(e) => {
+            println!("Invalid arguments: {:?}", e);
+            usage();
+        },
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 3 {
+        usage();
+    }
+    let m_len: usize = args[0].parse().unwrap_or_else(|_| usage());
+    let max_dgram_len: usize = args[1].parse().unwrap_or_else(|_| usage());
+    let loss: f64 = args[2].parse().unwrap_or_else(|_| usage());
+    if !(0.0 ..= 1.0).contains(&loss) {
+        usage();
+    }
+
+    let loss_model = match args.get(3) {
+        None => LossModel::Bernoulli { p: loss },
+        Some(burst_len) => {
+            let burst_len: f64 = burst_len.parse().unwrap_or_else(|_| usage());
+            // Bursts are at least a single datagram long, and some datagrams must arrive
+            // between them:
+            if burst_len < 1.0 || loss >= 1.0 {
+                usage();
+            }
+            // All the datagrams are lost in the bad state. The stationary probability of the
+            // bad state is p_good_to_bad / (p_good_to_bad + p_bad_to_good) = loss:
+            let p_bad_to_good = 1.0 / burst_len;
+            LossModel::GilbertElliott {
+                p_good_to_bad: p_bad_to_good * loss / (1.0 - loss),
+                p_bad_to_good,
+                loss_good: 0.0,
+                loss_bad: 1.0,
+            }
+        },
+    };
+
+    println!("message_len = {}, max_dgram_len = {}, loss model = {:?}",
+             m_len, max_dgram_len, loss_model);
+    println!("{:>12} {:>10} {:>8} {:>10} {:>9} {:>8}",
+             "", "delivery", "dgrams", "bytes", "overhead", "goodput");
+
+    let seed: &[_] = &[1,2,3,4,5];
+    let policies = [("full", Redundancy::Full),
+                    ("extra 1", Redundancy::Extra(1)),
+                    ("no extra", Redundancy::Extra(0))];
+    for &(name, redundancy) in &policies {
+        println!("{}:", name);
+        print_estimate("analytic",
+                       analytic_delivery(m_len, max_dgram_len, redundancy, &loss_model));
+        print_estimate("monte carlo",
+                       monte_carlo_delivery(m_len, max_dgram_len, redundancy, &loss_model,
+                                            NUM_TRIALS, seed));
+    }
+}
@@ -3,13 +3,14 @@ extern crate futures;
 extern crate tokio_core;
 extern crate fragmentos;
 
+use std::env;
 use std::time::Duration;
 
 use rand::{StdRng, Rng};
 use futures::{stream, Future, Stream, Sink};
-use futures::sync::mpsc;
 use fragmentos::FragMsgReceiver;
 use fragmentos::FragMsgSender;
+use fragmentos::sim::{sim_channel, SimConfig, LossModel};
 
 use self::tokio_core::reactor::{Core, Interval};
 
@@ -20,6 +21,9 @@ const MESSAGE_SIZE: usize = 1 << 12;
 const MAX_DGRAM_LEN: usize = 500;
 
 fn main() {
+    // Probability of losing a datagram on the way:
+    let loss: f64 = env::args().nth(1).map(|s| s.parse().unwrap()).unwrap_or(0.0);
+
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
@@ -27,8 +31,11 @@ fn main() {
     let sender_rng: StdRng = rand::SeedableRng::from_seed(seed);
 
     // We send tuples of (message_bytes, address)
-    // let (sink, stream) = sink_stream_pair::<(Vec<u8>, u32)>(1);
-    let (sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    let config = SimConfig {
+        loss: LossModel::Bernoulli { p: loss },
+        ..SimConfig::default()
+    };
+    let (sink, stream) = sim_channel::<u32>(config, seed, &handle);
 
     let frag_sender = FragMsgSender::new(sink, MAX_DGRAM_LEN, sender_rng);
 
@@ -36,7 +43,7 @@ fn main() {
         .unwrap()
         .map_err(|_| ());
 
-    let frag_receiver = FragMsgReceiver::new(stream, time_receiver);
+    let frag_receiver = FragMsgReceiver::with_max_total_message(stream, time_receiver, MESSAGE_SIZE);
 
     let seed: &[_] = &[1,2,3,4,5];
     let mut msg_rng: StdRng = rand::SeedableRng::from_seed(seed);
@@ -67,6 +74,9 @@ fn main() {
         core.run(keep_messages).unwrap();
     }
 
-    assert_eq!(num_incoming, NUM_MESSAGES);
+    println!("Received {} out of {} messages", num_incoming, NUM_MESSAGES);
+    if loss == 0.0 {
+        assert_eq!(num_incoming, NUM_MESSAGES);
+    }
 
 }
@@ -1,3 +1,5 @@
+// Kept as written before clippy was run in CI:
+#![allow(clippy::needless_bool, clippy::clone_on_copy, clippy::identity_op)]
 extern crate rand;
 extern crate futures;
 extern crate tokio_core;
@@ -18,13 +20,10 @@ use futures::{Stream, Sink, Future, Poll, Async};
 // use futures::stream::IterOk;
 use futures::{future, IntoFuture};
 
-use tokio_core::net::{UdpSocket};
 use tokio_core::reactor;
-use tokio_core::reactor::{Core, Interval};
+use tokio_core::reactor::{Core};
 
-use fragmentos::{FragMsgReceiver, FragMsgSender, max_message};
-use fragmentos::rate_limit::rate_limit_channel;
-use fragmentos::utils::DgramCodec;
+use fragmentos::{FragSocket, FragSocketConfig, RateLimitConfig, max_message};
 
 // Multiplier for the calculation of rate limit buffer:
 const RATE_LIMIT_BUFF_MULT: usize = 16;
@@ -158,11 +157,6 @@ fn main() {
 
     let client_addr = "0.0.0.0:0".parse().unwrap();
 
-    let socket = UdpSocket::bind(&client_addr, &handle).unwrap();
-    let dgram_codec = DgramCodec;
-    let (sink, stream) = socket.framed(dgram_codec).split();
-
-
     println!("max_message = {}", max_message(max_dgram_len).unwrap());
 
     let msg_stream = MsgStream {
@@ -175,28 +169,19 @@ fn main() {
 
 
     let queue_len = (max_message(max_dgram_len).unwrap() / max_dgram_len) * RATE_LIMIT_BUFF_MULT;
-    let (rl_sender, rl_receiver) = rate_limit_channel(queue_len, 16, &handle);
-    handle.spawn(
-        sink.sink_map_err(|_| ())
-            .send_all(rl_receiver)
-            .then(|_| Ok(()))
-    );
-
-    let frag_sender = FragMsgSender::new(rl_sender,
-                                         max_dgram_len, 
-                                         rand::thread_rng());
-
-    /*
-    let frag_sender = FragMsgSender::new(sink,
-                                         max_dgram_len, 
-                                         rand::thread_rng());
-    */
-
-    let time_receiver = Interval::new(Duration::new(1,0), &handle)
-        .unwrap()
-        .map_err(|_| ());
-
-    let frag_receiver = FragMsgReceiver::new(stream, time_receiver);
+    let config = FragSocketConfig {
+        max_dgram_len,
+        max_total_message: MAX_FRAG_MSG_LEN,
+        tick_duration: Duration::new(1,0),
+        rate_limit: Some(RateLimitConfig {
+            queue_len,
+            min_tokens_per_ms: 16,
+        }),
+        pacing: None,
+        path_mtu: None,
+    };
+    let frag_socket = FragSocket::bind(&client_addr, config, &handle).unwrap();
+    let (frag_sender, frag_receiver) = frag_socket.split();
 
     // Add some delay to the message stream:
     let chandle = handle.clone();
@@ -209,7 +194,7 @@ fn main() {
                           .and_then(move |timeout| timeout.and_then(move |_| Ok(item)))
             });
 
-    let send_all = frag_sender.send_all(msg_stream.map_err(|_| panic!("Error!")))
+    let send_all = frag_sender.send_all(msg_stream)
         .then(|_| Ok(()));
 
     // Messages sender:
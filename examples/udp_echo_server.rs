@@ -1,4 +1,3 @@
-extern crate rand;
 extern crate futures;
 extern crate tokio_core;
 
@@ -6,22 +5,18 @@ extern crate fragmentos;
 
 use std::net::SocketAddr;
 use std::{env};
-use std::time::Duration;
 
 use futures::{Future, Stream, Sink};
 
-use tokio_core::net::{UdpSocket};
-use tokio_core::reactor::{Core, Interval};
+use tokio_core::reactor::{Core};
 
-use fragmentos::{FragMsgReceiver, FragMsgSender, max_message };
-use fragmentos::rate_limit::rate_limit_channel;
-use fragmentos::utils::DgramCodec;
+use fragmentos::{FragSocket, FragSocketConfig};
 
 // Maximum size of UDP datagram we are willing to send.
 const UDP_MAX_DGRAM: usize = 512;
 
-// Multiplier for the calculation of rate limit buffer:
-const RATE_LIMIT_BUFF_MULT: usize = 16;
+// Maximum size of a message we are willing to receive.
+const MAX_TOTAL_MSG_LEN: usize = 1 << 20;
 
 
 fn main() {
@@ -33,26 +28,13 @@ fn main() {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let socket = UdpSocket::bind(&addr, &handle).unwrap();
-    let dgram_codec = DgramCodec;
-    let (sink, stream) = socket.framed(dgram_codec).split();
-
-    let max_dgram_len = UDP_MAX_DGRAM;
-
-    let queue_len = (max_message(max_dgram_len).unwrap() / max_dgram_len) * RATE_LIMIT_BUFF_MULT;
-    let (rl_sender, rl_receiver) = rate_limit_channel(queue_len, 16, &handle);
-    handle.spawn(
-        sink.sink_map_err(|_| ())
-            .send_all(rl_receiver)
-            .then(|_| Ok(()))
-    );
-
-    let frag_sender = FragMsgSender::new(rl_sender, max_dgram_len, rand::thread_rng());
-    // let frag_sender = FragMsgSender::new(sink, max_dgram_len, rand::thread_rng());
-    let time_receiver = Interval::new(Duration::new(1,0), &handle)
-        .unwrap()
-        .map_err(|_| ());
-    let frag_receiver = FragMsgReceiver::new(stream, time_receiver);
+    let config = FragSocketConfig {
+        max_dgram_len: UDP_MAX_DGRAM,
+        max_total_message: MAX_TOTAL_MSG_LEN,
+        ..FragSocketConfig::default()
+    };
+    let frag_socket = FragSocket::bind(&addr, config, &handle).unwrap();
+    let (frag_sender, frag_receiver) = frag_socket.split();
 
     let mut incoming_counter: usize = 0;
 
@@ -62,7 +44,7 @@ fn main() {
         x
     });
 
-    let send_all = frag_sender.send_all(frag_receiver.map_err(|_| ()));
-    core.run(send_all).unwrap();
+    let send_all = frag_sender.send_all(frag_receiver);
+    core.run(send_all.map(|_| ())).unwrap();
 
 }
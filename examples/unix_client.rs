@@ -0,0 +1,67 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate rand;
+
+extern crate fragmentos;
+
+use std::{env, fs};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::{stream, Future, Stream, Sink};
+use rand::{Rng, StdRng};
+
+use tokio_core::reactor::{Core, Interval};
+
+use fragmentos::transport::{DatagramTransport, UnixTransport, frag_transport};
+
+// Size of the messages sent to the server.
+// Much larger than the maximum size of a Unix datagram on most systems.
+const MSG_LEN: usize = 1 << 18;
+
+// Maximum size of a message we are willing to receive.
+const MAX_TOTAL_MSG_LEN: usize = 1 << 20;
+
+
+fn main() {
+    let server_path = PathBuf::from(
+        env::args().nth(1).unwrap_or("/tmp/fragmentos_echo.sock".to_string()));
+    // Amount of messages to send to the server:
+    let num_messages: usize = env::args().nth(2).unwrap_or("4".to_string()).parse().unwrap();
+
+    // We need a path of our own to get the echoes back:
+    let client_path = env::temp_dir().join(format!("fragmentos_client_{}.sock",
+                                                   std::process::id()));
+    let _ = fs::remove_file(&client_path);
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let transport = UnixTransport::bind(&client_path, &handle).unwrap();
+    println!("max_dgram_len = {}", transport.max_dgram_len());
+
+    let time_tick = Interval::new(Duration::new(1,0), &handle)
+        .unwrap()
+        .map_err(|_| ());
+    let mut rng = StdRng::new().unwrap();
+    let messages = (0 .. num_messages)
+        .map(|_| (rng.gen_iter::<u8>().take(MSG_LEN).collect::<Vec<u8>>(), server_path.clone()))
+        .collect::<Vec<_>>();
+
+    let (frag_sender, frag_receiver) = frag_transport(
+        transport, rng, time_tick, MAX_TOTAL_MSG_LEN);
+
+    handle.spawn(frag_sender.send_all(stream::iter_ok(messages.clone()))
+                 .then(|_| Ok(())));
+
+    let echoes = core.run(frag_receiver.take(num_messages as u64).collect())
+        .map_err(|_| ())
+        .unwrap();
+
+    let num_matching = echoes.iter()
+        .filter(|(msg, _)| messages.iter().any(|(orig, _)| orig == msg))
+        .count();
+    println!("Received {} echoes, {} of them match sent messages", echoes.len(), num_matching);
+
+    let _ = fs::remove_file(&client_path);
+}
@@ -0,0 +1,51 @@
+extern crate futures;
+extern crate tokio_core;
+extern crate rand;
+
+extern crate fragmentos;
+
+use std::{env, fs};
+use std::time::Duration;
+
+use futures::{Future, Stream, Sink};
+use rand::StdRng;
+
+use tokio_core::reactor::{Core, Interval};
+
+use fragmentos::transport::{DatagramTransport, UnixTransport, frag_transport};
+
+// Maximum size of a message we are willing to receive.
+const MAX_TOTAL_MSG_LEN: usize = 1 << 20;
+
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or("/tmp/fragmentos_echo.sock".to_string());
+    // Remove a socket file left by a previous run:
+    let _ = fs::remove_file(&path);
+
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let transport = UnixTransport::bind(&path, &handle).unwrap();
+    println!("Listening on {}, max_dgram_len = {}", path, transport.max_dgram_len());
+
+    let time_tick = Interval::new(Duration::new(1,0), &handle)
+        .unwrap()
+        .map_err(|_| ());
+    let (frag_sender, frag_receiver) = frag_transport(
+        transport, StdRng::new().unwrap(), time_tick, MAX_TOTAL_MSG_LEN);
+
+    let mut incoming_counter: usize = 0;
+
+    let frag_receiver = frag_receiver
+        .map(|(msg, path)| {
+            println!("Received a Fragmentos message of {} bytes! counter = {}",
+                     msg.len(), incoming_counter);
+            incoming_counter += 1;
+            (msg, path)
+        })
+        .map_err(|_| ());
+
+    let send_all = frag_sender.send_all(frag_receiver);
+    core.run(send_all.map(|_| ())).unwrap();
+}
@@ -0,0 +1,226 @@
+use core::cmp;
+use core::ops::Range;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use messages::max_frag_message;
+
+/*
+Fragmentos block (The message M carried inside a single Fragmentos message):
+
+- parentId          [8 bytes]
+- blockIndex        [4 bytes]   (Big endian)
+- blockCount        [4 bytes]   (Big endian)
+- blockData         [variable amount of bytes]
+
+A message that fits inside a single Fragmentos message is sent as is, without a
+block header. A larger message is split into blockCount blocks. Every block is
+sent as an independent Fragmentos message, marked by BLOCK_FLAG in its
+paddingCount. All the blocks of a message share the same random parentId.
+*/
+
+// Length of parentId:
+pub const PARENT_ID_LEN: usize = 8;
+// Length of all block fields, excluding blockData:
+pub const BLOCK_HEADER_LEN: usize = PARENT_ID_LEN + 4 + 4;
+
+
+/// A block of a message: its header, if the message is split into a few blocks, 
+/// and the range of its data inside the message.
+pub struct BlockRange {
+    pub opt_header: Option<[u8; BLOCK_HEADER_LEN]>,
+    pub data_range: Range<usize>,
+}
+
+impl BlockRange {
+    /// Length of the block, header included.
+    pub fn len(&self) -> usize {
+        self.opt_header.map_or(0, |header| header.len()) + self.data_range.len()
+    }
+}
+
+/// A parsed Fragmentos block.
+pub struct Block<'a> {
+    pub parent_id: &'a [u8; PARENT_ID_LEN],
+    pub block_index: u32,
+    pub block_count: u32,
+    pub block_data: &'a [u8],
+}
+
+fn write_u32(buf: &mut [u8; 4], num: u32) {
+    buf[0] = (num >> 24) as u8;
+    buf[1] = (num >> 16) as u8;
+    buf[2] = (num >> 8) as u8;
+    buf[3] = num as u8;
+}
+
+fn read_u32(buf: &[u8; 4]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) |
+        ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+/// Calculate the maximum amount of blockData that fits inside a single Fragmentos message, given
+/// the maximum datagram allowed on the underlying protocol.
+pub fn max_block_data(max_dgram_len: usize) -> Result<usize,()> {
+    let max_m = max_frag_message(max_dgram_len)?;
+    if max_m <= BLOCK_HEADER_LEN {
+        return Err(());
+    }
+    Ok(max_m - BLOCK_HEADER_LEN)
+}
+
+/// Calculate the largest message that is sent as a single Fragmentos message, given the maximum
+/// datagram allowed on the underlying protocol. Larger messages are split into a few blocks,
+/// and arrive only if all of their blocks arrive.
+pub fn max_message(max_dgram_len: usize) -> Result<usize,()> {
+    max_frag_message(max_dgram_len)
+}
+
+/// Calculate the blocks layout of a message of length m_len, given the maximum datagram allowed
+/// on the underlying protocol.
+/// A message of at most max_message() bytes is a single block without a header.
+pub fn block_layout(m_len: usize, parent_id: &[u8; PARENT_ID_LEN], max_dgram_len: usize)
+        -> Result<Vec<BlockRange>,()> {
+
+    if m_len <= max_message(max_dgram_len)? {
+        return Ok(vec![BlockRange {
+            opt_header: None,
+            data_range: 0 .. m_len,
+        }]);
+    }
+
+    let max_block_data = max_block_data(max_dgram_len)?;
+    let block_count = m_len.div_ceil(max_block_data);
+    if block_count > u32::MAX as usize {
+        return Err(());
+    }
+
+    Ok((0 .. block_count).map(|block_index| {
+        let start = block_index * max_block_data;
+        let end = cmp::min(start + max_block_data, m_len);
+
+        let mut header = [0u8; BLOCK_HEADER_LEN];
+        header[0 .. PARENT_ID_LEN].copy_from_slice(parent_id);
+        write_u32(array_mut_ref![header, PARENT_ID_LEN, 4], block_index as u32);
+        write_u32(array_mut_ref![header, PARENT_ID_LEN + 4, 4], block_count as u32);
+        BlockRange {
+            opt_header: Some(header),
+            data_range: start .. end,
+        }
+    }).collect::<Vec<_>>())
+}
+
+/// Split a message m into blocks, each containing at most max_block_data bytes of data.
+/// Every returned block (header included) could be sent as a single Fragmentos message.
+/// Unlike block_layout(), a small message is also given a block header.
+#[cfg(test)]
+pub fn split_blocks(m: &[u8], parent_id: &[u8; PARENT_ID_LEN], max_block_data: usize)
+        -> Result<Vec<Vec<u8>>,()> {
+
+    if max_block_data == 0 {
+        return Err(());
+    }
+    let block_count = cmp::max(m.len().div_ceil(max_block_data), 1);
+    Ok((0 .. block_count).map(|block_index| {
+        let start = block_index * max_block_data;
+        let end = cmp::min(start + max_block_data, m.len());
+        let mut block = vec![0u8; BLOCK_HEADER_LEN];
+        block[0 .. PARENT_ID_LEN].copy_from_slice(parent_id);
+        write_u32(array_mut_ref![block, PARENT_ID_LEN, 4], block_index as u32);
+        write_u32(array_mut_ref![block, PARENT_ID_LEN + 4, 4], block_count as u32);
+        block.extend_from_slice(&m[start .. end]);
+        block
+    }).collect::<Vec<Vec<u8>>>())
+}
+
+/// Parse a block. Returns None if the block is malformed.
+pub fn parse_block<'a>(block: &'a [u8]) -> Option<Block<'a>> {
+    if block.len() < BLOCK_HEADER_LEN {
+        return None;
+    }
+
+    let block_index = read_u32(array_ref![block, PARENT_ID_LEN, 4]);
+    let block_count = read_u32(array_ref![block, PARENT_ID_LEN + 4, 4]);
+
+    if block_index >= block_count {
+        return None;
+    }
+
+    Some(Block {
+        parent_id: array_ref![block, 0, PARENT_ID_LEN],
+        block_index,
+        block_count,
+        block_data: &block[BLOCK_HEADER_LEN ..],
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_block_data() {
+        assert!(max_block_data(0).is_err());
+        assert!(max_block_data(512).unwrap() + BLOCK_HEADER_LEN == max_frag_message(512).unwrap());
+    }
+
+    #[test]
+    fn test_split_parse_blocks() {
+        let m = (0 .. 100u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let blocks = split_blocks(&m, b"parentid", 30).unwrap();
+        assert_eq!(blocks.len(), 4);
+
+        let mut united = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            let block = parse_block(block).unwrap();
+            assert_eq!(block.parent_id, b"parentid");
+            assert_eq!(block.block_index, i as u32);
+            assert_eq!(block.block_count, 4);
+            united.extend_from_slice(block.block_data);
+        }
+        assert_eq!(united, m);
+    }
+
+    #[test]
+    fn test_block_layout() {
+        // A small message is a single block, without a header:
+        let max_m = max_message(100).unwrap();
+        let layout = block_layout(max_m, b"parentid", 100).unwrap();
+        assert_eq!(layout.len(), 1);
+        assert!(layout[0].opt_header.is_none());
+        assert_eq!(layout[0].data_range, 0 .. max_m);
+
+        let layout = block_layout(max_m + 1, b"parentid", 100).unwrap();
+        assert_eq!(layout.len(), 2);
+        let max_data = max_block_data(100).unwrap();
+        for (i, block_range) in layout.iter().enumerate() {
+            let header = block_range.opt_header.unwrap();
+            let block = parse_block(&header).unwrap();
+            assert_eq!(block.block_index, i as u32);
+            assert_eq!(block.block_count, 2);
+            assert!(block_range.len() <= max_m);
+            assert_eq!(block_range.data_range.start, i * max_data);
+        }
+        assert!(block_layout(0, b"parentid", 0).is_err());
+    }
+
+    #[test]
+    fn test_split_empty_message() {
+        let blocks = split_blocks(&[], b"parentid", 30).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let block = parse_block(&blocks[0]).unwrap();
+        assert_eq!(block.block_count, 1);
+        assert!(block.block_data.is_empty());
+    }
+
+    #[test]
+    fn test_parse_block_invalid() {
+        assert!(parse_block(b"short").is_none());
+
+        // block_index >= block_count:
+        let mut blocks = split_blocks(b"Some data", b"parentid", 30).unwrap();
+        blocks[0][PARENT_ID_LEN + 3] = 1;
+        assert!(parse_block(&blocks[0]).is_none());
+    }
+}
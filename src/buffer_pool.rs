@@ -0,0 +1,197 @@
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, MutexGuard};
+// Without std there are no threads to share the pool with:
+#[cfg(not(feature = "std"))]
+use alloc::rc::Rc;
+#[cfg(not(feature = "std"))]
+use core::cell::{RefCell, RefMut};
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// Amount of bytes a pool created with BufferPool::new() may keep for every buffer,
+/// enough for the largest UDP datagram.
+pub const DEFAULT_BYTES_PER_BUFFER: usize = 1 << 16;
+
+/// Statistics about the usage of a BufferPool.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Amount of buffers that were allocated, because the pool was empty.
+    pub allocated: usize,
+    /// Amount of buffers that were taken from the pool.
+    pub reused: usize,
+    /// Amount of buffers that were returned into the pool.
+    pub returned: usize,
+    /// Amount of returned buffers that were dropped, because the pool was full, or keeping them
+    /// would exceed the byte limit of the pool.
+    pub dropped: usize,
+}
+
+struct BufferPoolInner {
+    buffers: Vec<Vec<u8>>,
+    max_buffers: usize,
+    // Total capacity of the unused buffers, bounded by max_bytes:
+    pooled_bytes: usize,
+    max_bytes: usize,
+    stats: BufferPoolStats,
+}
+
+/// A pool of byte buffers, bounded both by the amount of buffers and by their total capacity.
+/// Cloning a BufferPool results in another handle to the same pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    #[cfg(feature = "std")]
+    inner: Arc<Mutex<BufferPoolInner>>,
+    #[cfg(not(feature = "std"))]
+    inner: Rc<RefCell<BufferPoolInner>>,
+}
+
+impl BufferPool {
+    /// Create a new pool, keeping at most max_buffers unused buffers,
+    /// of at most DEFAULT_BYTES_PER_BUFFER bytes each on average.
+    pub fn new(max_buffers: usize) -> Self {
+        BufferPool::with_max_bytes(max_buffers,
+                                   max_buffers.saturating_mul(DEFAULT_BYTES_PER_BUFFER))
+    }
+
+    /// Create a new pool, keeping at most max_buffers unused buffers,
+    /// with a total capacity of at most max_bytes.
+    pub fn with_max_bytes(max_buffers: usize, max_bytes: usize) -> Self {
+        let inner = BufferPoolInner {
+            buffers: Vec::new(),
+            max_buffers,
+            pooled_bytes: 0,
+            max_bytes,
+            stats: BufferPoolStats::default(),
+        };
+        BufferPool {
+            #[cfg(feature = "std")]
+            inner: Arc::new(Mutex::new(inner)),
+            #[cfg(not(feature = "std"))]
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn lock<'a>(&'a self) -> MutexGuard<'a, BufferPoolInner> {
+        self.inner.lock().unwrap()
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn lock<'a>(&'a self) -> RefMut<'a, BufferPoolInner> {
+        self.inner.borrow_mut()
+    }
+
+    /// Take an empty buffer from the pool.
+    /// A new buffer is allocated if the pool is empty.
+    pub fn take(&self) -> Vec<u8> {
+        let mut inner = self.lock();
+        match inner.buffers.pop() {
+            Some(buffer) => {
+                inner.pooled_bytes -= buffer.capacity();
+                inner.stats.reused += 1;
+                buffer
+            },
+            None => {
+                inner.stats.allocated += 1;
+                Vec::new()
+            },
+        }
+    }
+
+    /// Return a buffer into the pool, so that its memory could be used again.
+    /// The buffer is dropped if the pool is full, or if it is too large to be kept.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        let mut inner = self.lock();
+        if inner.buffers.len() >= inner.max_buffers ||
+            inner.pooled_bytes.saturating_add(buffer.capacity()) > inner.max_bytes {
+
+            inner.stats.dropped += 1;
+            return;
+        }
+        buffer.clear();
+        inner.pooled_bytes += buffer.capacity();
+        inner.stats.returned += 1;
+        inner.buffers.push(buffer);
+    }
+
+    /// Amount of unused buffers currently inside the pool.
+    pub fn len(&self) -> usize {
+        self.lock().buffers.len()
+    }
+
+    /// Check if there are no unused buffers inside the pool.
+    pub fn is_empty(&self) -> bool {
+        self.lock().buffers.is_empty()
+    }
+
+    /// Get the usage statistics of the pool.
+    pub fn stats(&self) -> BufferPoolStats {
+        self.lock().stats.clone()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool_basic() {
+        let pool = BufferPool::new(2);
+
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(&[1,2,3,4,5]);
+        let capacity = buffer.capacity();
+        pool.give(buffer);
+        assert_eq!(pool.len(), 1);
+
+        // The same memory is handed out again, cleared:
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+        assert!(pool.is_empty());
+
+        assert_eq!(pool.stats(), BufferPoolStats {
+            allocated: 1,
+            reused: 1,
+            returned: 1,
+            dropped: 0,
+        });
+    }
+
+    #[test]
+    fn test_buffer_pool_bounded() {
+        let pool = BufferPool::new(2);
+        for _ in 0 .. 5 {
+            pool.give(vec![0; 10]);
+        }
+        assert_eq!(pool.len(), 2);
+
+        let stats = pool.stats();
+        assert_eq!(stats.returned, 2);
+        assert_eq!(stats.dropped, 3);
+    }
+
+    #[test]
+    fn test_buffer_pool_max_bytes() {
+        let pool = BufferPool::with_max_bytes(8, 100);
+
+        // A single large buffer doesn't fit:
+        pool.give(Vec::with_capacity(101));
+        assert!(pool.is_empty());
+
+        pool.give(Vec::with_capacity(60));
+        pool.give(Vec::with_capacity(60));
+        assert_eq!(pool.len(), 1);
+
+        // Taking a buffer out frees its bytes:
+        let buffer = pool.take();
+        pool.give(Vec::with_capacity(40));
+        pool.give(buffer);
+        assert_eq!(pool.len(), 2);
+
+        let stats = pool.stats();
+        assert_eq!(stats.returned, 3);
+        assert_eq!(stats.dropped, 2);
+    }
+}
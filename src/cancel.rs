@@ -0,0 +1,119 @@
+//! Cancelling messages in flight: cancel handles, and the supersede keys that cancel the
+//! previous message with the same key.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const MIN_SUPERSEDED_PRUNE_LEN: usize = 64;
+
+
+/// Cancels a message that was handed to a FragMsgSender.
+/// Datagrams of the message that were not sent yet are dropped,
+/// both by the FragMsgSender and by a rate limiter after it.
+/// Datagrams already handed to the socket can not be retracted.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn downgrade(&self) -> WeakCancelHandle {
+        WeakCancelHandle {
+            cancelled: Arc::downgrade(&self.cancelled),
+        }
+    }
+}
+
+/// Cancels a message without keeping its handle alive.
+/// Once the message is dropped everywhere, there is nothing left to cancel.
+pub(crate) struct WeakCancelHandle {
+    cancelled: Weak<AtomicBool>,
+}
+
+impl WeakCancelHandle {
+    fn cancel(&self) {
+        if let Some(cancelled) = self.cancelled.upgrade() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn is_alive(&self) -> bool {
+        self.cancelled.upgrade().is_some()
+    }
+}
+
+/// The latest message of every supersede key.
+pub(crate) struct SupersedeKeys {
+    pub(crate) latest_by_key: HashMap<u64, WeakCancelHandle>,
+    // Size of latest_by_key at which keys of messages that are gone are forgotten:
+    prune_len: usize,
+}
+
+impl SupersedeKeys {
+    pub(crate) fn new() -> Self {
+        SupersedeKeys {
+            latest_by_key: HashMap::new(),
+            prune_len: MIN_SUPERSEDED_PRUNE_LEN,
+        }
+    }
+
+    /// Make the message with the given cancel handle the latest one of key, cancelling the
+    /// previous message with the same key, including its datagrams still waiting in a rate
+    /// limiter.
+    pub(crate) fn supersede(&mut self, key: u64, cancel_handle: &CancelHandle) {
+        if let Some(older) = self.latest_by_key.insert(key, cancel_handle.downgrade()) {
+            older.cancel();
+        }
+        // Forget the keys of messages that were sent, expired or cancelled,
+        // once the map doubled in size since the last time:
+        if self.latest_by_key.len() >= self.prune_len {
+            self.latest_by_key.retain(|_, latest| latest.is_alive());
+            self.prune_len = cmp::max(2 * self.latest_by_key.len(), MIN_SUPERSEDED_PRUNE_LEN);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supersede_keys() {
+        let mut supersede_keys = SupersedeKeys::new();
+        let older = CancelHandle::new();
+        let newer = CancelHandle::new();
+        supersede_keys.supersede(7, &older);
+        supersede_keys.supersede(8, &newer);
+        assert!(!older.is_cancelled());
+        supersede_keys.supersede(7, &newer);
+        assert!(older.is_cancelled());
+        assert!(!newer.is_cancelled());
+
+        // Keys of messages that are gone are forgotten:
+        drop(newer);
+        let handles = (0 .. MIN_SUPERSEDED_PRUNE_LEN as u64).map(|key| {
+            let cancel_handle = CancelHandle::new();
+            supersede_keys.supersede(100 + key, &cancel_handle);
+            cancel_handle
+        }).collect::<Vec<_>>();
+        assert_eq!(supersede_keys.latest_by_key.len(), handles.len());
+        assert!(handles.iter().all(|cancel_handle| !cancel_handle.is_cancelled()));
+    }
+}
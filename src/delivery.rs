@@ -0,0 +1,282 @@
+//! Estimates of the delivery rate, overhead and goodput of Fragmentos messages over a lossy
+//! network, both analytically and by Monte Carlo simulation of the real encoding and reassembly.
+//!
+//! In the model of the README, every datagram arrives independently with probability p, and a
+//! block split into n = 2b-1 shares arrives if at least b of them arrive:
+//! q = sum_{k=b}^{n} C(n,k) p^k (1-p)^(n-k). A message arrives if all of its blocks arrive.
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use ::blocks::{block_layout, PARENT_ID_LEN};
+use ::fragmenter::{Fragmenter, CompatRng};
+pub use ::fragmenter::Redundancy;
+use ::messages::{num_frag_messages, frag_message_len};
+use ::sim::{LossModel, LossProcess};
+use ::state_machine::FragStateMachine;
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// max_dgram_len is too small to carry any message data.
+    DgramTooSmall,
+    /// The loss model has no closed form. Use monte_carlo_delivery() instead.
+    NoAnalyticModel,
+    /// Overhead and goodput are not defined for an empty message.
+    EmptyMessage,
+    /// A probability of the loss model is not between 0 and 1.
+    InvalidLossModel,
+    /// At least one trial is needed to estimate the delivery rate.
+    NoTrials,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryEstimate {
+    /// Probability of a message arriving.
+    pub delivery_rate: f64,
+    /// Datagrams sent for every message.
+    pub dgrams_sent: usize,
+    /// Bytes sent for every message, Fragmentos fields and block headers included.
+    pub bytes_sent: usize,
+    /// Sent bytes per message byte.
+    pub overhead: f64,
+    /// Expected delivered message bytes per sent byte.
+    pub goodput: f64,
+}
+
+impl DeliveryEstimate {
+    fn new(m_len: usize, delivery_rate: f64, dgrams_sent: usize, bytes_sent: usize) -> Self {
+        DeliveryEstimate {
+            delivery_rate,
+            dgrams_sent,
+            bytes_sent,
+            overhead: bytes_sent as f64 / m_len as f64,
+            goodput: delivery_rate * m_len as f64 / bytes_sent as f64,
+        }
+    }
+}
+
+// The shares of a single block: b, the total amount of shares and the length of every share:
+struct BlockShares {
+    b: usize,
+    num_shares: usize,
+    dgram_len: usize,
+}
+
+/// Reject inputs the estimates are not defined for.
+fn check_inputs(m_len: usize, loss: &LossModel) -> Result<(), DeliveryError> {
+    if m_len == 0 {
+        return Err(DeliveryError::EmptyMessage);
+    }
+    let is_probability = |x: f64| (0.0 ..= 1.0).contains(&x);
+    let is_valid = match *loss {
+        LossModel::NoLoss => true,
+        LossModel::Bernoulli { p } => is_probability(p),
+        LossModel::GilbertElliott { p_good_to_bad, p_bad_to_good, loss_good, loss_bad } => 
+            is_probability(p_good_to_bad) && is_probability(p_bad_to_good) &&
+                is_probability(loss_good) && is_probability(loss_bad),
+    };
+    if !is_valid {
+        return Err(DeliveryError::InvalidLossModel);
+    }
+    Ok(())
+}
+
+/// The shares of every block of a message of length m_len.
+fn message_layout(m_len: usize, max_dgram_len: usize) -> Result<Vec<BlockShares>, DeliveryError> {
+    let parent_id = [0u8; PARENT_ID_LEN];
+    let blocks = block_layout(m_len, &parent_id, max_dgram_len)
+        .map_err(|_| DeliveryError::DgramTooSmall)?;
+
+    blocks.into_iter().map(|block_range| {
+        let block_len = block_range.len();
+        let num_shares = num_frag_messages(block_len, max_dgram_len)
+            .map_err(|_| DeliveryError::DgramTooSmall)?;
+        let dgram_len = frag_message_len(block_len, max_dgram_len)
+            .map_err(|_| DeliveryError::DgramTooSmall)?;
+        Ok(BlockShares {
+            b: num_shares.div_ceil(2),
+            num_shares,
+            dgram_len,
+        })
+    }).collect()
+}
+
+/// Probability that at least b out of n datagrams arrive,
+/// if every datagram arrives independently with probability p.
+pub fn block_delivery_probability(b: usize, n: usize, p: f64) -> f64 {
+    // C(n,k) p^k (1-p)^(n-k), for k = 0 .. n:
+    let mut choose = 1.0;
+    let mut sum = 0.0;
+    for k in 0 ..= n {
+        if k >= b {
+            sum += choose * p.powi(k as i32) * (1.0 - p).powi((n - k) as i32);
+        }
+        choose = choose * (n - k) as f64 / (k + 1) as f64;
+    }
+    sum
+}
+
+/// Calculate the delivery rate of a message of length m_len.
+/// Only loss models where datagrams are lost independently have a closed form.
+pub fn analytic_delivery(m_len: usize, max_dgram_len: usize, redundancy: Redundancy,
+                         loss: &LossModel) -> Result<DeliveryEstimate, DeliveryError> {
+
+    check_inputs(m_len, loss)?;
+    let p_arrive = match *loss {
+        LossModel::NoLoss => 1.0,
+        LossModel::Bernoulli { p } => 1.0 - p,
+        LossModel::GilbertElliott { .. } => return Err(DeliveryError::NoAnalyticModel),
+    };
+
+    let mut delivery_rate = 1.0;
+    let mut dgrams_sent = 0;
+    let mut bytes_sent = 0;
+    for block in message_layout(m_len, max_dgram_len)? {
+        let num_sent = redundancy.num_sent(block.b);
+        delivery_rate *= block_delivery_probability(block.b, num_sent, p_arrive);
+        dgrams_sent += num_sent;
+        bytes_sent += num_sent * block.dgram_len;
+    }
+    Ok(DeliveryEstimate::new(m_len, delivery_rate, dgrams_sent, bytes_sent))
+}
+
+/// Estimate the delivery rate of a message of length m_len by sending it num_trials times
+/// through the loss model, and reassembling it with a FragStateMachine.
+/// Losses are driven by a random generator seeded with seed.
+pub fn monte_carlo_delivery(m_len: usize, max_dgram_len: usize, redundancy: Redundancy,
+                            loss: &LossModel, num_trials: usize, seed: &[usize])
+    -> Result<DeliveryEstimate, DeliveryError> {
+
+    check_inputs(m_len, loss)?;
+    if num_trials == 0 {
+        return Err(DeliveryError::NoTrials);
+    }
+    let layout = message_layout(m_len, max_dgram_len)?;
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+
+    let mut msg = vec![0u8; m_len];
+    rng.fill_bytes(&mut msg);
+    let rng_fragmenter: StdRng = SeedableRng::from_seed(seed);
+    let dgrams = Fragmenter::new(max_dgram_len, CompatRng(rng_fragmenter))
+        .fragment(&msg)
+        .map_err(|_| DeliveryError::DgramTooSmall)?;
+
+    // The datagrams of every block are consecutive. Keep only the sent ones:
+    let mut sent_dgrams = Vec::new();
+    let mut block_start = 0;
+    for block in &layout {
+        let num_sent = redundancy.num_sent(block.b);
+        sent_dgrams.extend(&dgrams[block_start .. block_start + num_sent]);
+        block_start += block.num_shares;
+    }
+    let bytes_sent = sent_dgrams.iter().map(|dgram| dgram.len()).sum::<usize>();
+
+    // Consecutive messages go through the same loss process:
+    let mut loss_process = LossProcess::new(loss.clone());
+    let mut num_delivered = 0;
+    for _ in 0 .. num_trials {
+        let mut fsm = FragStateMachine::with_max_total_message(m_len);
+        let mut delivered = false;
+        for dgram in &sent_dgrams {
+            if loss_process.is_lost(&mut rng) {
+                continue;
+            }
+            // A reconstructed message that differs from the original doesn't count as delivered:
+            if let Some(united) = fsm.received_frag_message(dgram) {
+                delivered = united == msg;
+            }
+        }
+        if delivered {
+            num_delivered += 1;
+        }
+    }
+
+    let delivery_rate = num_delivered as f64 / num_trials as f64;
+    Ok(DeliveryEstimate::new(m_len, delivery_rate, sent_dgrams.len(), bytes_sent))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_delivery_probability_readme() {
+        // The example in the README: b = 2, p = 3/4:
+        let q = block_delivery_probability(2, 3, 0.75);
+        assert!((q - 0.84375).abs() < 1e-9);
+        // Plain fragmentation into two datagrams:
+        let q = block_delivery_probability(2, 2, 0.75);
+        assert!((q - 0.5625).abs() < 1e-9);
+
+        assert!((block_delivery_probability(5, 9, 1.0) - 1.0).abs() < 1e-9);
+        assert!(block_delivery_probability(5, 9, 0.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_analytic_delivery() {
+        let estimate = analytic_delivery(1000, 100, Redundancy::Full, &LossModel::NoLoss).unwrap();
+        assert!((estimate.delivery_rate - 1.0).abs() < 1e-9);
+        assert!(estimate.overhead > 1.0);
+        assert!((estimate.goodput * estimate.overhead - 1.0).abs() < 1e-9);
+
+        let full = analytic_delivery(1000, 100, Redundancy::Full,
+                                     &LossModel::Bernoulli { p: 0.1 }).unwrap();
+        let none = analytic_delivery(1000, 100, Redundancy::Extra(0),
+                                     &LossModel::Bernoulli { p: 0.1 }).unwrap();
+        assert!(full.delivery_rate > none.delivery_rate);
+        assert!(full.bytes_sent > none.bytes_sent);
+
+        assert_eq!(analytic_delivery(1000, 0, Redundancy::Full, &LossModel::NoLoss),
+                   Err(DeliveryError::DgramTooSmall));
+        let loss = LossModel::GilbertElliott {
+            p_good_to_bad: 0.1, p_bad_to_good: 0.5, loss_good: 0.0, loss_bad: 1.0,
+        };
+        assert_eq!(analytic_delivery(1000, 100, Redundancy::Full, &loss),
+                   Err(DeliveryError::NoAnalyticModel));
+    }
+
+    #[test]
+    fn test_monte_carlo_matches_analytic() {
+        let seed: &[_] = &[1,2,3,4,5];
+        let loss = LossModel::Bernoulli { p: 0.25 };
+        for &redundancy in &[Redundancy::Full, Redundancy::Extra(0), Redundancy::Extra(2)] {
+            let analytic = analytic_delivery(300, 50, redundancy, &loss).unwrap();
+            let simulated = monte_carlo_delivery(300, 50, redundancy, &loss, 1000, seed).unwrap();
+            assert_eq!(analytic.dgrams_sent, simulated.dgrams_sent);
+            assert_eq!(analytic.bytes_sent, simulated.bytes_sent);
+            assert!((analytic.delivery_rate - simulated.delivery_rate).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn test_monte_carlo_multiple_blocks() {
+        let seed: &[_] = &[1,2,3,4,5];
+        // A message larger than max_message(30) is split into a few blocks:
+        let simulated = monte_carlo_delivery(5000, 30, Redundancy::Full,
+                                             &LossModel::NoLoss, 3, seed).unwrap();
+        assert!((simulated.delivery_rate - 1.0).abs() < 1e-9);
+        let analytic = analytic_delivery(5000, 30, Redundancy::Full, &LossModel::NoLoss).unwrap();
+        assert_eq!(analytic.bytes_sent, simulated.bytes_sent);
+    }
+
+    #[test]
+    fn test_delivery_invalid_inputs() {
+        let seed: &[_] = &[1,2,3,4,5];
+        let loss = LossModel::Bernoulli { p: 0.25 };
+        assert_eq!(analytic_delivery(0, 100, Redundancy::Full, &loss),
+                   Err(DeliveryError::EmptyMessage));
+        assert_eq!(monte_carlo_delivery(0, 100, Redundancy::Full, &loss, 10, seed),
+                   Err(DeliveryError::EmptyMessage));
+        assert_eq!(monte_carlo_delivery(300, 100, Redundancy::Full, &loss, 0, seed),
+                   Err(DeliveryError::NoTrials));
+        assert_eq!(analytic_delivery(300, 100, Redundancy::Full, 
+                                     &LossModel::Bernoulli { p: 1.5 }),
+                   Err(DeliveryError::InvalidLossModel));
+        let loss = LossModel::GilbertElliott {
+            p_good_to_bad: f64::INFINITY, p_bad_to_good: 0.0, loss_good: 0.0, loss_bad: 1.0,
+        };
+        assert_eq!(monte_carlo_delivery(300, 100, Redundancy::Full, &loss, 10, seed),
+                   Err(DeliveryError::InvalidLossModel));
+    }
+}
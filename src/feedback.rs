@@ -0,0 +1,482 @@
+//! Delivery outcomes: learning on the sender side what happened to every message.
+//!
+//! A receiver with feedback enabled reports back to the source of every Fragmentos message
+//! whether it was reconstructed, failed to reconstruct, or was discarded after only some of its
+//! shares arrived. The sender
+//! matches the reports against the messages it tracks, and produces one DeliveryOutcome for
+//! every tracked message and destination. A message gets the Unknown outcome if not all of its
+//! reports arrived within outcome_timeout_ticks time ticks, for example because all of its
+//! shares were lost, or because the remote receiver does not send reports.
+//!
+//! Reports are control messages like path MTU probes, which are never valid Fragmentos
+//! messages (b is always 0), so receivers that don't expect them ignore them.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::mpsc;
+
+use ::messages::{short_hash, verify_frag_message, SHORT_HASH_LEN, MESSAGE_ID_LEN};
+use ::state_machine::{ExpiredMessage, MESSAGE_ID_TICKS};
+
+/*
+Fragmentos delivery report:
+
+- messageId         [8 bytes]   (The reported Fragmentos message)
+- b                 [1 byte]    (Always 0)
+- kind              [1 byte]    (Always 2. Kinds 0 and 1 are path MTU probes and echoes)
+- status            [1 byte]    (0 if the message was reconstructed, 1 if it was discarded,
+                                 2 if its reconstruction did not match its messageId)
+- messageB          [1 byte]    (b of the reported message, if it was discarded)
+- numShares         [1 byte]    (Amount of shares that arrived, if it was discarded)
+- shortHash         [8 bytes]   (First 8 bytes of Sha512/256)
+*/
+
+const KIND_REPORT: u8 = 2;
+const STATUS_DELIVERED: u8 = 0;
+const STATUS_EXPIRED: u8 = 1;
+const STATUS_FAILED: u8 = 2;
+const REPORT_LEN: usize = MESSAGE_ID_LEN + 1 + 1 + 1 + 1 + 1 + SHORT_HASH_LEN;
+
+// Sources of partially received messages are kept a bit longer than the messages themselves:
+const SOURCE_TICKS: usize = MESSAGE_ID_TICKS + 1;
+// Maximum amount of reports waiting to be sent. Further reports are dropped, and their
+// messages get the Unknown outcome on the sender side:
+const MAX_REPORTS: usize = 1024;
+// Maximum amount of partially received messages whose sources are kept. Further messages are
+// not reported:
+const MAX_SOURCES: usize = 1024;
+/// Maximum amount of outcomes waiting to be read. Further outcomes are dropped.
+pub const MAX_OUTCOMES: usize = 1024;
+
+
+/// What happened to a message sent to one destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// All the blocks of the message were reconstructed by the receiver.
+    Delivered,
+    /// The receiver discarded a block of the message, after only num_shares of the b shares
+    /// needed to reconstruct it arrived.
+    Expired { b: usize, num_shares: usize },
+    /// The receiver reconstructed a block of the message, but it did not match its messageId,
+    /// for example because a corrupted share passed the checks of its shortHash.
+    Failed,
+    /// No report arrived in time.
+    Unknown,
+}
+
+/// The outcome of a message sent with SendOptions::opt_track_id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryOutcome<A> {
+    pub track_id: u64,
+    pub address: A,
+    pub status: DeliveryStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Report {
+    Delivered,
+    Expired { b: u8, num_shares: u8 },
+    Failed,
+}
+
+fn encode_report(message_id: &[u8; MESSAGE_ID_LEN], report: &Report) -> Vec<u8> {
+    let mut dgram = vec![0u8; REPORT_LEN];
+    dgram[.. MESSAGE_ID_LEN].copy_from_slice(message_id);
+    dgram[MESSAGE_ID_LEN] = 0;
+    dgram[MESSAGE_ID_LEN + 1] = KIND_REPORT;
+    match *report {
+        Report::Delivered => dgram[MESSAGE_ID_LEN + 2] = STATUS_DELIVERED,
+        Report::Expired { b, num_shares } => {
+            dgram[MESSAGE_ID_LEN + 2] = STATUS_EXPIRED;
+            dgram[MESSAGE_ID_LEN + 3] = b;
+            dgram[MESSAGE_ID_LEN + 4] = num_shares;
+        },
+        Report::Failed => dgram[MESSAGE_ID_LEN + 2] = STATUS_FAILED,
+    }
+    let hash = short_hash(&dgram[.. REPORT_LEN - SHORT_HASH_LEN]);
+    dgram[REPORT_LEN - SHORT_HASH_LEN ..].copy_from_slice(&hash);
+    dgram
+}
+
+/// Parse a delivery report. Returns None if dgram is not a valid report.
+fn parse_report(dgram: &[u8]) -> Option<([u8; MESSAGE_ID_LEN], Report)> {
+    if dgram.len() != REPORT_LEN || dgram[MESSAGE_ID_LEN] != 0 ||
+        dgram[MESSAGE_ID_LEN + 1] != KIND_REPORT {
+        return None;
+    }
+    if !verify_frag_message(dgram) {
+        return None;
+    }
+    let mut message_id = [0u8; MESSAGE_ID_LEN];
+    message_id.copy_from_slice(&dgram[.. MESSAGE_ID_LEN]);
+
+    let report = match dgram[MESSAGE_ID_LEN + 2] {
+        STATUS_DELIVERED => Report::Delivered,
+        STATUS_EXPIRED => Report::Expired {
+            b: dgram[MESSAGE_ID_LEN + 3],
+            num_shares: dgram[MESSAGE_ID_LEN + 4],
+        },
+        STATUS_FAILED => Report::Failed,
+        _ => return None,
+    };
+    Some((message_id, report))
+}
+
+
+struct TrackedMessage<A> {
+    track_id: u64,
+    address: A,
+    // Fragmentos messages (One for every block) not reported yet:
+    message_ids: Vec<[u8; MESSAGE_ID_LEN]>,
+    ticks: usize,
+}
+
+struct FeedbackInner<A> {
+    outcome_timeout_ticks: usize,
+    outcome_sender: mpsc::Sender<DeliveryOutcome<A>>,
+    dropped_outcomes: usize,
+    next_tracked: usize,
+    tracked: HashMap<usize, TrackedMessage<A>>,
+    // (messageId, destination) -> index of the tracked message:
+    tracked_blocks: HashMap<([u8; MESSAGE_ID_LEN], A), usize>,
+    // Sources of partially received messages, and their remaining ticks:
+    sources: HashMap<[u8; MESSAGE_ID_LEN], (A, usize)>,
+    // Reports waiting to be sent:
+    reports: VecDeque<(Vec<u8>, A)>,
+}
+
+impl<A> FeedbackInner<A>
+where
+    A: Hash + Eq + Clone,
+{
+    /// Stop tracking a message, and produce its outcome.
+    fn finish(&mut self, index: usize, status: DeliveryStatus) {
+        let tracked_message = match self.tracked.remove(&index) {
+            Some(tracked_message) => tracked_message,
+            None => return,
+        };
+        for message_id in tracked_message.message_ids {
+            self.tracked_blocks.remove(&(message_id, tracked_message.address.clone()));
+        }
+        let res_send = self.outcome_sender.try_send(DeliveryOutcome {
+            track_id: tracked_message.track_id,
+            address: tracked_message.address,
+            status,
+        });
+        // Nobody is interested in the outcome if the receiving side was dropped:
+        if let Err(e) = res_send {
+            if e.is_full() {
+                self.dropped_outcomes += 1;
+            }
+        }
+    }
+
+    fn queue_report(&mut self, message_id: &[u8; MESSAGE_ID_LEN], report: Report) {
+        if let Some((address, _)) = self.sources.remove(message_id) {
+            if self.reports.len() < MAX_REPORTS {
+                self.reports.push_back((encode_report(message_id, &report), address));
+            }
+        }
+    }
+}
+
+/// Delivery reports sent and received by a socket.
+/// Reports about sent messages arrive at FragMsgReceiver, while FragMsgSender tracks the
+/// messages and sends the reports, so both should be given clones of the same 
+/// DeliveryFeedback. See FragMsgSender::set_feedback().
+pub struct DeliveryFeedback<A> {
+    inner: Arc<Mutex<FeedbackInner<A>>>,
+}
+
+impl<A> Clone for DeliveryFeedback<A> {
+    fn clone(&self) -> Self {
+        DeliveryFeedback {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<A> DeliveryFeedback<A>
+where
+    A: Hash + Eq + Clone,
+{
+    /// Create a new DeliveryFeedback, and the stream of outcomes of tracked messages.
+    /// outcome_timeout_ticks should be longer than the 30 ticks after which receivers discard
+    /// partially received messages, to leave time for their reports to arrive.
+    /// Outcomes are dropped while MAX_OUTCOMES of them are waiting to be read.
+    pub fn new(outcome_timeout_ticks: usize)
+        -> (Self, mpsc::Receiver<DeliveryOutcome<A>>) {
+
+        let (outcome_sender, outcome_receiver) = mpsc::channel(MAX_OUTCOMES);
+        let feedback = DeliveryFeedback {
+            inner: Arc::new(Mutex::new(FeedbackInner {
+                outcome_timeout_ticks,
+                outcome_sender,
+                dropped_outcomes: 0,
+                next_tracked: 0,
+                tracked: HashMap::new(),
+                tracked_blocks: HashMap::new(),
+                sources: HashMap::new(),
+                reports: VecDeque::new(),
+            })),
+        };
+        (feedback, outcome_receiver)
+    }
+
+    /// Track a message sent to all the given addresses, made of the given Fragmentos messages.
+    pub(crate) fn track(&self, track_id: u64, message_ids: Vec<[u8; MESSAGE_ID_LEN]>,
+                        addresses: &[A]) {
+        let mut inner = self.inner.lock().unwrap();
+        for address in addresses {
+            let index = inner.next_tracked;
+            inner.next_tracked = inner.next_tracked.wrapping_add(1);
+            for message_id in &message_ids {
+                inner.tracked_blocks.insert((*message_id, address.clone()), index);
+            }
+            inner.tracked.insert(index, TrackedMessage {
+                track_id,
+                address: address.clone(),
+                message_ids: message_ids.clone(),
+                ticks: 0,
+            });
+        }
+    }
+
+    /// Track a message sent to all the given addresses, split into the given datagrams.
+    pub(crate) fn track_dgrams(&self, track_id: u64, dgrams: &[Vec<u8>], addresses: &[A]) {
+        if dgrams.is_empty() || addresses.is_empty() {
+            return;
+        }
+        // The shares of every block are consecutive, and start with its messageId:
+        let mut message_ids: Vec<[u8; MESSAGE_ID_LEN]> = Vec::new();
+        for dgram in dgrams {
+            let message_id = array_ref![dgram, 0, MESSAGE_ID_LEN];
+            if message_ids.last() != Some(message_id) {
+                message_ids.push(*message_id);
+            }
+        }
+        self.track(track_id, message_ids, addresses);
+    }
+
+    /// Amount of outcomes dropped because MAX_OUTCOMES of them were waiting to be read.
+    pub fn dropped_outcomes(&self) -> usize {
+        self.inner.lock().unwrap().dropped_outcomes
+    }
+
+    /// Remember the source of a share of message_id that arrived from address, to report back
+    /// to it. Should be called only for shares the state machine accepted.
+    pub(crate) fn received_share(&self, message_id: &[u8; MESSAGE_ID_LEN], address: &A) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.sources.len() >= MAX_SOURCES && !inner.sources.contains_key(message_id) {
+            return;
+        }
+        inner.sources.entry(*message_id)
+            .or_insert_with(|| (address.clone(), SOURCE_TICKS));
+    }
+
+    /// A Fragmentos message was reconstructed.
+    pub(crate) fn delivered(&self, message_id: &[u8; MESSAGE_ID_LEN]) {
+        self.inner.lock().unwrap().queue_report(message_id, Report::Delivered);
+    }
+
+    /// Enough shares of a Fragmentos message arrived, but the reconstruction did not match
+    /// its messageId.
+    pub(crate) fn failed(&self, message_id: &[u8; MESSAGE_ID_LEN]) {
+        self.inner.lock().unwrap().queue_report(message_id, Report::Failed);
+    }
+
+    /// Handle a datagram that arrived from address.
+    /// Returns true if the datagram was a delivery report, and should not be processed further.
+    pub(crate) fn received_control(&self, dgram: &[u8], address: &A) -> bool {
+        let (message_id, report) = match parse_report(dgram) {
+            Some(parsed) => parsed,
+            None => return false,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let index = match inner.tracked_blocks.remove(&(message_id, address.clone())) {
+            Some(index) => index,
+            // Not tracked, or already reported:
+            None => return true,
+        };
+        match report {
+            Report::Delivered => {
+                let is_done = match inner.tracked.get_mut(&index) {
+                    Some(tracked_message) => {
+                        tracked_message.message_ids.retain(|id| *id != message_id);
+                        tracked_message.message_ids.is_empty()
+                    },
+                    None => false,
+                };
+                if is_done {
+                    inner.finish(index, DeliveryStatus::Delivered);
+                }
+            },
+            Report::Expired { b, num_shares } => {
+                inner.finish(index, DeliveryStatus::Expired {
+                    b: b as usize,
+                    num_shares: num_shares as usize,
+                });
+            },
+            Report::Failed => inner.finish(index, DeliveryStatus::Failed),
+        }
+        true
+    }
+
+    /// A report to be sent back to the source of a message.
+    pub(crate) fn pop_report(&self) -> Option<(Vec<u8>, A)> {
+        self.inner.lock().unwrap().reports.pop_front()
+    }
+
+    /// Should be called on every time tick, with the messages the receiver discarded.
+    /// Tracked messages without all their reports for outcome_timeout_ticks ticks get the
+    /// Unknown outcome.
+    pub(crate) fn time_tick(&self, expired: Vec<ExpiredMessage>) {
+        let mut inner = self.inner.lock().unwrap();
+        for expired_message in expired {
+            inner.queue_report(&expired_message.message_id, Report::Expired {
+                b: expired_message.b,
+                num_shares: expired_message.num_shares as u8,
+            });
+        }
+        inner.sources.retain(|_, &mut (_, ref mut ticks)| {
+            *ticks -= 1;
+            *ticks > 0
+        });
+
+        let outcome_timeout_ticks = inner.outcome_timeout_ticks;
+        let mut timed_out = Vec::new();
+        for (index, tracked_message) in inner.tracked.iter_mut() {
+            tracked_message.ticks += 1;
+            if tracked_message.ticks > outcome_timeout_ticks {
+                timed_out.push(*index);
+            }
+        }
+        for index in timed_out {
+            inner.finish(index, DeliveryStatus::Unknown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+    use ::state_machine::FragStateMachine;
+
+    fn collect_outcomes(outcome_receiver: mpsc::Receiver<DeliveryOutcome<u32>>)
+        -> Vec<DeliveryOutcome<u32>> {
+
+        outcome_receiver.wait().map(|outcome| outcome.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_reports() {
+        let message_id = [1,2,3,4,5,6,7,8];
+        let delivered = encode_report(&message_id, &Report::Delivered);
+        assert_eq!(parse_report(&delivered), Some((message_id, Report::Delivered)));
+
+        let expired = encode_report(&message_id, &Report::Expired { b: 5, num_shares: 3 });
+        assert_eq!(parse_report(&expired),
+                   Some((message_id, Report::Expired { b: 5, num_shares: 3 })));
+
+        let failed = encode_report(&message_id, &Report::Failed);
+        assert_eq!(parse_report(&failed), Some((message_id, Report::Failed)));
+
+        // A corrupted report is not a report:
+        let mut corrupt = expired.clone();
+        corrupt[MESSAGE_ID_LEN + 4] ^= 1;
+        assert_eq!(parse_report(&corrupt), None);
+
+        // Fragmentos receivers ignore reports:
+        let mut fsm = FragStateMachine::new();
+        assert_eq!(fsm.received_frag_message(&delivered), None);
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let (sender, outcome_receiver) = DeliveryFeedback::<u32>::new(3);
+        let (receiver, _) = DeliveryFeedback::<u32>::new(3);
+        let (id_a, id_b, id_c) = ([1; MESSAGE_ID_LEN], [2; MESSAGE_ID_LEN], [3; MESSAGE_ID_LEN]);
+
+        let id_d = [4; MESSAGE_ID_LEN];
+
+        // A message of two blocks to addresses 7 and 8, and single block messages to 7:
+        sender.track(100, vec![id_a, id_b], &[7, 8]);
+        sender.track(101, vec![id_c], &[7]);
+        sender.track(102, vec![id_d], &[7]);
+
+        // The receiver at 7 gets shares of all the blocks, from the sender at address 1:
+        for message_id in &[id_a, id_b, id_c, id_d] {
+            receiver.received_share(message_id, &1);
+        }
+        receiver.delivered(&id_a);
+        receiver.delivered(&id_b);
+        receiver.time_tick(vec![ExpiredMessage { message_id: id_c, b: 4, num_shares: 2 }]);
+        receiver.failed(&id_d);
+
+        while let Some((report, address)) = receiver.pop_report() {
+            assert_eq!(address, 1);
+            assert!(sender.received_control(&report, &7));
+        }
+        // Nothing arrives from 8:
+        for _ in 0 .. 4 {
+            sender.time_tick(Vec::new());
+        }
+        drop(sender);
+
+        assert_eq!(collect_outcomes(outcome_receiver), vec![
+            DeliveryOutcome { track_id: 100, address: 7, status: DeliveryStatus::Delivered },
+            DeliveryOutcome { track_id: 101, address: 7,
+                status: DeliveryStatus::Expired { b: 4, num_shares: 2 } },
+            DeliveryOutcome { track_id: 102, address: 7, status: DeliveryStatus::Failed },
+            DeliveryOutcome { track_id: 100, address: 8, status: DeliveryStatus::Unknown },
+        ]);
+    }
+
+    #[test]
+    fn test_max_reports() {
+        let (receiver, _) = DeliveryFeedback::<u32>::new(3);
+        for i in 0 .. MAX_REPORTS as u32 + 10 {
+            let mut message_id = [0u8; MESSAGE_ID_LEN];
+            message_id[0] = (i >> 8) as u8;
+            message_id[1] = i as u8;
+            receiver.received_share(&message_id, &1);
+            receiver.delivered(&message_id);
+        }
+        // Nobody sends the reports, so the last ones are dropped:
+        let mut num_reports = 0;
+        while receiver.pop_report().is_some() {
+            num_reports += 1;
+        }
+        assert_eq!(num_reports, MAX_REPORTS);
+    }
+
+    #[test]
+    fn test_max_sources() {
+        let (receiver, _) = DeliveryFeedback::<u32>::new(3);
+        for i in 0 .. MAX_SOURCES as u32 + 10 {
+            let mut message_id = [0u8; MESSAGE_ID_LEN];
+            message_id[0] = (i >> 8) as u8;
+            message_id[1] = i as u8;
+            receiver.received_share(&message_id, &1);
+        }
+        assert_eq!(receiver.inner.lock().unwrap().sources.len(), MAX_SOURCES);
+    }
+
+    #[test]
+    fn test_max_outcomes() {
+        let (sender, outcome_receiver) = DeliveryFeedback::<u32>::new(0);
+        let num_tracked = MAX_OUTCOMES + 10;
+        for i in 0 .. num_tracked {
+            sender.track(i as u64, vec![[1; MESSAGE_ID_LEN]], &[i as u32]);
+        }
+        // Nobody reads the outcomes yet:
+        sender.time_tick(Vec::new());
+        let num_dropped = sender.dropped_outcomes();
+        assert!(num_dropped > 0);
+        drop(sender);
+        assert_eq!(collect_outcomes(outcome_receiver).len() + num_dropped, num_tracked);
+    }
+}
@@ -1,7 +1,26 @@
+use std::collections::VecDeque;
+use std::hash::Hash;
 use std::marker::PhantomData;
-use futures::{Stream, Poll, Async};
+use futures::{Future, Stream, Poll, Async};
+use futures_cpupool::{CpuPool, CpuFuture};
+
+use ::state_machine::{FragStateMachine, UnitedBlock, DEFAULT_MAX_TOTAL_MESSAGE};
+use ::buffer_pool::BufferPool;
+use ::multipath::PeerMap;
+use ::pmtu::PathMtu;
+use ::feedback::DeliveryFeedback;
+use ::messages::MESSAGE_ID_LEN;
+
+enum Decoding {
+    Done(Option<Result<UnitedBlock, Vec<u8>>>),
+    InProgress(CpuFuture<UnitedBlock, Vec<u8>>),
+}
 
-use ::state_machine::{FragStateMachine};
+struct ParallelDecoder {
+    cpu_pool: CpuPool,
+    min_msg_len: usize,
+    max_in_progress: usize,
+}
 
 pub struct FragMsgReceiver<A,R,E,K>
 where 
@@ -9,7 +28,17 @@ where
     K: Stream<Item=(),Error=()>,
 {
     frag_state_machine: FragStateMachine,
+    opt_buffer_pool: Option<BufferPool>,
+    opt_parallel_decoder: Option<ParallelDecoder>,
+    // Maps source addresses to the peer they belong to:
+    opt_peer_map: Option<PeerMap<A>>,
+    opt_path_mtu: Option<PathMtu<A>>,
+    opt_feedback: Option<DeliveryFeedback<A>>,
+    // Messages being reconstructed, in the order their last share was received,
+    // with their source and messageId:
+    decode_queue: VecDeque<(A, [u8; MESSAGE_ID_LEN], Decoding)>,
     recv_stream: R,
+    recv_stream_done: bool,
     recv_time_tick: K,
     phantom_a: PhantomData<A>,
     phantom_k: PhantomData<K>,
@@ -21,15 +50,117 @@ where
     R: Stream<Item=(Vec<u8>, A), Error=E>,
     K: Stream<Item=(),Error=()>,
 {
+    /// Create a new receiver. 
+    /// Incoming messages larger than DEFAULT_MAX_TOTAL_MESSAGE bytes are discarded.
     pub fn new(recv_stream: R, recv_time_tick: K) -> Self {
+        FragMsgReceiver::with_max_total_message(recv_stream, recv_time_tick, 
+                                                DEFAULT_MAX_TOTAL_MESSAGE)
+    }
+
+    /// Create a new receiver. 
+    /// Incoming messages larger than max_total_message bytes are discarded.
+    pub fn with_max_total_message(recv_stream: R, recv_time_tick: K, 
+                                  max_total_message: usize) -> Self {
         FragMsgReceiver {
-            frag_state_machine: FragStateMachine::new(),
+            frag_state_machine: FragStateMachine::with_max_total_message(max_total_message),
+            opt_buffer_pool: None,
+            opt_parallel_decoder: None,
+            opt_peer_map: None,
+            opt_path_mtu: None,
+            opt_feedback: None,
+            decode_queue: VecDeque::new(),
             recv_stream,
+            recv_stream_done: false,
             recv_time_tick,
             phantom_a: PhantomData,
             phantom_k: PhantomData,
         }
     }
+
+    /// Use the given pool for reassembly buffers.
+    /// Received datagrams are returned into the pool after being processed.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.frag_state_machine.set_buffer_pool(buffer_pool.clone());
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+
+    /// Reconstruct messages of at least min_msg_len bytes on the given thread pool.
+    /// At most max_in_progress messages are queued before we stop reading datagrams.
+    /// Messages are still returned in the order their last required share was received.
+    pub fn set_cpu_pool(&mut self, cpu_pool: CpuPool, min_msg_len: usize, 
+                        max_in_progress: usize) {
+
+        self.opt_parallel_decoder = Some(ParallelDecoder {
+            cpu_pool,
+            min_msg_len,
+            max_in_progress,
+        });
+    }
+}
+
+impl<A,R,E,K> FragMsgReceiver<A,R,E,K>
+where
+    A: Hash + Eq + Clone,
+    R: Stream<Item=(Vec<u8>, A), Error=E>,
+    K: Stream<Item=(),Error=()>,
+{
+    /// Report messages as received from the peer of their source address, instead of the
+    /// source address itself. Shares of a message may arrive from different addresses of the 
+    /// same peer, for example when sent through a MultipathSink.
+    pub fn set_peer_map(&mut self, peer_map: PeerMap<A>) {
+        self.opt_peer_map = Some(peer_map);
+    }
+
+    /// Answer path MTU probes, and pass their echoes to path_mtu.
+    /// Probes that were not echoed in time are handled on every time tick.
+    /// See FragMsgSender::set_path_mtu().
+    pub fn set_path_mtu(&mut self, path_mtu: PathMtu<A>) {
+        self.opt_path_mtu = Some(path_mtu);
+    }
+
+    /// Report to the sources of messages whether they were reconstructed, failed to reconstruct
+    /// or were discarded,
+    /// and pass reports about sent messages to feedback. Sources are the peers of the
+    /// source addresses if a peer map is set.
+    /// See FragMsgSender::set_feedback().
+    pub fn set_feedback(&mut self, feedback: DeliveryFeedback<A>) {
+        self.opt_feedback = Some(feedback);
+    }
+
+    /// Get the next reconstructed message from the decode queue, if there is one ready.
+    fn poll_decode_queue(&mut self) -> Option<(Vec<u8>, A)> {
+        loop {
+            let unite_res = match self.decode_queue.front_mut() {
+                None => return None,
+                Some(&mut (_, _, Decoding::Done(ref mut opt_unite_res))) => 
+                    opt_unite_res.take().unwrap(),
+                Some(&mut (_, _, Decoding::InProgress(ref mut cpu_future))) => 
+                    match cpu_future.poll() {
+                        Ok(Async::Ready(block)) => Ok(block),
+                        Ok(Async::NotReady) => return None,
+                        Err(buffer) => Err(buffer),
+                    },
+            };
+            let (address, message_id, _) = self.decode_queue.pop_front().unwrap();
+            if let Some(msg) = self.united(&message_id, unite_res) {
+                return Some((msg, address));
+            }
+        }
+    }
+
+    /// Pass a reconstructed block to the state machine, and report to its source whether the
+    /// reconstruction matched its messageId.
+    /// Possibly return a full message, if all of its blocks were received.
+    fn united(&mut self, message_id: &[u8; MESSAGE_ID_LEN], 
+              unite_res: Result<UnitedBlock, Vec<u8>>) -> Option<Vec<u8>> {
+        if let Some(ref feedback) = self.opt_feedback {
+            match unite_res {
+                Ok(_) => feedback.delivered(message_id),
+                Err(_) => feedback.failed(message_id),
+            }
+        }
+        self.frag_state_machine.united(unite_res)
+    }
 }
 
 #[derive(Debug)]
@@ -41,6 +172,7 @@ pub enum FragMsgReceiverError<E> {
 
 impl<A,R,E,K> Stream for FragMsgReceiver<A,R,E,K>
 where 
+    A: Hash + Eq + Clone,
     R: Stream<Item=(Vec<u8>, A), Error=E>,
     K: Stream<Item=(),Error=()>,
 {
@@ -48,42 +180,114 @@ where
     type Error = FragMsgReceiverError<E>;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let total_msg: Vec<u8>;
-        let last_address: A;
-
         // Check if a time tick is ready:
         match self.recv_time_tick.poll() {
-            Ok(Async::Ready(Some(()))) => self.frag_state_machine.time_tick(),
+            Ok(Async::Ready(Some(()))) => {
+                let expired = self.frag_state_machine.time_tick();
+                if let Some(ref feedback) = self.opt_feedback {
+                    feedback.time_tick(expired);
+                }
+                if let Some(ref path_mtu) = self.opt_path_mtu {
+                    path_mtu.time_tick();
+                }
+            },
             Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
             Ok(Async::NotReady) => {},
             Err(()) => return Err(FragMsgReceiverError::RecvTimeTickError),
         };
 
         loop {
+            // Messages reconstructed on the thread pool are returned first:
+            if let Some(item) = self.poll_decode_queue() {
+                return Ok(Async::Ready(Some(item)));
+            }
+
+            let decode_queue_full = match self.opt_parallel_decoder {
+                Some(ref parallel_decoder) => 
+                    self.decode_queue.len() >= parallel_decoder.max_in_progress,
+                None => false,
+            };
+            if decode_queue_full {
+                return Ok(Async::NotReady);
+            }
+
+            if self.recv_stream_done {
+                // Wait for the remaining messages to be reconstructed:
+                return match self.decode_queue.is_empty() {
+                    true => Ok(Async::Ready(None)),
+                    false => Ok(Async::NotReady),
+                };
+            }
+
             let (dgram, address) = match self.recv_stream.poll() {
                 Ok(Async::Ready(Some((dgram, address)))) => (dgram, address),
-                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::Ready(None)) => {
+                    self.recv_stream_done = true;
+                    continue;
+                },
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
                 Err(e) => return Err(FragMsgReceiverError::RecvStreamError(e)),
             };
+            // Path MTU probes and echoes are not part of any message:
+            if let Some(ref path_mtu) = self.opt_path_mtu {
+                if path_mtu.received_control(&dgram, &address) {
+                    if let Some(ref buffer_pool) = self.opt_buffer_pool {
+                        buffer_pool.give(dgram);
+                    }
+                    continue;
+                }
+            }
+            let address = match self.opt_peer_map {
+                Some(ref peer_map) => peer_map.peer_of(address),
+                None => address,
+            };
+            if let Some(ref feedback) = self.opt_feedback {
+                if feedback.received_control(&dgram, &address) {
+                    if let Some(ref buffer_pool) = self.opt_buffer_pool {
+                        buffer_pool.give(dgram);
+                    }
+                    continue;
+                }
+            }
 
-            // Add fragment to state machine, possibly reconstructing a full message:
-            let msg_res = self.frag_state_machine.received_frag_message(&dgram);
+            // Add fragment to state machine, possibly getting enough shares 
+            // to reconstruct a full message:
+            let res_accept = self.frag_state_machine.accept_share(&dgram);
+            if let (Ok(_), Some(feedback)) = (&res_accept, self.opt_feedback.as_ref()) {
+                // Report back only to the sources of valid shares of messages being received:
+                feedback.received_share(array_ref![dgram, 0, MESSAGE_ID_LEN], &address);
+            }
+            let opt_unite_job = res_accept.unwrap_or(None);
+            if let Some(ref buffer_pool) = self.opt_buffer_pool {
+                buffer_pool.give(dgram);
+            }
 
-            match msg_res {
-                Some(msg) => {
-                    // We got a full message. 
-                    // We break outside of the loop.
-                    total_msg = msg;
-                    last_address = address;
-                    break;
-                }
-                None => {},
+            let unite_job = match opt_unite_job {
+                Some(unite_job) => unite_job,
+                None => continue,
+            };
+            let message_id = *unite_job.message_id();
+
+            let decoding = match self.opt_parallel_decoder {
+                Some(ref parallel_decoder) if unite_job.len() >= parallel_decoder.min_msg_len => 
+                    Decoding::InProgress(parallel_decoder.cpu_pool.spawn_fn(move || {
+                        unite_job.unite()
+                    })),
+                _ => {
+                    let unite_res = unite_job.unite();
+                    if self.decode_queue.is_empty() {
+                        match self.united(&message_id, unite_res) {
+                            // We have a full message:
+                            Some(msg) => return Ok(Async::Ready(Some((msg, address)))),
+                            None => continue,
+                        }
+                    }
+                    // Wait for the messages before this one:
+                    Decoding::Done(Some(unite_res))
+                },
             };
+            self.decode_queue.push_back((address, message_id, decoding));
         }
-
-        // We have a full message:
-        Ok(Async::Ready(Some((total_msg, last_address))))
     }
 }
 
@@ -93,7 +297,7 @@ mod tests {
     use super::*;
     use std::collections::VecDeque;
     use tokio_core::reactor::Core;
-    use futures::{Sink, Future};
+    use futures::{stream, Sink, Future};
     use futures::future::{loop_fn, Loop, ok};
     use futures::sync::mpsc;
 
@@ -123,6 +327,7 @@ mod tests {
 
 
     #[test]
+    #[allow(redundant_semicolons, clippy::manual_div_ceil)]
     fn test_frag_msg_receiver_basic() {
 
         // A maximum size of underlying datagram:
@@ -164,7 +369,7 @@ mod tests {
 
         let splitter = loop_fn(splitter, 
                                |SplitterState {time_sink, data_sink, time_turn, mut items} 
-                               : SplitterState<(Vec<u8>, u32)>| -> Box<Future<Item=_, Error=()>> {
+                               : SplitterState<(Vec<u8>, u32)>| -> Box<dyn Future<Item=_, Error=()>> {
             if time_turn {
                 Box::new(time_sink
                     .send(())
@@ -207,4 +412,32 @@ mod tests {
         assert_eq!(address, ADDRESS);
         assert_eq!(message, orig_message);
     }
+    #[test]
+    fn test_frag_msg_receiver_feedback_sources() {
+        let orig_message = b"This is some message to be split";
+        let frags = split_message(orig_message, b"nonce123", 22).unwrap();
+        let b = frags.len().div_ceil(2);
+
+        // A corrupted share arrives first from another address, and a share of the already
+        // reconstructed message arrives last:
+        let mut corrupted = frags[0].clone();
+        corrupted[MESSAGE_ID_LEN + 2] ^= 1;
+        let mut items = vec![(corrupted, 9u32)];
+        items.extend(frags.iter().take(b).map(|frag| (frag.clone(), 1u32)));
+        items.push((frags[b].clone(), 9u32));
+
+        let recv_stream = stream::iter_ok::<_, ()>(items);
+        let recv_time_tick = stream::poll_fn(|| Ok(Async::NotReady));
+        let mut fmr = FragMsgReceiver::new(recv_stream, recv_time_tick);
+        let (feedback, _) = DeliveryFeedback::new(40);
+        fmr.set_feedback(feedback.clone());
+
+        let received = fmr.collect().wait().unwrap();
+        assert_eq!(received, vec![(orig_message.to_vec(), 1)]);
+
+        // The report is sent only to the source of the valid shares:
+        let (_, address) = feedback.pop_report().unwrap();
+        assert_eq!(address, 1);
+        assert!(feedback.pop_report().is_none());
+    }
 }
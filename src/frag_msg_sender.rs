@@ -1,22 +1,212 @@
-use std::collections::VecDeque;
+use std::{mem, cmp};
+use std::time::Instant;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
 use std::marker::PhantomData;
 
-use futures::{Sink, Poll, StartSend, AsyncSink};
+use futures::{Future, Sink, Poll, StartSend, AsyncSink, Async};
+use futures_cpupool::{CpuPool, CpuFuture};
 use rand::Rng;
+use tokio_core::reactor::Handle;
 
-use ::messages::{split_message, NONCE_LEN};
+use ::buffer_pool::BufferPool;
+use ::rate_limit::{Pacer, Pacing, Length, QueueItem};
+use ::blocks::max_message;
+use ::fragmenter::{Fragmenter, CompatRng, FragmentError, Redundancy, SenderParams};
+use ::pmtu::PathMtu;
+use ::feedback::DeliveryFeedback;
+use ::cancel::{CancelHandle, SupersedeKeys};
+use ::send_queue::{SendQueue, Addresses};
 
 
-struct PendingDgrams<A> {
-    address: A,
-    dgrams: VecDeque<Vec<u8>>,
+/// Options of a message sent with FragMsgSender::start_send_msg().
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    /// Datagrams of the message that were not sent by the deadline are dropped, 
+    /// both by the FragMsgSender and by a rate limiter after it.
+    pub opt_deadline: Option<Instant>,
+    /// Datagrams of messages with a higher priority are sent before datagrams of queued
+    /// messages with a lower priority, both by the FragMsgSender and by a rate limiter after it.
+    /// Messages of the same priority are sent in the order they were received.
+    pub priority: u8,
+    /// Keep a clone of the handle to cancel the message later.
+    /// Messages sent with clones of the same SendOptions share the handle, and are all 
+    /// cancelled together.
+    pub opt_cancel: Option<CancelHandle>,
+    /// A new message cancels all the previous messages with the same key, for example older
+    /// versions of some state. Keys are shared by all the destinations of the FragMsgSender.
+    /// Superseding a message cancels its handle, and so every other message sharing it.
+    pub opt_supersede_key: Option<u64>,
+    /// Produce a DeliveryOutcome with this id for every address of the message.
+    /// Requires feedback on the sender, and on the remote receivers. 
+    /// See FragMsgSender::set_feedback(). Messages dropped before they were encoded,
+    /// for example because they were cancelled, have no outcome.
+    pub opt_track_id: Option<u64>,
 }
 
+impl SendOptions {
+    /// Create a handle to cancel the message sent with these options.
+    /// If these options are used for several messages, the handle cancels all of them.
+    pub fn cancel_handle(&mut self) -> CancelHandle {
+        self.opt_cancel.get_or_insert_with(CancelHandle::new).clone()
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        match self.opt_cancel {
+            Some(ref cancel_handle) => cancel_handle.is_cancelled(),
+            None => false,
+        }
+    }
+
+    pub(crate) fn is_expired(&self, now: Instant) -> bool {
+        match self.opt_deadline {
+            Some(deadline) => now >= deadline,
+            None => false,
+        }
+    }
+}
+
+/// A message to be sent to one or more addresses.
+#[derive(Debug, Clone)]
+pub struct OutMessage<A> {
+    pub msg: Vec<u8>,
+    pub addresses: Vec<A>,
+    pub options: SendOptions,
+}
+
+/// A datagram handed by FragMsgSender to the underlying sink.
+/// A plain (datagram, address) pair drops the options of the message.
+/// TaggedDgram keeps them, so that a rate limiter could use them.
+pub trait DgramItem<A> {
+    fn from_dgram(dgram: Vec<u8>, address: A, options: &SendOptions) -> Self;
+    fn into_dgram(self) -> (Vec<u8>, A);
+}
+
+impl<A> DgramItem<A> for (Vec<u8>, A) {
+    fn from_dgram(dgram: Vec<u8>, address: A, _options: &SendOptions) -> Self {
+        (dgram, address)
+    }
+
+    fn into_dgram(self) -> (Vec<u8>, A) {
+        self
+    }
+}
+
+/// A datagram together with the options of the message it belongs to.
+#[derive(Debug, Clone)]
+pub struct TaggedDgram<A> {
+    pub dgram: Vec<u8>,
+    pub address: A,
+    pub options: SendOptions,
+}
+
+impl<A> DgramItem<A> for TaggedDgram<A> {
+    fn from_dgram(dgram: Vec<u8>, address: A, options: &SendOptions) -> Self {
+        TaggedDgram {
+            dgram,
+            address,
+            options: options.clone(),
+        }
+    }
+
+    fn into_dgram(self) -> (Vec<u8>, A) {
+        (self.dgram, self.address)
+    }
+}
+
+impl<A> Length for TaggedDgram<A> {
+    fn len(&self) -> usize {
+        self.dgram.len()
+    }
+}
+
+impl<A> QueueItem for TaggedDgram<A> {
+    fn opt_deadline(&self) -> Option<Instant> {
+        self.options.opt_deadline
+    }
+
+    fn priority(&self) -> u8 {
+        self.options.priority
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.options.is_cancelled()
+    }
+}
+
+/// Messages and datagrams dropped by a FragMsgSender.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SenderStats {
+    /// Messages not fully sent by their deadline.
+    pub expired_messages: usize,
+    /// Datagrams dropped because their deadline passed.
+    pub expired_dgrams: usize,
+    /// Messages cancelled before they were fully sent.
+    pub cancelled_messages: usize,
+    /// Datagrams dropped because their message was cancelled.
+    pub cancelled_dgrams: usize,
+    /// Messages dropped because encoding them failed.
+    pub failed_messages: usize,
+}
+
+/// Errors of FragMsgSender::start_send_msg() and start_send_to_many().
+/// A message that could not be sent is returned inside the error.
+#[derive(Debug)]
+pub enum SendMsgError<T> {
+    /// The message has no addresses.
+    NoAddresses(T),
+    /// The message can not be split according to the parameters of its addresses.
+    InvalidParams(T, FragmentError),
+    /// The underlying sink failed.
+    SinkError,
+}
+
+impl<T> SendMsgError<T> {
+    fn map_msg<U, F: FnOnce(T) -> U>(self, f: F) -> SendMsgError<U> {
+        match self {
+            SendMsgError::NoAddresses(msg) => SendMsgError::NoAddresses(f(msg)),
+            SendMsgError::InvalidParams(msg, e) => SendMsgError::InvalidParams(f(msg), e),
+            SendMsgError::SinkError => SendMsgError::SinkError,
+        }
+    }
+}
+
+enum Encoding {
+    Done(Result<VecDeque<Vec<u8>>, FragmentError>),
+    InProgress(CpuFuture<VecDeque<Vec<u8>>, FragmentError>),
+}
+
+struct ParallelEncoder {
+    cpu_pool: CpuPool,
+    min_msg_len: usize,
+    max_in_progress: usize,
+}
+
+// Parameters for messages sent to every destination:
+type ParamsFn<A> = Box<dyn Fn(&A) -> SenderParams>;
+
+// A message and the addresses it is sent to:
+type ManyItem<A> = (Vec<u8>, Vec<A>);
+
+// A message waiting to be sent:
+type SendItem<A> = (Vec<u8>, Addresses<A>, SendOptions);
+
 pub struct FragMsgSender<A,R,SK,SKE> {
     send_sink: SK,
-    max_dgram_len: usize,
-    rng: R,
-    opt_pending_dgrams: Option<PendingDgrams<A>>,
+    fragmenter: Fragmenter<CompatRng<R>>,
+    // Messages whose datagrams are being sent, interleaved:
+    send_queue: SendQueue<A>,
+    opt_parallel_encoder: Option<ParallelEncoder>,
+    opt_pacer: Option<Pacer>,
+    opt_path_mtu: Option<PathMtu<A>>,
+    opt_params_of: Option<ParamsFn<A>>,
+    opt_feedback: Option<DeliveryFeedback<A>>,
+    // Path MTU probes and echoes and delivery reports, sent before any message datagrams, at the highest priority:
+    control_queue: VecDeque<(Vec<u8>, A)>,
+    // Messages waiting to be sent, by priority and then in their original order:
+    encode_queue: VecDeque<(Addresses<A>, SendOptions, Encoding)>,
+    supersede_keys: SupersedeKeys,
+    stats: SenderStats,
     phantom_sk: PhantomData<SK>,
     phantom_ske: PhantomData<SKE>,
 }
@@ -26,7 +216,8 @@ impl<A,R,SK,SKE> FragMsgSender<A,R,SK,SKE>
 where
     R: Rng,
     A: 'static,
-    SK: Sink<SinkItem=(Vec<u8>, A), SinkError=SKE>
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
 {
     pub fn new(send_sink: SK, max_dgram_len: usize, rng: R) -> Self {
         // Make sure that max_dgram_len is not too large,
@@ -34,14 +225,79 @@ where
 
         FragMsgSender {
             send_sink, 
-            max_dgram_len,
-            rng,
-            opt_pending_dgrams: None,
+            fragmenter: Fragmenter::new(max_dgram_len, CompatRng(rng)),
+            send_queue: SendQueue::new(),
+            opt_parallel_encoder: None,
+            opt_pacer: None,
+            opt_path_mtu: None,
+            opt_params_of: None,
+            opt_feedback: None,
+            control_queue: VecDeque::new(),
+            encode_queue: VecDeque::new(),
+            supersede_keys: SupersedeKeys::new(),
+            stats: SenderStats::default(),
             phantom_sk: PhantomData,
             phantom_ske: PhantomData,
         }
     }
 
+    /// Take buffers for outgoing datagrams from the given pool, instead of allocating them.
+    /// Buffers could be returned into the pool once sent, for example by PooledDgramCodec.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.fragmenter.set_buffer_pool(buffer_pool);
+    }
+
+    /// Encode messages of at least min_msg_len bytes on the given thread pool.
+    /// At most max_in_progress messages are queued before applying backpressure.
+    /// Messages of the same priority are still sent in the order they were received.
+    pub fn set_cpu_pool(&mut self, cpu_pool: CpuPool, min_msg_len: usize, 
+                        max_in_progress: usize) {
+
+        self.opt_parallel_encoder = Some(ParallelEncoder {
+            cpu_pool,
+            min_msg_len,
+            max_in_progress,
+        });
+    }
+
+    /// Spread the datagrams of every message over time, instead of sending them back to back.
+    /// The gaps are kept between datagrams of interleaved messages too. With Pacing::Window,
+    /// the window is divided by the datagrams of all the interleaved messages, so that each of
+    /// them is still spread over the window.
+    pub fn set_pacing(&mut self, pacing: Pacing, handle: &Handle) {
+        self.opt_pacer = Some(Pacer::new(pacing, handle));
+    }
+
+    pub fn stats(&self) -> SenderStats {
+        self.stats.clone()
+    }
+
+    /// Maximum length of sent datagrams, if neither per destination parameters nor path MTU
+    /// discovery are used.
+    pub fn max_dgram_len(&self) -> usize {
+        self.fragmenter.max_dgram_len()
+    }
+
+    /// Split messages according to the parameters params_of returns for their destination,
+    /// instead of max_dgram_len given to new(). If path MTU discovery is also used,
+    /// the discovered lengths are capped by the returned max_dgram_len.
+    /// Messages to destinations whose parameters are invalid are returned with
+    /// SendMsgError::InvalidParams.
+    pub fn set_params_fn<F>(&mut self, params_of: F) 
+    where
+        F: Fn(&A) -> SenderParams + 'static,
+    {
+        self.opt_params_of = Some(Box::new(params_of));
+    }
+
+    /// Interleave the datagrams of up to depth messages of the same priority round-robin, 
+    /// so that a burst of losses is spread across many messages. The default depth of 1 sends
+    /// the datagrams of every message contiguously, unless a message of higher priority 
+    /// overtakes it.
+    pub fn set_interleave_depth(&mut self, depth: usize) {
+        self.send_queue.set_interleave_depth(depth);
+    }
+
     /*
     /// Get the original inner send_sink
     fn into_inner(self) -> SK {
@@ -50,80 +306,448 @@ where
     */
 }
 
-impl<A,R,SK,SKE> Sink for FragMsgSender<A,R,SK,SKE>
+impl<A,R,SK,SKE> FragMsgSender<A,R,SK,SKE> 
 where
-    A: Copy,
     R: Rng,
-    SK: Sink<SinkItem=(Vec<u8>, A), SinkError=SKE>
+    A: Hash + Eq + Clone + 'static,
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
 {
-    type SinkItem = (Vec<u8>, A);
-    type SinkError = ();
+    /// Discover the largest datagram length for every destination, and split messages
+    /// accordingly. max_dgram_len given to new() is then not used.
+    /// The same path_mtu should be given to the receiver of the socket, which answers probes
+    /// and receives their echoes. See FragMsgReceiver::set_path_mtu().
+    pub fn set_path_mtu(&mut self, path_mtu: PathMtu<A>) {
+        self.opt_path_mtu = Some(path_mtu);
+    }
 
-    fn start_send(&mut self, item: Self::SinkItem) 
-        -> StartSend<Self::SinkItem, Self::SinkError> {
+    /// Track the delivery of messages sent with SendOptions::opt_track_id, using the reports
+    /// of the remote receivers. The same feedback should be given to the receiver of the
+    /// socket, which receives the reports, and sends reports to remote senders.
+    /// See FragMsgReceiver::set_feedback().
+    pub fn set_feedback(&mut self, feedback: DeliveryFeedback<A>) {
+        self.opt_feedback = Some(feedback);
+    }
 
-        let (msg, address) = item;
+    /// Split messages according to the parameters in table for their destination.
+    /// Destinations missing from table get max_dgram_len given to new(), and all the shares.
+    /// Fails if messages could not be split according to some parameters in table.
+    pub fn set_params_table(&mut self, table: HashMap<A, SenderParams>) 
+        -> Result<(), FragmentError> {
 
-        match self.opt_pending_dgrams.take() {
-            Some(pending_dgrams) => self.opt_pending_dgrams = Some(pending_dgrams),
-            None => {
-                // Generate a random nonce:
-                let nonce: &mut [u8; NONCE_LEN] = &mut [0; NONCE_LEN];
-                self.rng.fill_bytes(nonce);
+        for params in table.values() {
+            params.check()?;
+        }
+        let default_params = SenderParams {
+            max_dgram_len: self.max_dgram_len(),
+            redundancy: Redundancy::Full,
+        };
+        self.set_params_fn(move |address| match table.get(address) {
+            Some(params) => *params,
+            None => default_params,
+        });
+        Ok(())
+    }
+}
 
-                let dgrams = match split_message(
-                        msg.as_ref(), nonce, self.max_dgram_len) {
+impl<A,R,SK,SKE> FragMsgSender<A,R,SK,SKE>
+where
+    A: Hash + Eq + Clone,
+    R: Rng,
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
+{
+    /// Parameters for messages sent to address.
+    /// Lengths discovered by path MTU discovery are at least the min_dgram_len of its
+    /// PmtuConfig, which is validated to carry a block.
+    pub fn params_to(&self, address: &A) -> SenderParams {
+        let mut params = match self.opt_params_of {
+            Some(ref params_of) => params_of(address),
+            None => SenderParams {
+                max_dgram_len: self.fragmenter.max_dgram_len(),
+                redundancy: Redundancy::Full,
+            },
+        };
+        if let Some(ref path_mtu) = self.opt_path_mtu {
+            let discovered = path_mtu.max_dgram_len(address);
+            params.max_dgram_len = match self.opt_params_of {
+                Some(_) => cmp::min(params.max_dgram_len, discovered),
+                None => discovered,
+            };
+        }
+        params
+    }
 
-                    Ok(dgrams) => dgrams,
-                    Err(_) => panic!("Failed to split message!"),
-                }.into_iter().collect::<VecDeque<_>>(); 
+    /// Maximum length of a message sent to address as a single Fragmentos message.
+    /// Larger messages are split into a few blocks, and arrive only if all of their blocks
+    /// arrive.
+    pub fn max_message_to(&self, address: &A) -> Result<usize, ()> {
+        max_message(self.params_to(address).max_dgram_len)
+    }
 
-                self.opt_pending_dgrams = Some(PendingDgrams {
-                    address,
-                    dgrams,
-                });
+    /// Parameters for a message sent to all the given addresses:
+    /// Datagrams must fit the paths to all of them, using the largest redundancy.
+    /// Returns None if there are no addresses.
+    fn params_to_many(&self, addresses: &[A]) -> Option<SenderParams> {
+        let mut params_iter = addresses.iter().map(|address| self.params_to(address));
+        let first = params_iter.next()?;
+        Some(params_iter.fold(first, |acc, params| SenderParams {
+            max_dgram_len: cmp::min(acc.max_dgram_len, params.max_dgram_len),
+            redundancy: match (acc.redundancy, params.redundancy) {
+                (Redundancy::Extra(x), Redundancy::Extra(y)) => 
+                    Redundancy::Extra(cmp::max(x, y)),
+                _ => Redundancy::Full,
+            },
+        }))
+    }
+
+    /// Send all the queued path MTU probes and echoes, and delivery reports.
+    fn flush_control(&mut self) -> Poll<(), ()> {
+        if let Some(ref path_mtu) = self.opt_path_mtu {
+            while let Some(echo) = path_mtu.pop_echo() {
+                self.control_queue.push_back(echo);
+            }
+        }
+        if let Some(ref feedback) = self.opt_feedback {
+            while let Some(report) = feedback.pop_report() {
+                self.control_queue.push_back(report);
             }
+        }
+        let control_options = SendOptions {
+            priority: u8::MAX,
+            ..SendOptions::default()
         };
+        while let Some((dgram, address)) = self.control_queue.pop_front() {
+            let item = SK::SinkItem::from_dgram(dgram, address, &control_options);
+            match self.send_sink.start_send(item) {
+                Ok(AsyncSink::Ready) => {},
+                Ok(AsyncSink::NotReady(item)) => {
+                    self.control_queue.push_front(item.into_dgram());
+                    return Ok(Async::NotReady);
+                },
+                Err(_) => return Err(()),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
 
-        {
-            // Send all possible datagrams:
-            let pending_dgrams = match self.opt_pending_dgrams {
-                None => panic!("Invalid state!"),
-                Some(ref mut pending_dgrams) => pending_dgrams,
+    /// Move encoded messages from the encode queue into the interleaving queue,
+    /// keeping the order of the encode queue.
+    fn fill_pending(&mut self) -> Result<(), ()> {
+        loop {
+            match self.encode_queue.front() {
+                Some((addresses, options, _)) 
+                    if self.send_queue.has_room(options.priority, addresses.as_slice()) => {},
+                _ => break,
+            }
+            let res_dgrams = match self.encode_queue.front_mut() {
+                None => break,
+                Some(&mut (_, _, Encoding::Done(ref mut res_dgrams))) => 
+                    mem::replace(res_dgrams, Ok(VecDeque::new())),
+                Some(&mut (_, _, Encoding::InProgress(ref mut cpu_future))) => 
+                    match cpu_future.poll() {
+                        Ok(Async::Ready(dgrams)) => Ok(dgrams),
+                        Ok(Async::NotReady) => break,
+                        Err(e) => Err(e),
+                    },
             };
+            let (addresses, options, _) = self.encode_queue.pop_front().unwrap();
+            self.push_encoded(addresses, options, res_dgrams);
+        }
+        Ok(())
+    }
+
+    /// Push an encoded message into the interleaving queue. 
+    /// A message whose encoding failed is dropped.
+    fn push_encoded(&mut self, addresses: Addresses<A>, options: SendOptions, 
+                    res_dgrams: Result<VecDeque<Vec<u8>>, FragmentError>) {
+        match res_dgrams {
+            Ok(dgrams) => self.push_pending(addresses, options, dgrams),
+            Err(_) => self.stats.failed_messages += 1,
+        }
+    }
+
+    fn push_pending(&mut self, addresses: Addresses<A>, options: SendOptions, 
+                    dgrams: VecDeque<Vec<u8>>) {
+        let dgrams = Vec::from(dgrams);
+        if let (Some(track_id), Some(feedback)) = (options.opt_track_id, self.opt_feedback.as_ref()) {
+            feedback.track_dgrams(track_id, &dgrams, addresses.as_slice());
+        }
+        self.send_queue.push(dgrams, addresses, options);
+    }
 
-            while let Some(dgram) = pending_dgrams.dgrams.pop_front() {
-                match self.send_sink.start_send(
-                    (dgram, pending_dgrams.address)) {
+    /// Send as many pending datagrams as possible, in the order of the send queue.
+    /// If the underlying sink is not ready for a datagram to some address, that address waits
+    /// until the next flush, while sending continues to the other addresses.
+    /// Returns Async::Ready if there is nothing left to send.
+    fn flush_pending(&mut self) -> Poll<(), ()> {
+        if self.flush_control()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        self.send_queue.unblock();
+        let now = Instant::now();
+        loop {
+            self.fill_pending()?;
+            if let Some(ref mut pacer) = self.opt_pacer {
+                if pacer.poll_ready().map_err(|_| ())?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+            }
+            let (dgram, popped) = match self.send_queue.pop_dgram(now, &mut self.stats) {
+                Some(popped) => popped,
+                None if self.send_queue.is_empty() && self.encode_queue.is_empty() => 
+                    return Ok(Async::Ready(())),
+                // Waiting for blocked destinations, or for messages to be encoded:
+                None => return Ok(Async::NotReady),
+            };
 
-                    Ok(AsyncSink::Ready) => {},
-                    Ok(AsyncSink::NotReady((dgram, _))) => {
-                        pending_dgrams.dgrams.push_front(dgram);
-                        return Ok(AsyncSink::NotReady((msg, address)));
-                    }
-                    Err(_) => return Err(()),
+            let item = SK::SinkItem::from_dgram(dgram, popped.address().clone(), popped.options());
+            match self.send_sink.start_send(item) {
+                Ok(AsyncSink::Ready) => {},
+                Ok(AsyncSink::NotReady(item)) => {
+                    // Retry later, while sending to the other destinations:
+                    self.send_queue.unsent(popped, item.into_dgram().0);
+                    continue;
+                },
+                Err(_) => return Err(()),
+            }
+            self.send_queue.sent(popped);
+            if let Some(ref mut pacer) = self.opt_pacer {
+                // Interleaved messages share the gaps. Only once all of them were sent, the
+                // first datagram of the next message could be sent right away:
+                match self.send_queue.num_dgrams() {
+                    0 => pacer.reset(),
+                    num_dgrams => pacer.sent(num_dgrams),
                 }
             }
         }
-        self.opt_pending_dgrams = None;
+    }
+}
+
+impl<A,R,SK,SKE> FragMsgSender<A,R,SK,SKE>
+where
+    A: Hash + Eq + Clone,
+    R: Rng,
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
+{
+    /// Send the same message to all the given addresses.
+    /// The message is encoded once, and the same datagrams are sent to every address.
+    /// Like Sink::start_send, returns the message and addresses back if there is no room for 
+    /// the message yet.
+    ///
+    /// While the underlying sink is not ready for one of the addresses, sending continues to
+    /// the others.
+    pub fn start_send_to_many(&mut self, msg: Vec<u8>, addresses: Vec<A>)
+        -> StartSend<ManyItem<A>, SendMsgError<ManyItem<A>>> {
+
+        let out_message = OutMessage {
+            msg,
+            addresses,
+            options: SendOptions::default(),
+        };
+        let async_sink = self.start_send_msg(out_message)
+            .map_err(|e| e.map_msg(|out_message| (out_message.msg, out_message.addresses)))?;
+        Ok(match async_sink {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(out_message) => 
+                AsyncSink::NotReady((out_message.msg, out_message.addresses)),
+        })
+    }
+
+    /// Send a message with the given options.
+    /// Returns the message back if there is no room for it yet, or inside the error if it can
+    /// not be sent.
+    pub fn start_send_msg(&mut self, out_message: OutMessage<A>) 
+        -> StartSend<OutMessage<A>, SendMsgError<OutMessage<A>>> {
+
+        let OutMessage { msg, addresses, options } = out_message;
+        let into_out_message = |(msg, addresses, options): SendItem<A>| 
+            OutMessage { msg, addresses: addresses.into_vec(), options };
+        let async_sink = self.start_send_to(msg, Addresses::from(addresses), options)
+            .map_err(|e| e.map_msg(into_out_message))?;
+        Ok(match async_sink {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady(item) => AsyncSink::NotReady(into_out_message(item)),
+        })
+    }
+
+    fn start_send_to(&mut self, msg: Vec<u8>, addresses: Addresses<A>, mut options: SendOptions)
+        -> StartSend<SendItem<A>, SendMsgError<SendItem<A>>> {
+
+        let params = match self.params_to_many(addresses.as_slice()) {
+            Some(params) => params,
+            None => return Err(SendMsgError::NoAddresses((msg, addresses, options))),
+        };
+        self.flush_pending().map_err(|()| SendMsgError::SinkError)?;
+        if options.is_cancelled() {
+            self.stats.cancelled_messages += 1;
+            return Ok(AsyncSink::Ready);
+        }
+        if options.is_expired(Instant::now()) {
+            self.stats.expired_messages += 1;
+            return Ok(AsyncSink::Ready);
+        }
+        let opt_supersede = options.opt_supersede_key
+            .map(|key| (key, options.cancel_handle()));
+
+        // Keep probing while there is traffic to the addresses:
+        if let Some(ref path_mtu) = self.opt_path_mtu {
+            for address in addresses.as_slice() {
+                if let Some(probe) = path_mtu.poll_probe(address) {
+                    self.control_queue.push_back((probe, address.clone()));
+                }
+            }
+        }
+
+        // There is room for another message in the interleaving queue, 
+        // and no queued message comes before it:
+        let priority = options.priority;
+        let has_room = self.send_queue.has_room(priority, addresses.as_slice()) && !self.encode_queue.iter()
+            .any(|(_, queued_options, _)| queued_options.priority >= priority);
+
+        let use_cpu_pool = match self.opt_parallel_encoder {
+            Some(ref parallel_encoder) => msg.len() >= parallel_encoder.min_msg_len,
+            None => false,
+        };
+
+        let encode_now = has_room && !use_cpu_pool;
+        let can_queue = match self.opt_parallel_encoder {
+            Some(ref parallel_encoder) => 
+                self.encode_queue.len() < parallel_encoder.max_in_progress,
+            None => false,
+        };
+        if !encode_now && !can_queue {
+            return Ok(AsyncSink::NotReady((msg, addresses, options)));
+        }
+        let encode_job = match self.fragmenter.encode_job(msg, params) {
+            Ok(encode_job) => encode_job,
+            Err((msg, e)) => return Err(SendMsgError::InvalidParams((msg, addresses, options), e)),
+        };
+
+        if encode_now {
+            // Encode the message right away:
+            self.push_encoded(addresses, options, encode_job.encode());
+        } else {
+            let encoding = match self.opt_parallel_encoder {
+                Some(ref parallel_encoder) if use_cpu_pool => 
+                    Encoding::InProgress(parallel_encoder.cpu_pool.spawn_fn(move || {
+                        encode_job.encode()
+                    })),
+                // Small messages are encoded right away, 
+                // but still wait for the messages before them.
+                _ => Encoding::Done(encode_job.encode()),
+            };
+            // Queue the message after all the messages of the same or higher priority:
+            let index = self.encode_queue.iter()
+                .position(|(_, queued_options, _)| queued_options.priority < priority)
+                .unwrap_or(self.encode_queue.len());
+            self.encode_queue.insert(index, (addresses, options, encoding));
+        }
+
+        if let Some((key, cancel_handle)) = opt_supersede {
+            self.supersede_keys.supersede(key, &cancel_handle);
+        }
+
+        self.flush_pending().map_err(|()| SendMsgError::SinkError)?;
         Ok(AsyncSink::Ready)
     }
 
+    /// A future that completes once the message was sent to all the given addresses.
+    pub fn send_to_many(self, msg: Vec<u8>, addresses: Vec<A>) -> SendToMany<A,R,SK,SKE> {
+        SendToMany {
+            opt_frag_sender: Some(self),
+            opt_item: Some((msg, addresses)),
+        }
+    }
+}
+
+impl<A,R,SK,SKE> Sink for FragMsgSender<A,R,SK,SKE>
+where
+    A: Hash + Eq + Clone,
+    R: Rng,
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
+{
+    type SinkItem = (Vec<u8>, A);
+    type SinkError = ();
+
+    /// Fails if the message can not be split according to the parameters of its address.
+    /// Use start_send_msg() to get such messages back, together with the reason.
+    fn start_send(&mut self, item: Self::SinkItem) 
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+
+        let (msg, address) = item;
+        let async_sink = self.start_send_to(msg, Addresses::One(address), SendOptions::default())
+            .map_err(|_| ())?;
+        Ok(match async_sink {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady((msg, addresses, _)) => match addresses {
+                Addresses::One(address) => AsyncSink::NotReady((msg, address)),
+                Addresses::Many(_) => unreachable!(),
+            },
+        })
+    }
+
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.send_sink.poll_complete().map_err(|_| ())
+        let flush_res = self.flush_pending()?;
+        let inner_res = self.send_sink.poll_complete().map_err(|_| ())?;
+        if flush_res.is_ready() && inner_res.is_ready() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
     }
 
 }
 
+/// Future returned by FragMsgSender::send_to_many().
+/// Resolves into the FragMsgSender once the message was sent to all the addresses.
+pub struct SendToMany<A,R,SK,SKE> {
+    opt_frag_sender: Option<FragMsgSender<A,R,SK,SKE>>,
+    opt_item: Option<ManyItem<A>>,
+}
+
+impl<A,R,SK,SKE> Future for SendToMany<A,R,SK,SKE>
+where
+    A: Hash + Eq + Clone,
+    R: Rng,
+    SK: Sink<SinkError=SKE>,
+    SK::SinkItem: DgramItem<A>,
+{
+    type Item = FragMsgSender<A,R,SK,SKE>;
+    type Error = SendMsgError<ManyItem<A>>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let frag_sender = self.opt_frag_sender.as_mut()
+                .expect("Polled SendToMany after completion");
+            if let Some((msg, addresses)) = self.opt_item.take() {
+                if let AsyncSink::NotReady(item) = frag_sender.start_send_to_many(msg, addresses)? {
+                    self.opt_item = Some(item);
+                    return Ok(Async::NotReady);
+                }
+            }
+            let poll_res = frag_sender.poll_complete()
+                .map_err(|()| SendMsgError::SinkError)?;
+            if poll_res.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(self.opt_frag_sender.take().unwrap()))
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::time::{Duration, Instant};
     use rand;
     use rand::{StdRng};
     use tokio_core::reactor::Core;
-    use futures::{Future, Stream};
+    use futures::{future, stream, Future, Stream};
     use futures::sync::mpsc;
 
     use ::state_machine::FragStateMachine;
@@ -157,8 +781,41 @@ mod tests {
     }
     */
 
+    type ChannelSender = FragMsgSender<u32, StdRng, mpsc::Sender<(Vec<u8>, u32)>,
+                                       mpsc::SendError<(Vec<u8>, u32)>>;
+
+    fn new_rng() -> StdRng {
+        let seed: &[_] = &[1,2,3,4,5];
+        rand::SeedableRng::from_seed(seed)
+    }
+
+    /// A sender of datagrams of up to 22 bytes into a channel without buffer space,
+    /// which applies backpressure after every datagram.
+    fn new_channel_sender() -> (ChannelSender, mpsc::Receiver<(Vec<u8>, u32)>) {
+        let (send_sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+        (FragMsgSender::new(send_sink, 22, new_rng()), stream)
+    }
+
+    /// Amount of datagrams of msg, when split by a sender from new_channel_sender().
+    fn num_dgrams(msg: &[u8]) -> usize {
+        Fragmenter::new(22, CompatRng(new_rng())).fragment(msg).unwrap().len()
+    }
+
+    /// Start sending out_messages while nobody reads from the channel of fms yet.
+    /// Only the first datagram is sent, and the rest wait inside fms.
+    fn start_send_blocked(core: &mut Core, mut fms: ChannelSender, 
+                          out_messages: Vec<OutMessage<u32>>) -> ChannelSender {
+        core.run(future::lazy(move || {
+            for out_message in out_messages {
+                assert!(fms.start_send_msg(out_message).unwrap().is_ready());
+            }
+            Ok::<_, ()>(fms)
+        })).unwrap()
+    }
+
 
     #[test]
+    #[allow(clippy::manual_div_ceil, clippy::needless_range_loop, clippy::needless_borrow)]
     fn test_frag_msg_sender_basic() {
         let orig_message: Vec<u8> = b"This is some message to be split".to_vec();
         let orig_message_copy = orig_message.clone();
@@ -166,6 +823,7 @@ mod tests {
         // A maximum size of underlying datagram:
         const MAX_DGRAM_LEN: usize = 22;
         const ADDRESS: u32 = 0x12345678;
+        const MAX_TOTAL_MESSAGE: usize = 1 << 16;
 
         let seed: &[_] = &[1,2,3,4,5];
         let rng: StdRng = rand::SeedableRng::from_seed(seed);
@@ -190,7 +848,7 @@ mod tests {
         }
 
         // Feed a Fragmentos state machine with the sent messages:
-        let mut fsm = FragStateMachine::new();
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
 
         let b = (sent_dgrams.len() + 1) / 2;
         for i in 0 .. b - 1 {
@@ -207,5 +865,414 @@ mod tests {
 
         assert_eq!(united, orig_message_copy);
     }
-}
 
+    #[test]
+    fn test_frag_msg_sender_pacing() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (mut fms, stream) = new_channel_sender();
+        fms.set_pacing(Pacing::Gap(Duration::from_millis(5)), &handle);
+        let send_msg_fut = fms.send((b"This is some message to be split".to_vec(), 0));
+        handle.spawn(send_msg_fut.then(|_| Ok(())));
+
+        let mut recv_times = Vec::new();
+        {
+            let collector = stream.for_each(|_item| {
+                recv_times.push(Instant::now());
+                Ok(())
+            });
+            core.run(collector).unwrap();
+        }
+
+        assert!(recv_times.len() > 1);
+        for pair in recv_times.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(4));
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_pacing_interleave() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let short_message = b"A short message".to_vec();
+        let long_message = b"A longer message, which is still being sent after the short one".to_vec();
+        let total_dgrams = num_dgrams(&short_message) + num_dgrams(&long_message);
+        assert!(num_dgrams(&long_message) > num_dgrams(&short_message));
+
+        // A gap of 10ms between any two datagrams:
+        let window = Duration::from_millis(10) * total_dgrams as u32;
+        let (mut fms, stream) = new_channel_sender();
+        fms.set_interleave_depth(2);
+        fms.set_pacing(Pacing::Window(window), &handle);
+        let messages = vec![(short_message, 1u32), (long_message, 2u32)];
+        let send_all = fms.send_all(stream::iter_ok(messages));
+        handle.spawn(send_all.then(|_| Ok(())));
+
+        let mut received = Vec::new();
+        {
+            let collector = stream.for_each(|(_, address)| {
+                received.push((Instant::now(), address));
+                Ok(())
+            });
+            core.run(collector).unwrap();
+        }
+
+        // The messages are interleaved:
+        assert_eq!(received.len(), total_dgrams);
+        let addresses = received.iter().map(|&(_, address)| address).collect::<Vec<u32>>();
+        let first_long = addresses.iter().position(|&address| address == 2).unwrap();
+        let last_short = addresses.iter().rposition(|&address| address == 1).unwrap();
+        assert!(first_long < last_short);
+        // The same gap is kept between datagrams of different messages, and after the short
+        // message is done:
+        for pair in received.windows(2) {
+            assert!(pair[1].0 - pair[0].0 >= Duration::from_millis(9));
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_interleave() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (mut fms, stream) = new_channel_sender();
+        fms.set_interleave_depth(4);
+        let messages = (0 .. 4u32)
+            .map(|i| (format!("This is message number {}, to be split", i).into_bytes(), i))
+            .collect::<Vec<_>>();
+        let send_all = fms.send_all(stream::iter_ok(messages.clone()));
+        handle.spawn(send_all.then(|_| Ok(())));
+
+        let sent_dgrams = core.run(stream.collect()).unwrap();
+
+        // Datagrams of the same message are never sent more than twice in a row:
+        let message_ids = sent_dgrams.iter()
+            .map(|(dgram, _)| dgram[0 .. 8].to_vec())
+            .collect::<Vec<_>>();
+        assert!(message_ids.windows(3).all(|w| w[0] != w[1] || w[1] != w[2]));
+
+        // All the messages are still received:
+        let mut fsm = FragStateMachine::new();
+        let mut received = sent_dgrams.iter()
+            .filter_map(|&(ref dgram, address)|
+                        fsm.received_frag_message(dgram).map(|msg| (msg, address)))
+            .collect::<Vec<_>>();
+        received.sort_by_key(|&(_, address)| address);
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn test_frag_msg_sender_send_to_many() {
+        let addresses = vec![1u32, 2, 3];
+        let orig_message = b"This is a message sent to many destinations".to_vec();
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (fms, stream) = new_channel_sender();
+        let send_to_many = fms.send_to_many(orig_message.clone(), addresses.clone());
+        handle.spawn(send_to_many.then(|_| Ok(())));
+
+        let sent_dgrams = core.run(stream.collect()).unwrap();
+
+        // Every address gets exactly the same datagrams, encoded once:
+        let dgrams_to = |address| sent_dgrams.iter()
+            .filter(|&&(_, dgram_address)| dgram_address == address)
+            .map(|(dgram, _)| dgram.clone())
+            .collect::<Vec<_>>();
+        let first_dgrams = dgrams_to(addresses[0]);
+        assert!(first_dgrams.len() > 1);
+        assert_eq!(first_dgrams.len() * addresses.len(), sent_dgrams.len());
+        for &address in &addresses[1 ..] {
+            assert_eq!(dgrams_to(address), first_dgrams);
+        }
+
+        let mut fsm = FragStateMachine::new();
+        let united = first_dgrams.iter()
+            .filter_map(|dgram| fsm.received_frag_message(dgram))
+            .next();
+        assert_eq!(united, Some(orig_message));
+    }
+
+    /// A sink that is never ready for datagrams to address 0, and keeps all the others.
+    #[derive(Default)]
+    struct StuckSink {
+        sent: Vec<(Vec<u8>, u32)>,
+    }
+
+    impl Sink for StuckSink {
+        type SinkItem = (Vec<u8>, u32);
+        type SinkError = ();
+
+        fn start_send(&mut self, item: Self::SinkItem) 
+            -> StartSend<Self::SinkItem, Self::SinkError> {
+
+            if item.1 == 0 {
+                return Ok(AsyncSink::NotReady(item));
+            }
+            self.sent.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_stuck_destination() {
+        let mut core = Core::new().unwrap();
+        let mut fms = FragMsgSender::new(StuckSink::default(), 22, new_rng());
+
+        let first_message = b"The first message for all destinations".to_vec();
+        let second_message = b"The second message, only for the others".to_vec();
+        let fms = core.run(future::lazy(move || {
+            assert!(fms.start_send_to_many(first_message.clone(), vec![1, 0, 2])
+                    .unwrap().is_ready());
+            assert!(fms.poll_complete().unwrap().is_not_ready());
+            // The first message only waits for the stuck destination, and takes no room:
+            assert!(fms.start_send_to_many(second_message.clone(), vec![2, 1])
+                    .unwrap().is_ready());
+            assert!(fms.poll_complete().unwrap().is_not_ready());
+            Ok::<_, ()>(fms)
+        })).unwrap();
+
+        assert_eq!(fms.send_queue.pending.len(), 1);
+        assert_eq!(fms.send_queue.pending[0].num_left(), num_dgrams(b"The first message for all destinations"));
+        for &address in &[1, 2] {
+            let mut fsm = FragStateMachine::new();
+            let united = fms.send_sink.sent.iter()
+                .filter(|&&(_, dgram_address)| dgram_address == address)
+                .filter_map(|(dgram, _)| fsm.received_frag_message(dgram))
+                .collect::<Vec<_>>();
+            assert_eq!(united, vec![b"The first message for all destinations".to_vec(),
+                                    b"The second message, only for the others".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_params_table() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (mut fms, stream) = new_channel_sender();
+        let mut table = HashMap::new();
+        table.insert(2u32, SenderParams { max_dgram_len: 60, redundancy: Redundancy::Full });
+        table.insert(3u32, SenderParams { max_dgram_len: 100, redundancy: Redundancy::Extra(0) });
+        fms.set_params_table(table).unwrap();
+        assert_eq!(fms.params_to(&1).max_dgram_len, 22);
+        assert_eq!(fms.max_message_to(&2), max_message(60));
+        assert!(fms.max_message_to(&3).unwrap() > fms.max_message_to(&2).unwrap());
+
+        let orig_message = b"This message is split differently for every destination".to_vec();
+        let messages = (1 .. 4u32).map(|address| (orig_message.clone(), address))
+            .collect::<Vec<_>>();
+        let send_all = fms.send_all(stream::iter_ok(messages.clone()));
+        handle.spawn(send_all.then(|_| Ok(())));
+
+        let sent_dgrams = core.run(stream.collect()).unwrap();
+        for &(max_dgram_len, address) in &[(22, 1), (60, 2), (100, 3)] {
+            let dgrams = sent_dgrams.iter()
+                .filter(|&&(_, dgram_address)| dgram_address == address)
+                .map(|(dgram, _)| dgram.clone())
+                .collect::<Vec<_>>();
+            assert!(dgrams.iter().all(|dgram| dgram.len() <= max_dgram_len));
+            if address == 3 {
+                // Only the shares required for reconstruction:
+                assert_eq!(dgrams.len(), 1);
+            } else {
+                assert!(dgrams.len() > 1);
+            }
+
+            let mut fsm = FragStateMachine::new();
+            let united = dgrams.iter()
+                .filter_map(|dgram| fsm.received_frag_message(dgram))
+                .next();
+            assert_eq!(united, Some(orig_message.clone()));
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_invalid_messages() {
+        let mut core = Core::new().unwrap();
+        let (mut fms, _stream) = new_channel_sender();
+
+        let mut table = HashMap::new();
+        table.insert(2u32, SenderParams { max_dgram_len: 18, redundancy: Redundancy::Full });
+        assert_eq!(fms.set_params_table(table), Err(FragmentError::DgramTooSmall));
+        fms.set_params_fn(|&address| SenderParams { 
+            max_dgram_len: if address == 2 { 18 } else { 22 },
+            redundancy: Redundancy::Full,
+        });
+
+        let orig_message = b"This message can not be sent".to_vec();
+        let fms = core.run(future::lazy(move || {
+            // Messages that can not be sent are returned:
+            match fms.start_send_to_many(orig_message.clone(), vec![]) {
+                Err(SendMsgError::NoAddresses((msg, addresses))) => {
+                    assert_eq!(msg, orig_message);
+                    assert!(addresses.is_empty());
+                },
+                _ => panic!("Message without addresses was accepted"),
+            }
+            match fms.start_send_to_many(orig_message.clone(), vec![1, 2]) {
+                Err(SendMsgError::InvalidParams((msg, addresses), e)) => {
+                    assert_eq!(msg, orig_message);
+                    assert_eq!(addresses, vec![1, 2]);
+                    assert_eq!(e, FragmentError::DgramTooSmall);
+                },
+                _ => panic!("Message with invalid parameters was accepted"),
+            }
+            Ok::<_, ()>(fms)
+        })).unwrap();
+        assert_eq!(fms.stats(), SenderStats::default());
+    }
+
+    #[test]
+    fn test_frag_msg_sender_deadline() {
+        let mut core = Core::new().unwrap();
+        let (fms, stream) = new_channel_sender();
+
+        // The first datagram of the first message is out, and the rest wait inside fms.
+        // The stale message arrives after its deadline:
+        let first_message = b"This message keeps the sender busy for a while".to_vec();
+        let stale_message = OutMessage {
+            msg: b"This message is no longer interesting".to_vec(),
+            addresses: vec![2],
+            options: SendOptions {
+                opt_deadline: Some(Instant::now()),
+                ..SendOptions::default()
+            },
+        };
+        let out_messages = vec![
+            OutMessage { msg: first_message.clone(), addresses: vec![1], options: SendOptions::default() },
+            stale_message,
+        ];
+        let fms = start_send_blocked(&mut core, fms, out_messages);
+
+        let fresh_message = b"This message should arrive".to_vec();
+        let send_fresh = fms.send((fresh_message.clone(), 3)).map(|fms| fms.stats());
+        let (stats, sent_dgrams) = core.run(send_fresh.join(stream.collect())).unwrap();
+
+        assert_eq!(stats, SenderStats {
+            expired_messages: 1,
+            ..SenderStats::default()
+        });
+        assert!(sent_dgrams.iter().all(|&(_, address)| address != 2));
+        for &(ref msg, address) in &[(first_message, 1), (fresh_message, 3)] {
+            let mut fsm = FragStateMachine::new();
+            let united = sent_dgrams.iter()
+                .filter(|&&(_, dgram_address)| dgram_address == address)
+                .filter_map(|(dgram, _)| fsm.received_frag_message(dgram))
+                .next();
+            assert_eq!(united.as_ref(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn test_frag_msg_sender_priority() {
+        let mut core = Core::new().unwrap();
+        let (fms, stream) = new_channel_sender();
+
+        let bulk_message = b"Some bulk data that could wait for a while".to_vec();
+        let urgent_message = b"Urgent control message".to_vec();
+        let num_bulk_dgrams = num_dgrams(&bulk_message);
+        let num_urgent_dgrams = num_dgrams(&urgent_message);
+
+        // The bulk message is still being sent when the urgent message arrives:
+        let out_messages = vec![
+            OutMessage { msg: bulk_message, addresses: vec![1], options: SendOptions::default() },
+            OutMessage { 
+                msg: urgent_message, 
+                addresses: vec![2], 
+                options: SendOptions {
+                    priority: 1,
+                    ..SendOptions::default()
+                },
+            },
+        ];
+        let fms = start_send_blocked(&mut core, fms, out_messages);
+
+        let flush_fms = fms.flush().map(drop);
+        let (_, sent_dgrams) = core.run(flush_fms.join(stream.collect())).unwrap();
+        let addresses = sent_dgrams.iter().map(|&(_, address)| address).collect::<Vec<u32>>();
+
+        // Only the first bulk datagram was sent before the urgent message:
+        let mut expected = vec![1];
+        expected.extend(vec![2; num_urgent_dgrams]);
+        expected.extend(vec![1; num_bulk_dgrams - 1]);
+        assert_eq!(addresses, expected);
+    }
+
+    #[test]
+    fn test_frag_msg_sender_cancel() {
+        let mut core = Core::new().unwrap();
+        let (mut fms, stream) = new_channel_sender();
+
+        fms.set_interleave_depth(3);
+        let msg = b"Some version of a state that changes often".to_vec();
+        let num_msg_dgrams = num_dgrams(&msg);
+
+        let mut options = SendOptions::default();
+        let cancel_handle = options.cancel_handle();
+        let state_options = SendOptions {
+            opt_supersede_key: Some(7),
+            ..SendOptions::default()
+        };
+        let out_messages = vec![
+            OutMessage { msg: msg.clone(), addresses: vec![1], options },
+            OutMessage { msg: msg.clone(), addresses: vec![2], options: state_options.clone() },
+            // Supersedes the previous message:
+            OutMessage { msg, addresses: vec![3], options: state_options },
+        ];
+        let fms = start_send_blocked(&mut core, fms, out_messages);
+        cancel_handle.cancel();
+
+        let flush_fms = fms.flush().map(|fms| fms.stats());
+        let (stats, sent_dgrams) = core.run(flush_fms.join(stream.collect())).unwrap();
+        let addresses = sent_dgrams.iter().map(|&(_, address)| address).collect::<Vec<u32>>();
+
+        let mut expected = vec![1];
+        expected.extend(vec![3; num_msg_dgrams]);
+        assert_eq!(addresses, expected);
+        assert_eq!(stats, SenderStats {
+            cancelled_messages: 2,
+            cancelled_dgrams: 2 * num_msg_dgrams - 1,
+            ..SenderStats::default()
+        });
+    }
+
+    #[test]
+    fn test_frag_msg_sender_supersede_keys() {
+        let mut core = Core::new().unwrap();
+        let (mut fms, stream) = new_channel_sender();
+        core.handle().spawn(stream.for_each(|_| Ok(())));
+
+        // Every message has its own key, and is sent before the next one:
+        let mut out_messages = (0 .. 200u64).map(|key| OutMessage {
+            msg: b"A short message".to_vec(),
+            addresses: vec![1],
+            options: SendOptions {
+                opt_supersede_key: Some(key),
+                ..SendOptions::default()
+            },
+        }).collect::<VecDeque<_>>();
+        core.run(future::poll_fn(|| {
+            while let Some(out_message) = out_messages.pop_front() {
+                if let AsyncSink::NotReady(out_message) = fms.start_send_msg(out_message)
+                    .map_err(|_| ())? {
+                    out_messages.push_front(out_message);
+                    return Ok(Async::NotReady);
+                }
+            }
+            fms.poll_complete().map_err(|_| ())
+        })).unwrap();
+
+        // Keys of messages that were already sent are forgotten:
+        let latest_by_key = &fms.supersede_keys.latest_by_key;
+        assert!(latest_by_key.len() <= 64);
+        assert!(latest_by_key.values().filter(|latest| latest.is_alive()).count() <= 1);
+    }
+}
@@ -0,0 +1,411 @@
+use std::io;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::{Future, Sink, Stream, Poll, StartSend};
+use futures::stream::SplitStream;
+use rand::StdRng;
+
+use tokio_core::net::{UdpSocket, UdpFramed};
+use tokio_core::reactor::{Handle, Interval};
+
+use ::frag_msg_receiver::{FragMsgReceiver, FragMsgReceiverError};
+use ::frag_msg_sender::{FragMsgSender, OutMessage, SenderStats, TaggedDgram, SendMsgError};
+use ::fragmenter::SenderParams;
+use ::messages::max_frag_message;
+use ::state_machine::DEFAULT_MAX_TOTAL_MESSAGE;
+use ::pmtu::{PathMtu, PmtuConfig};
+use ::feedback::DeliveryFeedback;
+use ::rate_limit::{rate_limit_channel_stats, Pacing, RateLimitStats};
+use ::utils::DgramCodec;
+
+// Multiplier for the calculation of the default rate limit queue length:
+const RATE_LIMIT_BUFF_MULT: usize = 16;
+
+type DgramSink = Box<dyn Sink<SinkItem=TaggedDgram<SocketAddr>, SinkError=()>>;
+type TickStream = Box<dyn Stream<Item=(), Error=()>>;
+
+fn send_msg_error<T>(e: SendMsgError<T>) -> io::Error {
+    match e {
+        SendMsgError::NoAddresses(_) => 
+            io::Error::new(io::ErrorKind::InvalidInput, "Message has no addresses"),
+        SendMsgError::InvalidParams(..) => 
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid sender parameters"),
+        SendMsgError::SinkError => io::Error::other("Failed to send datagrams"),
+    }
+}
+
+
+/// Rate limiting of outgoing datagrams. See rate_limit_channel().
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Maximum amount of datagrams waiting to be sent.
+    /// Once reached, new datagrams wait outside the queue, whatever their priority.
+    /// Priority only orders the datagrams already in the queue.
+    pub queue_len: usize,
+    /// Minimum amount of bytes sent every millisecond.
+    pub min_tokens_per_ms: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FragSocketConfig {
+    /// Maximum size of UDP datagram we are willing to send.
+    pub max_dgram_len: usize,
+    /// Maximum size of a message we are willing to receive.
+    pub max_total_message: usize,
+    /// Time between two ticks of the receiver.
+    /// Partially received messages are discarded after 30 ticks.
+    pub tick_duration: Duration,
+    /// Rate limiting of outgoing datagrams. None to send datagrams as fast as possible.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Spreading of the datagrams of every message over time.
+    /// None to send them back to back.
+    pub pacing: Option<Pacing>,
+    /// Discovery of the largest datagram length for every destination.
+    /// If set, max_dgram_len is replaced by the discovered lengths, starting from
+    /// min_dgram_len. None to always use max_dgram_len.
+    pub path_mtu: Option<PmtuConfig>,
+}
+
+impl Default for FragSocketConfig {
+    fn default() -> Self {
+        let max_dgram_len = 512;
+        let queue_len = (max_frag_message(max_dgram_len).unwrap() / max_dgram_len)
+            * RATE_LIMIT_BUFF_MULT;
+
+        FragSocketConfig {
+            max_dgram_len,
+            max_total_message: DEFAULT_MAX_TOTAL_MESSAGE,
+            tick_duration: Duration::new(1,0),
+            rate_limit: Some(RateLimitConfig {
+                queue_len,
+                min_tokens_per_ms: 16,
+            }),
+            pacing: None,
+            path_mtu: None,
+        }
+    }
+}
+
+/// A UDP socket sending and receiving Fragmentos messages.
+/// Owns the underlying socket, the rate limiter and the time ticks of the receiver.
+pub struct FragSocket {
+    local_addr: SocketAddr,
+    frag_sender: FragMsgSender<SocketAddr, StdRng, DgramSink, ()>,
+    frag_receiver: FragMsgReceiver<SocketAddr, SplitStream<UdpFramed<DgramCodec>>,
+                                   io::Error, TickStream>,
+    opt_path_mtu: Option<PathMtu<SocketAddr>>,
+    opt_feedback: Option<DeliveryFeedback<SocketAddr>>,
+    opt_rate_limit_stats: Option<RateLimitStats>,
+}
+
+impl FragSocket {
+    /// Bind a new socket to the given address.
+    pub fn bind(addr: &SocketAddr, config: FragSocketConfig, handle: &Handle)
+        -> io::Result<FragSocket> {
+
+        let socket = UdpSocket::bind(addr, handle)?;
+        let local_addr = socket.local_addr()?;
+        let (udp_sink, udp_stream) = socket.framed(DgramCodec).split();
+        let udp_sink = udp_sink.sink_map_err(|_| ());
+
+        // The rate limiter drops datagrams whose deadline has passed:
+        let mut opt_rate_limit_stats = None;
+        let dgram_sink: DgramSink = match config.rate_limit {
+            Some(ref rate_limit) => {
+                let (rl_sender, rl_receiver, rl_stats) = rate_limit_channel_stats(
+                    rate_limit.queue_len, rate_limit.min_tokens_per_ms, handle);
+                handle.spawn(
+                    udp_sink
+                        .send_all(rl_receiver.map(|tagged: TaggedDgram<SocketAddr>| 
+                                                  (tagged.dgram, tagged.address)))
+                        .then(|_| Ok(()))
+                );
+                opt_rate_limit_stats = Some(rl_stats);
+                Box::new(rl_sender.sink_map_err(|_| ()))
+            },
+            None => Box::new(udp_sink.with(|tagged: TaggedDgram<SocketAddr>| 
+                                           Ok((tagged.dgram, tagged.address)))),
+        };
+
+        let time_tick: TickStream = Box::new(
+            Interval::new(config.tick_duration, handle)?
+                .map_err(|_| ()));
+
+        let mut frag_sender = FragMsgSender::new(dgram_sink, config.max_dgram_len,
+                                                 StdRng::new()?);
+        if let Some(pacing) = config.pacing {
+            frag_sender.set_pacing(pacing, handle);
+        }
+        let mut frag_receiver = FragMsgReceiver::with_max_total_message(
+            udp_stream, time_tick, config.max_total_message);
+        let opt_path_mtu = match config.path_mtu {
+            Some(pmtu_config) => {
+                let path_mtu = PathMtu::new(pmtu_config, StdRng::new()?)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, 
+                                                "Invalid path MTU configuration"))?;
+                frag_sender.set_path_mtu(path_mtu.clone());
+                frag_receiver.set_path_mtu(path_mtu.clone());
+                Some(path_mtu)
+            },
+            None => None,
+        };
+
+        Ok(FragSocket {
+            local_addr,
+            frag_sender,
+            frag_receiver,
+            opt_path_mtu,
+            opt_feedback: None,
+            opt_rate_limit_stats,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Maximum length of datagrams sent to addr.
+    pub fn max_dgram_len_to(&self, addr: &SocketAddr) -> usize {
+        self.frag_sender.params_to(addr).max_dgram_len
+    }
+
+    /// Maximum length of a message sent to addr as a single block.
+    /// See FragMsgSender::max_message_to().
+    pub fn max_message_to(&self, addr: &SocketAddr) -> Result<usize, ()> {
+        self.frag_sender.max_message_to(addr)
+    }
+
+    /// Send a message with the given options, for example a deadline or a cancel handle.
+    /// See FragMsgSender::start_send_msg().
+    pub fn start_send_msg(&mut self, out_message: OutMessage<SocketAddr>)
+        -> StartSend<OutMessage<SocketAddr>, io::Error> {
+
+        self.frag_sender.start_send_msg(out_message)
+            .map_err(send_msg_error)
+    }
+
+    /// Messages and datagrams dropped so far.
+    /// expired_dgrams and cancelled_dgrams also count datagrams dropped by the rate limiter.
+    pub fn stats(&self) -> SenderStats {
+        let mut stats = self.frag_sender.stats();
+        if let Some(ref rate_limit_stats) = self.opt_rate_limit_stats {
+            stats.expired_dgrams += rate_limit_stats.expired_items();
+            stats.cancelled_dgrams += rate_limit_stats.cancelled_items();
+        }
+        stats
+    }
+
+    /// Use different datagram lengths and redundancy for some destinations.
+    /// See FragMsgSender::set_params_table().
+    pub fn set_params_table(&mut self, table: HashMap<SocketAddr, SenderParams>) 
+        -> io::Result<()> {
+
+        self.frag_sender.set_params_table(table)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid sender parameters"))
+    }
+
+    /// Report delivery outcomes of messages sent with SendOptions::opt_track_id, and send
+    /// delivery reports to the sources of received messages.
+    /// See FragMsgSender::set_feedback().
+    pub fn set_feedback(&mut self, feedback: DeliveryFeedback<SocketAddr>) {
+        self.frag_sender.set_feedback(feedback.clone());
+        self.frag_receiver.set_feedback(feedback.clone());
+        self.opt_feedback = Some(feedback);
+    }
+
+    /// Send a message to the given address.
+    /// Returns the socket once the message was handed to the underlying socket.
+    pub fn send_to(self, msg: Vec<u8>, addr: SocketAddr)
+        -> Box<dyn Future<Item=FragSocket, Error=io::Error>> {
+
+        Box::new(self.send((msg, addr)))
+    }
+
+    /// Send the same message to all the given addresses, encoding it only once.
+    /// See FragMsgSender::start_send_to_many().
+    pub fn start_send_to_many(&mut self, msg: Vec<u8>, addrs: Vec<SocketAddr>)
+        -> StartSend<(Vec<u8>, Vec<SocketAddr>), io::Error> {
+
+        self.frag_sender.start_send_to_many(msg, addrs)
+            .map_err(send_msg_error)
+    }
+
+    /// Receive the next message, together with the address it was sent from.
+    pub fn recv_from(self)
+        -> Box<dyn Future<Item=(FragSocket, Vec<u8>, SocketAddr), Error=io::Error>> {
+
+        Box::new(self.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|(opt_item, frag_socket)| match opt_item {
+                Some((msg, addr)) => Ok((frag_socket, msg, addr)),
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                           "Socket was closed")),
+            }))
+    }
+}
+
+impl Sink for FragSocket {
+    type SinkItem = (Vec<u8>, SocketAddr);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+
+        self.frag_sender.start_send(item)
+            .map_err(|()| io::Error::other("Failed to send datagrams"))
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.frag_sender.poll_complete()
+            .map_err(|()| io::Error::other("Failed to send datagrams"))
+    }
+}
+
+impl Stream for FragSocket {
+    type Item = (Vec<u8>, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let res = self.frag_receiver.poll().map_err(|e| match e {
+            FragMsgReceiverError::RecvStreamError(e) => e,
+            FragMsgReceiverError::RecvTimeTickError =>
+                io::Error::other("Time tick error"),
+        });
+        if self.opt_path_mtu.is_some() || self.opt_feedback.is_some() {
+            // Send echoes of received probes and delivery reports, even if nothing else is sent:
+            self.frag_sender.poll_complete()
+                .map_err(|()| io::Error::other("Failed to send datagrams"))?;
+        }
+        res
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use ::frag_msg_sender::SendOptions;
+    use ::feedback::{DeliveryOutcome, DeliveryStatus};
+
+    /// Send a message of 2000 bytes, returning the time it took to receive it.
+    fn send_recv(config: FragSocketConfig) -> Duration {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let client = FragSocket::bind(&local_addr, config.clone(), &handle).unwrap();
+        let server = FragSocket::bind(&local_addr, config, &handle).unwrap();
+        let server_addr = server.local_addr();
+        let client_addr = client.local_addr();
+
+        let orig_message = (0 .. 2000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let start = Instant::now();
+        handle.spawn(client.send_to(orig_message.clone(), server_addr)
+                     .map(|_client| ())
+                     .map_err(|_| ()));
+
+        let (_server, msg, addr) = core.run(server.recv_from()).unwrap();
+        assert_eq!(msg, orig_message);
+        assert_eq!(addr, client_addr);
+        start.elapsed()
+    }
+
+    #[test]
+    fn test_frag_socket_send_recv() {
+        send_recv(FragSocketConfig::default());
+    }
+
+    #[test]
+    fn test_frag_socket_pacing() {
+        // The message is sent as 9 datagrams of 512 bytes, 20ms apart. It is reconstructed
+        // once 5 of them arrive:
+        let elapsed = send_recv(FragSocketConfig {
+            pacing: Some(Pacing::Window(Duration::from_millis(180))),
+            ..FragSocketConfig::default()
+        });
+        // Timers may fire a little early or late, so only the range is checked:
+        assert!(elapsed >= Duration::from_millis(60));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_frag_socket_path_mtu() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let config = FragSocketConfig {
+            path_mtu: Some(PmtuConfig::default()),
+            ..FragSocketConfig::default()
+        };
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let mut client = FragSocket::bind(&local_addr, config.clone(), &handle).unwrap();
+        let server = FragSocket::bind(&local_addr, config, &handle).unwrap();
+        let server_addr = server.local_addr();
+
+        // The server sends back every message it receives:
+        let (server_sink, server_stream) = server.split();
+        handle.spawn(server_sink.send_all(server_stream).then(|_| Ok(())));
+
+        assert_eq!(client.max_dgram_len_to(&server_addr), 512);
+        let orig_message = (0 .. 5000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        for _ in 0 .. 2 {
+            client = core.run(client.send_to(orig_message.clone(), server_addr)).unwrap();
+            let (new_client, msg, addr) = core.run(client.recv_from()).unwrap();
+            client = new_client;
+            assert_eq!(msg, orig_message);
+            assert_eq!(addr, server_addr);
+        }
+        // Loopback carries larger datagrams. Probes may still be in flight, 
+        // so discovery might not have reached the largest probed length yet:
+        let max_dgram_len = client.max_dgram_len_to(&server_addr);
+        assert!(max_dgram_len > 512);
+        assert!(max_dgram_len <= 1472);
+    }
+
+    #[test]
+    fn test_frag_socket_feedback() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let mut client = FragSocket::bind(&local_addr, FragSocketConfig::default(), &handle)
+            .unwrap();
+        let mut server = FragSocket::bind(&local_addr, FragSocketConfig::default(), &handle)
+            .unwrap();
+        let server_addr = server.local_addr();
+
+        let (client_feedback, outcome_receiver) = DeliveryFeedback::new(40);
+        client.set_feedback(client_feedback);
+        let (server_feedback, _) = DeliveryFeedback::new(40);
+        server.set_feedback(server_feedback);
+        handle.spawn(server.for_each(|_| Ok(())).map_err(|_| ()));
+
+        let out_message = OutMessage {
+            msg: (0 .. 2000u32).map(|i| i as u8).collect::<Vec<u8>>(),
+            addresses: vec![server_addr],
+            options: SendOptions {
+                opt_track_id: Some(3),
+                ..SendOptions::default()
+            },
+        };
+        let client = core.run(future::lazy(move || {
+            assert!(client.start_send_msg(out_message).unwrap().is_ready());
+            client.flush()
+        })).unwrap();
+        // Keep receiving, to get the delivery reports:
+        handle.spawn(client.for_each(|_| Ok(())).map_err(|_| ()));
+
+        let outcomes = core.run(outcome_receiver.take(1).collect()).unwrap();
+        assert_eq!(outcomes, vec![DeliveryOutcome {
+            track_id: 3,
+            address: server_addr,
+            status: DeliveryStatus::Delivered,
+        }]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::{io, cmp};
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use rand::StdRng;
+
+use ::fragmenter::{Fragmenter, CompatRng};
+use ::reassembler::{Reassembler, ReassemblerEvent};
+
+// Maximum size of a UDP datagram:
+const MAX_UDP_DGRAM: usize = 1 << 16;
+// Time between two ticks of the Reassembler.
+// Partially received messages are discarded after 30 ticks.
+const TICK_DURATION: Duration = Duration::from_secs(1);
+
+
+/// A blocking UDP socket sending and receiving Fragmentos messages.
+/// Does not require any async runtime.
+pub struct FragUdpSocket {
+    socket: UdpSocket,
+    fragmenter: Fragmenter<CompatRng<StdRng>>,
+    reassembler: Reassembler<Instant>,
+    recv_buffer: Vec<u8>,
+    opt_read_timeout: Option<Duration>,
+}
+
+impl FragUdpSocket {
+    /// Bind a new socket to the given address.
+    /// Datagrams of at most max_dgram_len bytes are sent.
+    /// Incoming messages larger than max_total_message bytes are discarded.
+    pub fn bind<A: ToSocketAddrs>(addr: A, max_dgram_len: usize, max_total_message: usize)
+        -> io::Result<FragUdpSocket> {
+
+        Ok(FragUdpSocket {
+            socket: UdpSocket::bind(addr)?,
+            fragmenter: Fragmenter::new(max_dgram_len, CompatRng(StdRng::new()?)),
+            reassembler: Reassembler::new(max_total_message, TICK_DURATION),
+            recv_buffer: vec![0; MAX_UDP_DGRAM],
+            opt_read_timeout: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Send a message to the given address.
+    pub fn send_to<A: ToSocketAddrs>(&mut self, msg: &[u8], addr: A) -> io::Result<()> {
+        let addr = match addr.to_socket_addrs()?.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "No addresses to send message to")),
+        };
+
+        let dgrams = self.fragmenter.fragment(msg)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                        "max_dgram_len is too small"))?;
+        for dgram in dgrams {
+            self.socket.send_to(&dgram, addr)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next message, together with the address of the sender of its last datagram.
+    /// Blocks until a full message is received, or until the read timeout expires. In the
+    /// latter case, an error of kind WouldBlock is returned.
+    pub fn recv_from(&mut self) -> io::Result<(Vec<u8>, SocketAddr)> {
+        let opt_deadline = self.opt_read_timeout.map(|read_timeout| Instant::now() + read_timeout);
+
+        loop {
+            // Discard old partially received messages:
+            let now = Instant::now();
+            self.reassembler.handle_timeout(now);
+
+            // Wake up for the next tick of the reassembler, or when the read timeout expires:
+            let mut wake_up = self.reassembler.next_timeout().unwrap_or(now + TICK_DURATION);
+            if let Some(deadline) = opt_deadline {
+                if now >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                              "Read timeout expired"));
+                }
+                wake_up = cmp::min(wake_up, deadline);
+            }
+            self.socket.set_read_timeout(Some(wake_up - now))?;
+
+            match self.socket.recv_from(&mut self.recv_buffer) {
+                Ok((len, addr)) => {
+                    let events = self.reassembler.received(&self.recv_buffer[.. len],
+                                                           Instant::now());
+                    for event in events {
+                        if let ReassemblerEvent::Message(msg) = event {
+                            return Ok((msg, addr));
+                        }
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                    e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    /// Set the read timeout of recv_from(). None means recv_from() blocks indefinitely.
+    /// A zero duration is rejected, like in std::net::UdpSocket.
+    pub fn set_read_timeout(&mut self, opt_read_timeout: Option<Duration>) -> io::Result<()> {
+        if opt_read_timeout == Some(Duration::new(0,0)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "Cannot set a zero duration timeout"));
+        }
+        self.opt_read_timeout = opt_read_timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.opt_read_timeout
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frag_udp_socket_send_recv() {
+        let mut client = FragUdpSocket::bind("127.0.0.1:0", 512, 1 << 16).unwrap();
+        let mut server = FragUdpSocket::bind("127.0.0.1:0", 512, 1 << 16).unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let orig_message = (0 .. 2000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        client.send_to(&orig_message, server.local_addr().unwrap()).unwrap();
+
+        let (msg, addr) = server.recv_from().unwrap();
+        assert_eq!(msg, orig_message);
+        assert_eq!(addr, client.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_frag_udp_socket_read_timeout() {
+        let mut socket = FragUdpSocket::bind("127.0.0.1:0", 512, 1 << 16).unwrap();
+        assert!(socket.set_read_timeout(Some(Duration::new(0,0))).is_err());
+
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert_eq!(socket.read_timeout(), Some(Duration::from_millis(50)));
+        let err = socket.recv_from().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,319 @@
+use core::cmp;
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use rand_core::RngCore;
+#[cfg(feature = "std")]
+use rand::Rng;
+
+use ::messages::{split_message_parts, num_frag_messages, NONCE_LEN};
+use ::buffer_pool::BufferPool;
+use ::blocks::{block_layout, max_block_data, BlockRange, PARENT_ID_LEN};
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FragmentError {
+    /// max_dgram_len is too small to carry any message data.
+    DgramTooSmall,
+    /// Reed-Solomon encoding of a block failed.
+    EncodeFailed,
+}
+
+/// Which shares of every block are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redundancy {
+    /// All the 2b-1 shares.
+    Full,
+    /// Only b + extra shares. Extra(0) is plain fragmentation, where every datagram has to
+    /// arrive.
+    Extra(usize),
+}
+
+impl Redundancy {
+    /// Amount of shares sent out of the 2b-1 shares of a block.
+    pub(crate) fn num_sent(&self, b: usize) -> usize {
+        match *self {
+            Redundancy::Full => 2*b - 1,
+            Redundancy::Extra(extra) => cmp::min(b + extra, 2*b - 1),
+        }
+    }
+}
+
+/// How messages are split into datagrams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderParams {
+    /// Maximum length of a datagram.
+    pub max_dgram_len: usize,
+    /// Which shares of every block are sent.
+    pub redundancy: Redundancy,
+}
+
+impl SenderParams {
+    /// Check that messages could be split according to these parameters.
+    pub fn check(&self) -> Result<(), FragmentError> {
+        max_block_data(self.max_dgram_len)
+            .map(|_| ())
+            .map_err(|_| FragmentError::DgramTooSmall)
+    }
+}
+
+// A block of the message, and its nonce:
+type PlannedBlock = (BlockRange, [u8; NONCE_LEN]);
+
+/// Everything needed to encode a message into datagrams.
+/// Random values are generated in advance, so that encoding could be done on another thread.
+#[cfg(feature = "std")]
+pub struct EncodeJob {
+    msg: Vec<u8>,
+    params: SenderParams,
+    blocks: Vec<PlannedBlock>,
+    opt_buffer_pool: Option<BufferPool>,
+}
+
+#[cfg(feature = "std")]
+impl EncodeJob {
+    /// Returns the message back if it can not be split according to params.
+    pub fn new<R: RngCore>(msg: Vec<u8>, params: SenderParams, rng: &mut R,
+                       opt_buffer_pool: Option<BufferPool>) 
+        -> Result<Self, (Vec<u8>, FragmentError)> {
+
+        let blocks = match plan_blocks(msg.len(), params.max_dgram_len, rng) {
+            Ok(blocks) => blocks,
+            Err(e) => return Err((msg, e)),
+        };
+        Ok(EncodeJob {
+            msg,
+            params,
+            blocks,
+            opt_buffer_pool,
+        })
+    }
+
+    pub fn encode(self) -> Result<VecDeque<Vec<u8>>, FragmentError> {
+        let mut dgrams = VecDeque::new();
+        encode_blocks(&self.msg, &self.blocks, self.params,
+                      &self.opt_buffer_pool, &mut dgrams)?;
+        Ok(dgrams)
+    }
+}
+
+/// Generate the random parentId and nonces needed to send a message of length m_len.
+fn plan_blocks<R: RngCore>(m_len: usize, max_dgram_len: usize, rng: &mut R)
+    -> Result<Vec<PlannedBlock>, FragmentError> {
+
+    // Large messages are split into a few blocks,
+    // each sent as a separate Fragmentos message.
+    let parent_id: &mut [u8; PARENT_ID_LEN] = &mut [0; PARENT_ID_LEN];
+    rng.fill_bytes(parent_id);
+
+    let blocks = block_layout(m_len, parent_id, max_dgram_len)
+        .map_err(|_| FragmentError::DgramTooSmall)?;
+
+    Ok(blocks.into_iter().map(|block_range| {
+        // Generate a random nonce:
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        (block_range, nonce)
+    }).collect::<Vec<_>>())
+}
+
+/// Encode all the blocks of msg, appending the datagrams to be sent to dgrams.
+fn encode_blocks<E>(msg: &[u8], blocks: &[PlannedBlock], params: SenderParams,
+                    opt_buffer_pool: &Option<BufferPool>, dgrams: &mut E) 
+    -> Result<(), FragmentError>
+where
+    E: Extend<Vec<u8>>,
+{
+    let max_dgram_len = params.max_dgram_len;
+    let mut block_dgrams = Vec::new();
+    for (block_range, nonce) in blocks {
+        let block_len = block_range.len();
+        let num_dgrams = num_frag_messages(block_len, max_dgram_len)
+            .map_err(|_| FragmentError::DgramTooSmall)?;
+        // The first b shares carry the block data. Only the parity shares that are sent
+        // are computed:
+        let b = num_dgrams.div_ceil(2);
+        let num_sent = params.redundancy.num_sent(b);
+        if let Some(ref buffer_pool) = *opt_buffer_pool {
+            while block_dgrams.len() < num_sent {
+                block_dgrams.push(buffer_pool.take());
+            }
+        }
+
+        // Every datagram is written directly into its own buffer,
+        // which is later handed as is to the underlying sink.
+        let data = &msg[block_range.data_range.clone()];
+        let res = match block_range.opt_header {
+            Some(ref header) => split_message_parts(&[header, data], true, nonce, 
+                                                    max_dgram_len, num_sent, &mut block_dgrams),
+            None => split_message_parts(&[data], false, nonce, 
+                                        max_dgram_len, num_sent, &mut block_dgrams),
+        };
+        match res {
+            Ok(()) => {
+                // All the buffers are handed over to the caller:
+                dgrams.extend(block_dgrams.drain(..));
+            },
+            Err(_) => return Err(FragmentError::EncodeFailed),
+        };
+    }
+    Ok(())
+}
+
+
+/// Lets a rand::Rng, as taken by the async layer, be used where a RngCore is required.
+#[cfg(feature = "std")]
+pub struct CompatRng<R>(pub R);
+
+#[cfg(feature = "std")]
+impl<R: Rng> RngCore for CompatRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), ::rand_core::Error> {
+        self.0.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+
+/// Splits messages into datagrams, without doing any IO.
+/// Every returned datagram should be sent to the remote side, in any order.
+///
+/// Returned datagrams are owned by the caller. Their buffers are reused only if a BufferPool
+/// is set, and the caller gives the buffers back into it after sending.
+pub struct Fragmenter<R> {
+    max_dgram_len: usize,
+    rng: R,
+    opt_buffer_pool: Option<BufferPool>,
+}
+
+impl<R: RngCore> Fragmenter<R> {
+    /// Create a new Fragmenter, producing datagrams of at most max_dgram_len bytes.
+    pub fn new(max_dgram_len: usize, rng: R) -> Self {
+        Fragmenter {
+            max_dgram_len,
+            rng,
+            opt_buffer_pool: None,
+        }
+    }
+
+    /// Take buffers for datagrams from the given pool, instead of allocating them.
+    /// Sent datagrams should be given back into the same pool.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+
+    pub fn max_dgram_len(&self) -> usize {
+        self.max_dgram_len
+    }
+
+    /// Split a message into datagrams. All the shares of every block are returned.
+    pub fn fragment(&mut self, msg: &[u8]) -> Result<Vec<Vec<u8>>, FragmentError> {
+        let params = SenderParams {
+            max_dgram_len: self.max_dgram_len,
+            redundancy: Redundancy::Full,
+        };
+        self.fragment_with(msg, params)
+    }
+
+    /// Split a message into datagrams, according to the given params instead of the
+    /// max_dgram_len of the Fragmenter.
+    pub fn fragment_with(&mut self, msg: &[u8], params: SenderParams)
+        -> Result<Vec<Vec<u8>>, FragmentError> {
+
+        let blocks = plan_blocks(msg.len(), params.max_dgram_len, &mut self.rng)?;
+        let mut dgrams = Vec::new();
+        encode_blocks(msg, &blocks, params, &self.opt_buffer_pool, &mut dgrams)?;
+        Ok(dgrams)
+    }
+
+    /// Prepare the encoding of a message according to params,
+    /// so that it could be done later, possibly on another thread.
+    #[cfg(feature = "std")]
+    pub(crate) fn encode_job(&mut self, msg: Vec<u8>, params: SenderParams)
+        -> Result<EncodeJob, (Vec<u8>, FragmentError)> {
+
+        EncodeJob::new(msg, params, &mut self.rng, self.opt_buffer_pool.clone())
+    }
+}
+
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use rand;
+    use rand::StdRng;
+
+    use ::state_machine::FragStateMachine;
+
+    fn new_fragmenter(max_dgram_len: usize) -> Fragmenter<CompatRng<StdRng>> {
+        let seed: &[_] = &[1,2,3,4,5];
+        let rng: StdRng = rand::SeedableRng::from_seed(seed);
+        Fragmenter::new(max_dgram_len, CompatRng(rng))
+    }
+
+    #[test]
+    fn test_fragmenter_basic() {
+        let mut fragmenter = new_fragmenter(22);
+        let orig_message = b"This is some message to be split";
+        let dgrams = fragmenter.fragment(orig_message).unwrap();
+        assert!(dgrams.len() > 1);
+        assert!(dgrams.iter().all(|dgram| dgram.len() <= 22));
+
+        // Any b datagrams are enough:
+        let b = dgrams.len().div_ceil(2);
+        let mut fsm = FragStateMachine::new();
+        let mut opt_united = None;
+        for dgram in &dgrams[dgrams.len() - b ..] {
+            assert_eq!(opt_united, None);
+            opt_united = fsm.received_frag_message(dgram);
+        }
+        assert_eq!(opt_united.unwrap(), orig_message);
+    }
+
+    #[test]
+    fn test_fragmenter_dgram_too_small() {
+        let mut fragmenter = new_fragmenter(0);
+        assert_eq!(fragmenter.fragment(b"Some message"),
+                   Err(FragmentError::DgramTooSmall));
+    }
+
+    #[test]
+    fn test_fragmenter_redundancy() {
+        let mut fragmenter = new_fragmenter(512);
+        let orig_message = (0 .. 5000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let full = fragmenter.fragment_with(&orig_message, SenderParams {
+            max_dgram_len: 40,
+            redundancy: Redundancy::Full,
+        }).unwrap();
+        let plain = fragmenter.fragment_with(&orig_message, SenderParams {
+            max_dgram_len: 40,
+            redundancy: Redundancy::Extra(0),
+        }).unwrap();
+        assert!(full.iter().chain(plain.iter()).all(|dgram| dgram.len() <= 40));
+        // The message is split into two blocks, of 2b-1 and b shares:
+        assert_eq!(full.len(), 2 * plain.len() - 2);
+
+        // Without redundancy, all the datagrams are needed:
+        let mut fsm = FragStateMachine::new();
+        let mut opt_united = None;
+        for dgram in &plain {
+            assert_eq!(opt_united, None);
+            opt_united = fsm.received_frag_message(dgram);
+        }
+        assert_eq!(opt_united.unwrap(), orig_message);
+    }
+}
@@ -1,31 +1,103 @@
-#![feature(test)]
+#![cfg_attr(not(feature = "std"), no_std)]
+// Errors without details are reported as Result<_,()> throughout the crate:
+#![allow(clippy::result_unit_err)]
 #[macro_use]
 extern crate arrayref;
 
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+// With no_std, core is already linked:
+#[cfg(feature = "std")]
+extern crate core;
+
 // #[macro_use]
 // extern crate tokio_core;
 
 extern crate reed_solomon_erasure;
+#[cfg(feature = "std")]
 extern crate futures;
+#[cfg(feature = "std")]
+extern crate futures_cpupool;
+#[cfg(feature = "std")]
 extern crate tokio_core;
+#[cfg(feature = "std")]
 extern crate rand;
-extern crate ring;
+extern crate rand_core;
+extern crate sha2;
 
-// TODO: How to make sure this only compiles in dev?
-extern crate test;
+#[cfg(all(feature = "std", unix))]
+extern crate tokio_uds;
+#[cfg(all(feature = "std", unix))]
+extern crate libc;
+#[cfg(feature = "tokio1")]
+extern crate futures_03;
+#[cfg(feature = "tokio1")]
+extern crate tokio_1;
 
 mod shares;
 mod messages;
+mod blocks;
+mod buffer_pool;
 mod state_machine;
+mod fragmenter;
+mod reassembler;
+#[cfg(feature = "std")]
+mod rate_limit_queue;
+#[cfg(feature = "std")]
 pub mod rate_limit;
+#[cfg(feature = "std")]
 pub mod utils;
+#[cfg(feature = "std")]
 mod frag_msg_receiver;
+#[cfg(feature = "std")]
+mod cancel;
+#[cfg(feature = "std")]
+mod send_queue;
+#[cfg(feature = "std")]
 mod frag_msg_sender;
+#[cfg(feature = "std")]
+mod frag_socket;
+#[cfg(feature = "std")]
+mod frag_udp_socket;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod delivery;
+#[cfg(feature = "std")]
+pub mod multipath;
+#[cfg(feature = "std")]
+pub mod pmtu;
+#[cfg(feature = "std")]
+pub mod feedback;
+#[cfg(feature = "tokio1")]
+pub mod tokio1;
+#[cfg(all(test, feature = "std"))]
+mod test_support;
 
 
+#[cfg(feature = "std")]
 pub use ::frag_msg_receiver::FragMsgReceiver;
-pub use ::frag_msg_sender::FragMsgSender;
-pub use ::messages::{max_message};
+#[cfg(feature = "std")]
+pub use ::frag_msg_sender::{FragMsgSender, SendToMany, SendOptions, OutMessage, SenderStats,
+                             DgramItem, TaggedDgram, SendMsgError};
+#[cfg(feature = "std")]
+pub use ::cancel::CancelHandle;
+#[cfg(feature = "std")]
+pub use ::frag_socket::{FragSocket, FragSocketConfig, RateLimitConfig};
+#[cfg(feature = "std")]
+pub use ::rate_limit::Pacing;
+#[cfg(feature = "std")]
+pub use ::frag_udp_socket::FragUdpSocket;
+pub use ::blocks::max_message;
+pub use ::buffer_pool::{BufferPool, BufferPoolStats, DEFAULT_BYTES_PER_BUFFER};
+pub use ::fragmenter::{Fragmenter, FragmentError, Redundancy, SenderParams};
+#[cfg(feature = "std")]
+pub use ::fragmenter::CompatRng;
+pub use ::reassembler::{Reassembler, ReassemblerEvent};
+pub use ::state_machine::{ExpiredMessage, DEFAULT_MAX_TOTAL_MESSAGE};
 
 // For profiling:
 pub use ::shares::{split_data, unite_data};
@@ -1,6 +1,12 @@
-use ring::digest::{digest, SHA512_256};
+use core::cmp;
+use core::ops::Range;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use sha2::{Digest, Sha512Trunc256};
 
-use shares::{split_data, unite_data, DataShare};
+use shares::{encode_in_place, reconstruct_in_place};
+#[cfg(test)]
+use shares::{unite_data, DataShare};
 
 /*
 Fragmentos message fragment:
@@ -12,10 +18,13 @@ Fragmentos message fragment:
 - shortHash         [8 bytes]   (First 8 bytes of Sha512/256)
 
 `T := nonce8 || paddingCount || M || padding`
+
+paddingCount is always smaller than b <= 128. Its highest bit, BLOCK_FLAG, is set if M is a 
+block of a larger message (see blocks.rs).
 */
 
 // Length of the short_hash function output.
-const SHORT_HASH_LEN: usize = 8;
+pub const SHORT_HASH_LEN: usize = 8;
 // Length of messageId (First 8 bytes of sha256 of the underlying T data):
 pub const MESSAGE_ID_LEN: usize = SHORT_HASH_LEN;
 // Length in bytes of the Reed Solomon error correcting code:
@@ -24,22 +33,26 @@ pub const ECC_LEN: usize = 8;
 pub const NONCE_LEN: usize = 8;
 // Length of all message fields, excluding shareData:
 const FIELDS_LEN: usize = MESSAGE_ID_LEN + 1 + 1 + ECC_LEN;
+// Maximum legal value of b:
+pub const MAX_B: usize = 128;
+// Set in paddingCount if M is a block of a larger message:
+pub const BLOCK_FLAG: u8 = 0x80;
 
 
-/// Calculate max possible message for Fragmentos, given the maximum datagram allowed on the
-/// underlying protocol.
-pub fn max_message(max_dgram_len: usize) -> Result<usize,()> {
+/// Calculate max possible M of a single Fragmentos message, given the maximum datagram allowed
+/// on the underlying protocol. Larger messages are split into blocks, see blocks::block_layout().
+pub fn max_frag_message(max_dgram_len: usize) -> Result<usize,()> {
     if max_dgram_len <= FIELDS_LEN {
         return Err(());
     }
 
-    Ok((128 * (max_dgram_len - FIELDS_LEN)) - (NONCE_LEN + 1))
+    Ok((MAX_B * (max_dgram_len - FIELDS_LEN)) - (NONCE_LEN + 1))
 }
 
-fn short_hash(input_data: &[u8]) -> [u8; SHORT_HASH_LEN] {
+pub fn short_hash(input_data: &[u8]) -> [u8; SHORT_HASH_LEN] {
     let mut hash_output = [0x0; SHORT_HASH_LEN];
-    let digest_res = digest(&SHA512_256, input_data);
-    hash_output.copy_from_slice(&digest_res.as_ref()[0 .. MESSAGE_ID_LEN]);
+    let digest_res = Sha512Trunc256::digest(input_data);
+    hash_output.copy_from_slice(&digest_res[0 .. MESSAGE_ID_LEN]);
     hash_output
 }
 
@@ -51,57 +64,146 @@ pub fn calc_message_id(t: &[u8]) -> [u8; MESSAGE_ID_LEN] {
 }
 */
 
+/// Calculate the amount of shares required to reconstruct a message of length m_len.
+fn calc_b(m_len: usize, max_dgram_len: usize) -> Result<usize,()> {
+    if m_len > max_frag_message(max_dgram_len)? {
+        return Err(());
+    }
+
+    let len_without_padding = NONCE_LEN + 1 + m_len;
+    let space_in_msg = max_dgram_len - FIELDS_LEN;
+    Ok(len_without_padding.div_ceil(space_in_msg))
+}
+
+/// Calculate the amount of Fragmentos messages a message of length m_len is split into.
+pub fn num_frag_messages(m_len: usize, max_dgram_len: usize) -> Result<usize,()> {
+    Ok(2 * calc_b(m_len, max_dgram_len)? - 1)
+}
+
+/// Calculate the length of every Fragmentos message a message of length m_len is split into.
+#[cfg(feature = "std")]
+pub fn frag_message_len(m_len: usize, max_dgram_len: usize) -> Result<usize,()> {
+    let b = calc_b(m_len, max_dgram_len)?;
+    let len_without_padding = NONCE_LEN + 1 + m_len;
+    Ok(FIELDS_LEN + len_without_padding.div_ceil(b))
+}
+
 /// Split a message m into a few Fragmentos messages, to be sent to the destination.
 /// Could fail if message is too large.
 /// Returns a list of Fragmentos messages (As vectors) to be sent to the remote side.
+#[cfg(test)]
 pub fn split_message(m: &[u8], nonce: &[u8; NONCE_LEN], max_dgram_len: usize) 
         -> Result<Vec<Vec<u8>>,()> {
 
-    if m.len() > max_message(max_dgram_len)? {
-        return Err(());
-    }
+    let mut fmessages = Vec::new();
+    split_message_parts(&[m], false, nonce, max_dgram_len, usize::MAX, &mut fmessages)?;
+    Ok(fmessages)
+}
 
-    let len_without_padding = MESSAGE_ID_LEN + 1 + m.len();
-    let space_in_msg = max_dgram_len - (MESSAGE_ID_LEN + 1 + 1 + ECC_LEN);
-    let b = (len_without_padding + space_in_msg - 1) / space_in_msg;
+/// Split a message into a few Fragmentos messages, writing every Fragmentos message directly
+/// into its own buffer inside fmessages. The message m is the concatenation of m_parts.
+/// is_block marks m as a block of a larger message.
+///
+/// Buffers that already exist inside fmessages are reused, to avoid allocations. 
+/// fmessages is resized to contain exactly the resulting Fragmentos messages.
+/// Every byte of m is copied exactly once, and parity shares are computed in place.
+///
+/// Only the first max_fmessages Fragmentos messages are produced, but never less than the b 
+/// messages that carry the data. Parity shares that are not produced are not computed.
+pub fn split_message_parts(m_parts: &[&[u8]], is_block: bool, nonce: &[u8; NONCE_LEN], 
+                           max_dgram_len: usize, max_fmessages: usize, 
+                           fmessages: &mut Vec<Vec<u8>>) 
+    -> Result<(),()> {
+
+    let m_len = m_parts.iter().map(|part| part.len()).sum::<usize>();
+    let b = calc_b(m_len, max_dgram_len)?;
+    let len_without_padding = NONCE_LEN + 1 + m_len;
 
     let padding_count = (b - (len_without_padding % b)) % b;
+    let share_length = (len_without_padding + padding_count) / b;
 
-    // Construct T:
-    let mut t = Vec::new();
+    // T := nonce8 || paddingCount || M || padding
+    // We never construct T in memory. Instead, we go over its parts:
+    let padding_count_arr = match is_block {
+        true => [padding_count as u8 | BLOCK_FLAG],
+        false => [padding_count as u8],
+    };
+    let t_head: [&[u8]; 2] = [nonce, &padding_count_arr];
+
+    // Calculate messageId over T:
+    let message_id = {
+        let zeroes = [0u8; 256];
+        let mut hasher = Sha512Trunc256::new();
+        for part in t_head.iter().chain(m_parts.iter()) {
+            hasher.update(part);
+        }
+        hasher.update(&zeroes[.. padding_count]);
+        let mut message_id = [0u8; MESSAGE_ID_LEN];
+        message_id.copy_from_slice(&hasher.finalize()[0 .. MESSAGE_ID_LEN]);
+        message_id
+    };
 
-    t.extend_from_slice(nonce);     // nonce8
-    t.push(padding_count as u8);    // paddingCount
-    t.extend_from_slice(m);         // M
-    // Push zeroes for padding:
-    for _ in 0 .. padding_count {
-        t.push(0);
+    // Prepare a zeroed buffer for every Fragmentos message:
+    let fmessage_len = FIELDS_LEN + share_length;
+    let num_fmessages = cmp::max(b, cmp::min(max_fmessages, 2*b - 1));
+    fmessages.truncate(num_fmessages);
+    while fmessages.len() < num_fmessages {
+        fmessages.push(Vec::new());
+    }
+    for fmessage in fmessages.iter_mut() {
+        fmessage.clear();
+        fmessage.resize(fmessage_len, 0);
     }
 
-    let message_id = short_hash(&t);
-    let data_shares = match split_data(&t, b as u8) {
-        Ok(data_shares) => data_shares,
-        // TODO: Fix error handling here:
-        Err(_) => return Err(()),
-    };
+    // Copy T into the shareData of the first b Fragmentos messages. 
+    // Padding is already zeroed.
+    let share_start = MESSAGE_ID_LEN + 1 + 1;
+    let mut share_index = 0;
+    let mut share_pos = 0;
+    for part in t_head.iter().chain(m_parts.iter()) {
+        let mut part: &[u8] = part;
+        while !part.is_empty() {
+            let amount = cmp::min(share_length - share_pos, part.len());
+            let dest_start = share_start + share_pos;
+            fmessages[share_index][dest_start .. dest_start + amount]
+                .copy_from_slice(&part[.. amount]);
+            part = &part[amount ..];
+            share_pos += amount;
+            if share_pos == share_length {
+                share_index += 1;
+                share_pos = 0;
+            }
+        }
+    }
+
+    // Calculate the parity shares in place:
+    {
+        let mut shards = fmessages
+            .iter_mut()
+            .map(|fmessage| &mut fmessage[share_start .. share_start + share_length])
+            .collect::<Vec<&mut [u8]>>();
+
+        match encode_in_place(&mut shards, b as u8) {
+            Ok(()) => {},
+            // TODO: Fix error handling here:
+            Err(_) => return Err(()),
+        };
+    }
 
-    Ok(data_shares
-        .into_iter()
-        .enumerate()
-        .map(|(i, data_share)| {
-            let mut fmessage = Vec::new();
-            fmessage.extend_from_slice(&message_id);
-            fmessage.push(b as u8);
-            fmessage.push(i as u8);
-            fmessage.extend_from_slice(&data_share.data);
-            let frag_hash = short_hash(&fmessage);
-            fmessage.extend_from_slice(&frag_hash);
-            fmessage
-        }).collect::<Vec<Vec<u8>>>()
-    )
+    // Fill in the rest of the fields:
+    for (i, fmessage) in fmessages.iter_mut().enumerate() {
+        fmessage[0 .. MESSAGE_ID_LEN].copy_from_slice(&message_id);
+        fmessage[MESSAGE_ID_LEN] = b as u8;
+        fmessage[MESSAGE_ID_LEN + 1] = i as u8;
+        let frag_hash = short_hash(&fmessage[.. fmessage_len - SHORT_HASH_LEN]);
+        fmessage[fmessage_len - SHORT_HASH_LEN ..].copy_from_slice(&frag_hash);
+    }
+
+    Ok(())
 }
 
 /// Reconstruct a message given a list of data shares.
+#[cfg(test)]
 pub fn unite_message(message_id: &[u8; MESSAGE_ID_LEN], data_shares: &[DataShare]) 
         -> Result<Vec<u8>,()> {
 
@@ -116,17 +218,57 @@ pub fn unite_message(message_id: &[u8; MESSAGE_ID_LEN], data_shares: &[DataShare
         Err(_) => return Err(()),
     };
 
+    let (m_range, _is_block) = extract_message(message_id, &t)?;
+    Ok(t[m_range].to_vec())
+}
+
+/// Reconstruct a message in place.
+/// buffer contains consecutive slots of share_length bytes, one for every possible shareIndex.
+/// present[i] is true if the i-th slot contains a received share. 
+/// On success, returns the range of the message M inside buffer, and whether M is a block of a
+/// larger message.
+pub fn unite_message_in_place(message_id: &[u8; MESSAGE_ID_LEN], b: u8, share_length: usize,
+                              buffer: &mut [u8], present: &[bool]) 
+    -> Result<(Range<usize>, bool),()> {
+
+    if b == 0 || share_length == 0 || buffer.len() != share_length * present.len() {
+        return Err(());
+    }
+
+    {
+        let mut shards = buffer.chunks_mut(share_length).collect::<Vec<&mut [u8]>>();
+        match reconstruct_in_place(&mut shards, present, b) {
+            Ok(()) => {},
+            // TODO: Fix error handling here:
+            Err(_) => return Err(()),
+        };
+    }
+
+    extract_message(message_id, &buffer[.. b as usize * share_length])
+}
+
+/// Verify a reconstructed T against the given message_id.
+/// Returns the range of the message M inside T, and whether M is a block of a larger message.
+fn extract_message(message_id: &[u8; MESSAGE_ID_LEN], t: &[u8]) 
+    -> Result<(Range<usize>, bool),()> {
     // Make sure that the provided message_id matches the calculated message_id:
-    let c_message_id = short_hash(&t);
+    let c_message_id = short_hash(t);
     if message_id != &c_message_id[..] {
         return Err(());
     }
 
+    if t.len() < NONCE_LEN + 1 {
+        return Err(());
+    }
+
     // let nonce = &t[0..NONCE_LEN];
-    let padding_count = t[NONCE_LEN] as usize;
-    let m = &t[NONCE_LEN + 1 .. t.len() - padding_count];
+    let is_block = t[NONCE_LEN] & BLOCK_FLAG != 0;
+    let padding_count = (t[NONCE_LEN] & !BLOCK_FLAG) as usize;
+    if NONCE_LEN + 1 + padding_count > t.len() {
+        return Err(());
+    }
 
-    Ok(m.to_vec())
+    Ok((NONCE_LEN + 1 .. t.len() - padding_count, is_block))
 }
 
 /// Read a fragmentos message and possibly correct it using the given error correction code.
@@ -138,7 +280,7 @@ pub fn verify_frag_message(frag_message: &[u8]) -> bool {
     } else {
         let hashed_content = &frag_message[ .. frag_message.len() - SHORT_HASH_LEN];
         let hash_output = short_hash(hashed_content);
-        &hash_output == &frag_message[frag_message.len() - SHORT_HASH_LEN .. ]
+        hash_output == frag_message[frag_message.len() - SHORT_HASH_LEN .. ]
     }
 }
 
@@ -146,15 +288,22 @@ pub fn verify_frag_message(frag_message: &[u8]) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand;
-    use rand::{StdRng, Rng};
-    use test::Bencher;
 
     #[test]
-    fn test_max_message() {
-        assert!(max_message(0).is_err());
-        assert!(max_message(MESSAGE_ID_LEN + ECC_LEN + 1).is_err());
-        assert!(max_message(512).unwrap() > 512);
+    fn test_max_frag_message() {
+        assert!(max_frag_message(0).is_err());
+        assert!(max_frag_message(MESSAGE_ID_LEN + ECC_LEN + 1).is_err());
+        assert!(max_frag_message(512).unwrap() > 512);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_frag_message_len() {
+        for m_len in &[0, 1, 13, 100, 1000] {
+            let frags = split_message(&vec![0x55; *m_len], b"nonce123", 50).unwrap();
+            assert_eq!(frags.len(), num_frag_messages(*m_len, 50).unwrap());
+            assert!(frags.iter().all(|frag| frag.len() == frag_message_len(*m_len, 50).unwrap()));
+        }
     }
 
     #[test]
@@ -163,6 +312,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_split_unite_message() {
         let orig_message = b"This is some message to be split";
         let frags = split_message(orig_message, 
@@ -187,6 +337,96 @@ mod tests {
         assert_eq!(orig_message, &new_message[..]);
     }
 
+    #[test]
+    fn test_split_message_parts() {
+        let orig_message = b"This is some message to be split";
+        let frags = split_message(orig_message, 
+                                  b"nonce123", 22).unwrap();
+
+        // Splitting the message in parts gives exactly the same Fragmentos messages:
+        let mut parts_frags = Vec::new();
+        split_message_parts(&[&orig_message[.. 10], &[], &orig_message[10 ..]], false,
+                            b"nonce123", 22, usize::MAX, &mut parts_frags).unwrap();
+        assert_eq!(frags, parts_frags);
+
+        // Reusing the buffers of a larger previous message:
+        let mut reused_frags = vec![vec![0xaa; 100]; 100];
+        split_message_parts(&[orig_message], false,
+                            b"nonce123", 22, usize::MAX, &mut reused_frags).unwrap();
+        assert_eq!(frags, reused_frags);
+
+        // Producing only some of the parity shares gives a prefix of the Fragmentos messages:
+        let b = frags.len() / 2 + 1;
+        for max_fmessages in 0 .. frags.len() {
+            let mut partial_frags = Vec::new();
+            split_message_parts(&[orig_message], false,
+                                b"nonce123", 22, max_fmessages, &mut partial_frags).unwrap();
+            assert_eq!(&frags[.. cmp::max(b, max_fmessages)], &partial_frags[..]);
+        }
+    }
+
+    #[test]
+    fn test_split_block_flag() {
+        let orig_message = b"This is some message to be split";
+        let mut frags = Vec::new();
+        split_message_parts(&[orig_message], true, b"nonce123", 22, usize::MAX, 
+                            &mut frags).unwrap();
+        let message_id = array_ref![&frags[0],0,MESSAGE_ID_LEN];
+        let b = frags[0][MESSAGE_ID_LEN];
+        let share_length = frags[0].len() - FIELDS_LEN;
+
+        let mut buffer = vec![0u8; frags.len() * share_length];
+        for (i, frag) in frags.iter().enumerate() {
+            buffer[i * share_length .. (i + 1) * share_length]
+                .copy_from_slice(&frag[MESSAGE_ID_LEN + 1 + 1 .. frag.len() - ECC_LEN]);
+        }
+        let present = vec![true; frags.len()];
+        let (m_range, is_block) = unite_message_in_place(message_id, b, share_length, 
+                                                         &mut buffer, &present).unwrap();
+        assert_eq!(orig_message, &buffer[m_range]);
+        assert!(is_block);
+    }
+
+    #[test]
+    fn test_split_message_too_large() {
+        let orig_message = vec![0; max_frag_message(22).unwrap() + 1];
+        assert!(split_message(&orig_message, b"nonce123", 22).is_err());
+
+        let orig_message = vec![0; max_frag_message(22).unwrap()];
+        let frags = split_message(&orig_message, b"nonce123", 22).unwrap();
+        assert_eq!(frags.len(), 255);
+        assert_eq!(num_frag_messages(orig_message.len(), 22).unwrap(), 255);
+    }
+
+    #[test]
+    fn test_unite_message_in_place() {
+        let orig_message = b"This is some message to be split";
+        let frags = split_message(orig_message, 
+                                  b"nonce123", 22).unwrap();
+        let message_id = array_ref![&frags[0],0,MESSAGE_ID_LEN];
+        let b = frags[0][MESSAGE_ID_LEN];
+        let share_length = frags[0].len() - FIELDS_LEN;
+
+        // Take the last b shares:
+        let mut buffer = vec![0u8; frags.len() * share_length];
+        let mut present = vec![false; frags.len()];
+        for i in frags.len() - b as usize .. frags.len() {
+            buffer[i * share_length .. (i + 1) * share_length]
+                .copy_from_slice(&frags[i][MESSAGE_ID_LEN + 1 + 1 .. frags[i].len() - ECC_LEN]);
+            present[i] = true;
+        }
+
+        let (m_range, is_block) = unite_message_in_place(message_id, b, share_length, 
+                                                         &mut buffer, &present).unwrap();
+        assert_eq!(orig_message, &buffer[m_range]);
+        assert!(!is_block);
+
+        // Not enough shares:
+        present[frags.len() - 1] = false;
+        assert!(unite_message_in_place(message_id, b, share_length, 
+                                       &mut buffer, &present).is_err());
+    }
+
     #[test]
     fn test_verify_frag_message() {
         let orig_message = b"This is some message to be split";
@@ -203,27 +443,4 @@ mod tests {
         frags[0][10] = 0x29;
         assert!(!verify_frag_message(&frags[0]));
     }
-
-    #[bench]
-    fn bench_unite_message(bencher: &mut Bencher) {
-        let seed: &[_] = &[1,2,3,4,5];
-        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
-        let mut orig_message = vec![0; 4096];
-        rng.fill_bytes(&mut orig_message);
-
-        let frags = split_message(&orig_message, 
-                                  b"nonce123", 200).unwrap();
-        let frag_len = frags[0].len();
-        assert!(frags.len() > 1);
-
-        let message_id = array_ref![&frags[0],0,MESSAGE_ID_LEN];
-        let b = frags[0][MESSAGE_ID_LEN];
-
-        let data_shares = &(0 .. b).map(|i| DataShare {
-            input: i, 
-            data: (&frags[i as usize][MESSAGE_ID_LEN + 1 + 1 .. frag_len - ECC_LEN]).to_vec()
-        }).collect::<Vec<DataShare>>();
-
-        bencher.iter(|| unite_message(message_id, &data_shares[0 .. b as usize]).unwrap());
-    }
 }
@@ -0,0 +1,279 @@
+//! Sending the shares of a message over several paths to the same peer.
+//!
+//! Every share of a message is independently useful, so the shares could be spread over
+//! several uplinks or several addresses of the same peer. An outage on one path then costs only
+//! the shares sent over it.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use futures::{Sink, Poll, StartSend, AsyncSink, Async};
+
+
+/// A sink index that was not returned by MultipathSink::add_sink().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSinkIndex;
+
+struct Path<B> {
+    sink_index: usize,
+    address: B,
+    weight: usize,
+    // Current weight for smooth weighted round robin:
+    current: isize,
+}
+
+/// A sink of (datagram, peer) that sends every datagram over one of the paths of the peer.
+/// A path is a pair of an inner sink and a destination address on that sink.
+/// Paths are picked by smooth weighted round robin, so a path of weight 2 gets twice the
+/// datagrams of a path of weight 1, and datagrams of different paths are interleaved.
+///
+/// Datagrams to a peer without any path of nonzero weight are dropped, and counted.
+/// See dropped_dgrams().
+pub struct MultipathSink<P,B,SK> {
+    sinks: Vec<SK>,
+    paths: HashMap<P, Vec<Path<B>>>,
+    dropped_dgrams: usize,
+}
+
+impl<P,B,SK,SKE> MultipathSink<P,B,SK>
+where
+    P: Hash + Eq,
+    B: PartialEq + Clone,
+    SK: Sink<SinkItem=(Vec<u8>, B), SinkError=SKE>,
+{
+    pub fn new() -> Self {
+        MultipathSink {
+            sinks: Vec::new(),
+            paths: HashMap::new(),
+            dropped_dgrams: 0,
+        }
+    }
+
+    /// Add an inner sink, for example a socket bound to one of the local uplinks.
+    /// Returns the index of the sink, to be used in add_path().
+    pub fn add_sink(&mut self, sink: SK) -> usize {
+        self.sinks.push(sink);
+        self.sinks.len() - 1
+    }
+
+    /// Send datagrams for peer through the sink sink_index, to the given address.
+    /// If the path already exists, only its weight is updated.
+    /// A weight of 0 disables the path, for example during an outage.
+    pub fn add_path(&mut self, peer: P, sink_index: usize, address: B, weight: usize) 
+        -> Result<(), InvalidSinkIndex> {
+
+        if sink_index >= self.sinks.len() {
+            return Err(InvalidSinkIndex);
+        }
+        let paths = self.paths.entry(peer).or_default();
+        for path in paths.iter_mut() {
+            if path.sink_index == sink_index && path.address == address {
+                path.weight = weight;
+                return Ok(());
+            }
+        }
+        paths.push(Path {
+            sink_index,
+            address,
+            weight,
+            current: 0,
+        });
+        Ok(())
+    }
+
+    /// Remove all the paths of peer.
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.paths.remove(peer);
+    }
+
+    /// Amount of datagrams dropped so far, because their peer had no path of nonzero weight.
+    pub fn dropped_dgrams(&self) -> usize {
+        self.dropped_dgrams
+    }
+
+    /// Pick the next path for peer. Returns the index of the sink and the destination address.
+    fn next_path(&mut self, peer: &P) -> Option<(usize, B)> {
+        let paths = self.paths.get_mut(peer)?;
+        let total_weight = paths.iter().map(|path| path.weight as isize).sum::<isize>();
+        if total_weight == 0 {
+            return None;
+        }
+        for path in paths.iter_mut() {
+            path.current += path.weight as isize;
+        }
+        let path = paths.iter_mut()
+            .filter(|path| path.weight > 0)
+            .max_by_key(|path| path.current)?;
+        path.current -= total_weight;
+        Some((path.sink_index, path.address.clone()))
+    }
+}
+
+impl<P,B,SK,SKE> Sink for MultipathSink<P,B,SK>
+where
+    P: Hash + Eq,
+    B: PartialEq + Clone,
+    SK: Sink<SinkItem=(Vec<u8>, B), SinkError=SKE>,
+{
+    type SinkItem = (Vec<u8>, P);
+    type SinkError = SKE;
+
+    /// If the sink of the picked path is not ready, the datagram is returned, and the path
+    /// loses its turn. A busy path therefore gets fewer datagrams than its weight.
+    fn start_send(&mut self, item: Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+
+        let (dgram, peer) = item;
+        let (sink_index, address) = match self.next_path(&peer) {
+            Some(path) => path,
+            // No route to peer:
+            None => {
+                self.dropped_dgrams += 1;
+                return Ok(AsyncSink::Ready);
+            },
+        };
+        Ok(match self.sinks[sink_index].start_send((dgram, address))? {
+            AsyncSink::Ready => AsyncSink::Ready,
+            AsyncSink::NotReady((dgram, _)) => AsyncSink::NotReady((dgram, peer)),
+        })
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let mut is_ready = true;
+        for sink in &mut self.sinks {
+            is_ready &= sink.poll_complete()?.is_ready();
+        }
+        match is_ready {
+            true => Ok(Async::Ready(())),
+            false => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Maps the source addresses of incoming datagrams to the peer they belong to.
+/// See FragMsgReceiver::set_peer_map().
+pub struct PeerMap<A> {
+    peers: HashMap<A, A>,
+}
+
+impl<A> PeerMap<A>
+where
+    A: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        PeerMap {
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Datagrams from address belong to peer.
+    pub fn add_address(&mut self, address: A, peer: A) {
+        self.peers.insert(address, peer);
+    }
+
+    pub fn remove_address(&mut self, address: &A) {
+        self.peers.remove(address);
+    }
+
+    /// The peer of the given source address. Unknown addresses are their own peer.
+    pub fn peer_of(&self, address: A) -> A {
+        match self.peers.get(&address) {
+            Some(peer) => peer.clone(),
+            None => address,
+        }
+    }
+}
+
+impl<P,B,SK,SKE> Default for MultipathSink<P,B,SK>
+where
+    P: Hash + Eq,
+    B: PartialEq + Clone,
+    SK: Sink<SinkItem=(Vec<u8>, B), SinkError=SKE>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A> Default for PeerMap<A>
+where
+    A: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, Stream};
+    use futures::sync::mpsc;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_multipath_sink_weights() {
+        let (sink_a, stream_a) = mpsc::channel::<(Vec<u8>, u32)>(16);
+        let (sink_b, stream_b) = mpsc::channel::<(Vec<u8>, u32)>(16);
+
+        let mut multipath_sink = MultipathSink::new();
+        let index_a = multipath_sink.add_sink(sink_a);
+        let index_b = multipath_sink.add_sink(sink_b);
+        multipath_sink.add_path("peer", index_a, 1, 2).unwrap();
+        multipath_sink.add_path("peer", index_b, 2, 1).unwrap();
+        multipath_sink.add_path("other", index_b, 3, 1).unwrap();
+
+        let dgrams = (0 .. 9u8).map(|i| (vec![i], "peer"))
+            .chain(vec![(vec![9], "other"), (vec![10], "unknown")]);
+        let mut core = Core::new().unwrap();
+        let multipath_sink = core.run(multipath_sink.send_all(stream::iter_ok(dgrams)))
+            .unwrap().0;
+        // The datagram to the unknown peer was dropped:
+        assert_eq!(multipath_sink.dropped_dgrams(), 1);
+        drop(multipath_sink);
+
+        let received_a = core.run(stream_a.collect()).unwrap();
+        let received_b = core.run(stream_b.collect()).unwrap();
+        // Datagrams of the two paths are interleaved 2:1:
+        assert_eq!(received_a, vec![(vec![0], 1), (vec![2], 1), (vec![3], 1),
+                                    (vec![5], 1), (vec![6], 1), (vec![8], 1)]);
+        assert_eq!(received_b, vec![(vec![1], 2), (vec![4], 2), (vec![7], 2), (vec![9], 3)]);
+    }
+
+    #[test]
+    fn test_multipath_sink_disabled_path() {
+        let (sink_a, stream_a) = mpsc::channel::<(Vec<u8>, u32)>(16);
+        let (sink_b, stream_b) = mpsc::channel::<(Vec<u8>, u32)>(16);
+
+        let mut multipath_sink = MultipathSink::new();
+        let index_a = multipath_sink.add_sink(sink_a);
+        let index_b = multipath_sink.add_sink(sink_b);
+        multipath_sink.add_path(0u32, index_a, 1, 1).unwrap();
+        multipath_sink.add_path(0u32, index_b, 2, 1).unwrap();
+        // Path b is down:
+        multipath_sink.add_path(0u32, index_b, 2, 0).unwrap();
+        assert_eq!(multipath_sink.add_path(0u32, index_b + 1, 3, 1), Err(InvalidSinkIndex));
+
+        let dgrams = (0 .. 4u8).map(|i| (vec![i], 0u32));
+        let mut core = Core::new().unwrap();
+        let multipath_sink = core.run(multipath_sink.send_all(stream::iter_ok(dgrams)))
+            .unwrap().0;
+        assert_eq!(multipath_sink.dropped_dgrams(), 0);
+        drop(multipath_sink);
+
+        assert_eq!(core.run(stream_a.collect()).unwrap().len(), 4);
+        assert_eq!(core.run(stream_b.collect()).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_peer_map() {
+        let mut peer_map = PeerMap::new();
+        peer_map.add_address(2u32, 1u32);
+        assert_eq!(peer_map.peer_of(2), 1);
+        assert_eq!(peer_map.peer_of(1), 1);
+        assert_eq!(peer_map.peer_of(3), 3);
+        peer_map.remove_address(&2);
+        assert_eq!(peer_map.peer_of(2), 2);
+    }
+}
@@ -0,0 +1,509 @@
+//! Path MTU discovery: finding the largest datagram that reliably reaches every destination.
+//!
+//! The sender sends probe datagrams of increasing lengths to a destination, and the remote
+//! receiver echoes back every probe it gets. A length is confirmed once the echo of its probe
+//! arrives, and considered too large if probe_attempts probes of that length got no echo.
+//! The lengths are searched by bisection between min_dgram_len, which is assumed to reach any
+//! destination, and max_dgram_len.
+//!
+//! Probes and echoes are control messages, which are never valid Fragmentos messages
+//! (b is always 0), so receivers that don't do path MTU discovery ignore them.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use rand::{Rng, StdRng};
+
+use ::messages::{short_hash, verify_frag_message, SHORT_HASH_LEN};
+use ::blocks::max_block_data;
+
+/*
+Fragmentos control message:
+
+- probeId           [8 bytes]
+- b                 [1 byte]    (Always 0)
+- kind              [1 byte]    (0 for a probe, 1 for an echo. 2 is a delivery report, 
+                                 see feedback.rs)
+- probeLen          [2 bytes]   (Big endian length of the probe)
+- padding           [variable]  (Zeroes, up to probeLen for a probe. Empty for an echo)
+- shortHash         [8 bytes]   (First 8 bytes of Sha512/256)
+*/
+
+const PROBE_ID_LEN: usize = 8;
+const KIND_PROBE: u8 = 0;
+const KIND_ECHO: u8 = 1;
+// Length of all control message fields, excluding padding:
+const CONTROL_FIELDS_LEN: usize = PROBE_ID_LEN + 1 + 1 + 2 + SHORT_HASH_LEN;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ControlMessage {
+    Probe { probe_id: [u8; PROBE_ID_LEN], probe_len: usize },
+    Echo { probe_id: [u8; PROBE_ID_LEN], probe_len: usize },
+}
+
+fn encode_control(probe_id: &[u8; PROBE_ID_LEN], kind: u8, probe_len: usize,
+                  dgram_len: usize) -> Vec<u8> {
+
+    let mut dgram = vec![0u8; dgram_len];
+    dgram[.. PROBE_ID_LEN].copy_from_slice(probe_id);
+    dgram[PROBE_ID_LEN] = 0;
+    dgram[PROBE_ID_LEN + 1] = kind;
+    dgram[PROBE_ID_LEN + 2] = (probe_len >> 8) as u8;
+    dgram[PROBE_ID_LEN + 3] = probe_len as u8;
+    let hash = short_hash(&dgram[.. dgram_len - SHORT_HASH_LEN]);
+    dgram[dgram_len - SHORT_HASH_LEN ..].copy_from_slice(&hash);
+    dgram
+}
+
+fn encode_probe(probe_id: &[u8; PROBE_ID_LEN], probe_len: usize) -> Vec<u8> {
+    encode_control(probe_id, KIND_PROBE, probe_len, probe_len)
+}
+
+fn encode_echo(probe_id: &[u8; PROBE_ID_LEN], probe_len: usize) -> Vec<u8> {
+    encode_control(probe_id, KIND_ECHO, probe_len, CONTROL_FIELDS_LEN)
+}
+
+/// Parse a control message. Returns None if dgram is not a valid control message.
+fn parse_control(dgram: &[u8]) -> Option<ControlMessage> {
+    if dgram.len() < CONTROL_FIELDS_LEN || dgram[PROBE_ID_LEN] != 0 {
+        return None;
+    }
+    if !verify_frag_message(dgram) {
+        return None;
+    }
+    let mut probe_id = [0u8; PROBE_ID_LEN];
+    probe_id.copy_from_slice(&dgram[.. PROBE_ID_LEN]);
+    let probe_len = ((dgram[PROBE_ID_LEN + 2] as usize) << 8) | dgram[PROBE_ID_LEN + 3] as usize;
+
+    match dgram[PROBE_ID_LEN + 1] {
+        KIND_PROBE if dgram.len() == probe_len =>
+            Some(ControlMessage::Probe { probe_id, probe_len }),
+        KIND_ECHO if dgram.len() == CONTROL_FIELDS_LEN =>
+            Some(ControlMessage::Echo { probe_id, probe_len }),
+        _ => None,
+    }
+}
+
+
+/// A PmtuConfig that PathMtu::new() rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmtuConfigError {
+    /// min_dgram_len is too small to carry a probe, or a block of a message.
+    MinDgramTooSmall,
+    /// max_dgram_len is smaller than min_dgram_len, or larger than 65535.
+    InvalidMaxDgram,
+    /// granularity, probe_attempts, max_paths or max_echoes is 0.
+    ZeroLimit,
+}
+
+#[derive(Debug, Clone)]
+pub struct PmtuConfig {
+    /// Datagram length used until a larger one is confirmed. Should reach any destination.
+    pub min_dgram_len: usize,
+    /// Largest datagram length to probe for. At most 65535.
+    pub max_dgram_len: usize,
+    /// Discovery of a destination stops once the confirmed length is at most granularity
+    /// bytes below the smallest length known to be too large.
+    pub granularity: usize,
+    /// Amount of unanswered probes of a length before the length is considered too large.
+    pub probe_attempts: usize,
+    /// Amount of time ticks to wait for the echo of a probe.
+    pub probe_timeout_ticks: usize,
+    /// Maximum amount of destinations to remember. Once reached, the destination that was 
+    /// sent to least recently is forgotten to make room for a new one.
+    pub max_paths: usize,
+    /// Maximum amount of echoes waiting to be sent. Probes that arrive while the queue is full
+    /// are not answered, and will be sent again by their source.
+    pub max_echoes: usize,
+}
+
+impl PmtuConfig {
+    /// Check that discovery could work with this configuration.
+    pub fn validate(&self) -> Result<(), PmtuConfigError> {
+        if self.min_dgram_len < CONTROL_FIELDS_LEN || max_block_data(self.min_dgram_len).is_err() {
+            return Err(PmtuConfigError::MinDgramTooSmall);
+        }
+        if self.max_dgram_len < self.min_dgram_len || self.max_dgram_len > 0xffff {
+            return Err(PmtuConfigError::InvalidMaxDgram);
+        }
+        if self.granularity == 0 || self.probe_attempts == 0 || 
+            self.max_paths == 0 || self.max_echoes == 0 {
+            return Err(PmtuConfigError::ZeroLimit);
+        }
+        Ok(())
+    }
+}
+
+impl Default for PmtuConfig {
+    /// Between 512 bytes and the largest UDP payload over IPv4 on Ethernet.
+    fn default() -> Self {
+        PmtuConfig {
+            min_dgram_len: 512,
+            max_dgram_len: 1472,
+            granularity: 16,
+            probe_attempts: 3,
+            probe_timeout_ticks: 2,
+            max_paths: 1024,
+            max_echoes: 64,
+        }
+    }
+}
+
+struct Probe {
+    probe_id: [u8; PROBE_ID_LEN],
+    probe_len: usize,
+    attempts: usize,
+    // Ticks since the probe was sent. None if the probe should be sent again:
+    opt_ticks: Option<usize>,
+}
+
+struct PathState {
+    // Largest confirmed datagram length:
+    low: usize,
+    // Smallest datagram length known to be too large:
+    high: usize,
+    opt_probe: Option<Probe>,
+    // Value of PathMtuInner::num_polls when this path was last sent to:
+    last_used: u64,
+}
+
+struct PathMtuInner<A> {
+    config: PmtuConfig,
+    rng: StdRng,
+    paths: HashMap<A, PathState>,
+    // Echoes waiting to be sent:
+    echoes: VecDeque<(Vec<u8>, A)>,
+    // Amount of calls to poll_probe(), used to find the least recently used path:
+    num_polls: u64,
+}
+
+/// Discovered datagram lengths for every destination.
+/// Probes are sent by FragMsgSender, but their echoes arrive at FragMsgReceiver, so both
+/// should be given clones of the same PathMtu. See FragMsgSender::set_path_mtu().
+pub struct PathMtu<A> {
+    inner: Arc<Mutex<PathMtuInner<A>>>,
+}
+
+impl<A> Clone for PathMtu<A> {
+    fn clone(&self) -> Self {
+        PathMtu {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<A> PathMtu<A>
+where
+    A: Hash + Eq + Clone,
+{
+    pub fn new(config: PmtuConfig, rng: StdRng) -> Result<Self, PmtuConfigError> {
+        config.validate()?;
+        Ok(PathMtu {
+            inner: Arc::new(Mutex::new(PathMtuInner {
+                config,
+                rng,
+                paths: HashMap::new(),
+                echoes: VecDeque::new(),
+                num_polls: 0,
+            })),
+        })
+    }
+
+    /// Largest datagram length confirmed for address, or min_dgram_len if there is none yet.
+    pub fn max_dgram_len(&self, address: &A) -> usize {
+        let inner = self.inner.lock().unwrap();
+        match inner.paths.get(address) {
+            // Never below the validated minimum, so that messages could always be split:
+            Some(path_state) => cmp::max(path_state.low, inner.config.min_dgram_len),
+            None => inner.config.min_dgram_len,
+        }
+    }
+
+    /// Forget everything discovered about address, for example after a route change.
+    /// Discovery starts again with the next message sent to address.
+    pub fn reset(&self, address: &A) {
+        self.inner.lock().unwrap().paths.remove(address);
+    }
+
+    /// A probe to be sent to address, if discovery of address is not done, and no probe is
+    /// waiting for an echo.
+    pub(crate) fn poll_probe(&self, address: &A) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let PathMtuInner { ref config, ref mut rng, ref mut paths, 
+                           ref mut num_polls, .. } = *inner;
+        *num_polls += 1;
+
+        if paths.len() >= config.max_paths && !paths.contains_key(address) {
+            let opt_oldest = paths.iter()
+                .min_by_key(|&(_, path_state)| path_state.last_used)
+                .map(|(oldest, _)| oldest.clone());
+            if let Some(oldest) = opt_oldest {
+                paths.remove(&oldest);
+            }
+        }
+        let path_state = paths.entry(address.clone()).or_insert_with(|| PathState {
+            low: config.min_dgram_len,
+            high: config.max_dgram_len + 1,
+            opt_probe: None,
+            last_used: 0,
+        });
+        path_state.last_used = *num_polls;
+
+        if path_state.opt_probe.is_none() {
+            if path_state.high - path_state.low <= config.granularity {
+                // Discovery is done:
+                return None;
+            }
+            // The largest length is tried first, as it usually works:
+            let probe_len = if path_state.high > config.max_dgram_len {
+                config.max_dgram_len
+            } else {
+                (path_state.low + path_state.high) / 2
+            };
+            let mut probe_id = [0u8; PROBE_ID_LEN];
+            rng.fill_bytes(&mut probe_id);
+            path_state.opt_probe = Some(Probe {
+                probe_id,
+                probe_len,
+                attempts: 0,
+                opt_ticks: None,
+            });
+        }
+
+        let probe = path_state.opt_probe.as_mut().unwrap();
+        match probe.opt_ticks {
+            // Still waiting for an echo:
+            Some(_) => None,
+            None => {
+                probe.attempts += 1;
+                probe.opt_ticks = Some(0);
+                Some(encode_probe(&probe.probe_id, probe.probe_len))
+            },
+        }
+    }
+
+    /// Handle a datagram that arrived from address.
+    /// Returns true if the datagram was a control message, and should not be processed further.
+    pub(crate) fn received_control(&self, dgram: &[u8], address: &A) -> bool {
+        let control_message = match parse_control(dgram) {
+            Some(control_message) => control_message,
+            None => return false,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        match control_message {
+            ControlMessage::Probe { probe_id, probe_len } => {
+                if inner.echoes.len() < inner.config.max_echoes {
+                    inner.echoes.push_back((encode_echo(&probe_id, probe_len), address.clone()));
+                }
+            },
+            ControlMessage::Echo { probe_id, probe_len } => {
+                if let Some(path_state) = inner.paths.get_mut(address) {
+                    let is_current = match path_state.opt_probe {
+                        Some(ref probe) => probe.probe_id == probe_id &&
+                            probe.probe_len == probe_len,
+                        None => false,
+                    };
+                    if is_current {
+                        path_state.low = cmp::max(path_state.low, probe_len);
+                        path_state.opt_probe = None;
+                    }
+                }
+            },
+        }
+        true
+    }
+
+    /// An echo to be sent back to the source of a probe.
+    pub(crate) fn pop_echo(&self) -> Option<(Vec<u8>, A)> {
+        self.inner.lock().unwrap().echoes.pop_front()
+    }
+
+    /// Should be called periodically. Probes that were not echoed for probe_timeout_ticks
+    /// ticks are sent again, or their length is considered too large.
+    pub fn time_tick(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let PathMtuInner { ref config, ref mut paths, .. } = *inner;
+
+        for path_state in paths.values_mut() {
+            let timed_out = match path_state.opt_probe {
+                Some(Probe { opt_ticks: Some(ref mut ticks), .. }) => {
+                    *ticks += 1;
+                    *ticks > config.probe_timeout_ticks
+                },
+                _ => false,
+            };
+            if !timed_out {
+                continue;
+            }
+            let give_up = {
+                let probe = path_state.opt_probe.as_mut().unwrap();
+                probe.opt_ticks = None;
+                probe.attempts >= config.probe_attempts
+            };
+            if give_up {
+                path_state.high = path_state.opt_probe.take().unwrap().probe_len;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use ::state_machine::FragStateMachine;
+
+    fn new_path_mtu(seed: usize) -> PathMtu<u32> {
+        let seed: &[_] = &[1,2,3,4,seed];
+        PathMtu::new(PmtuConfig::default(), SeedableRng::from_seed(seed)).unwrap()
+    }
+
+    #[test]
+    fn test_control_messages() {
+        let probe_id = [1,2,3,4,5,6,7,8];
+        let probe = encode_probe(&probe_id, 600);
+        assert_eq!(probe.len(), 600);
+        assert_eq!(parse_control(&probe),
+                   Some(ControlMessage::Probe { probe_id, probe_len: 600 }));
+
+        let echo = encode_echo(&probe_id, 600);
+        assert_eq!(echo.len(), CONTROL_FIELDS_LEN);
+        assert_eq!(parse_control(&echo),
+                   Some(ControlMessage::Echo { probe_id, probe_len: 600 }));
+
+        // A corrupted probe is not a control message:
+        let mut corrupt = probe.clone();
+        corrupt[100] ^= 1;
+        assert_eq!(parse_control(&corrupt), None);
+        // A truncated probe is not a control message:
+        assert_eq!(parse_control(&probe[.. 599]), None);
+
+        // Fragmentos receivers ignore control messages:
+        let mut fsm = FragStateMachine::new();
+        assert_eq!(fsm.received_frag_message(&probe), None);
+        assert_eq!(fsm.received_frag_message(&echo), None);
+    }
+
+    /// Run discovery between a sender and a receiver over a path that drops every datagram
+    /// longer than path_len. Returns the discovered length.
+    fn discover(path_len: usize) -> usize {
+        let sender = new_path_mtu(1);
+        let receiver = new_path_mtu(2);
+        let address = 7u32;
+
+        for _ in 0 .. 100 {
+            if let Some(probe) = sender.poll_probe(&address) {
+                if probe.len() <= path_len {
+                    assert!(receiver.received_control(&probe, &address));
+                    let (echo, echo_address) = receiver.pop_echo().unwrap();
+                    assert_eq!(echo_address, address);
+                    assert!(sender.received_control(&echo, &address));
+                }
+            }
+            sender.time_tick();
+        }
+        sender.max_dgram_len(&address)
+    }
+
+    #[test]
+    fn test_discover_max() {
+        assert_eq!(discover(1500), 1472);
+    }
+
+    #[test]
+    fn test_discover_bisection() {
+        let config = PmtuConfig::default();
+        let discovered = discover(1000);
+        assert!(discovered <= 1000);
+        assert!(discovered + config.granularity >= 1000);
+    }
+
+    #[test]
+    fn test_discover_nothing() {
+        assert_eq!(discover(100), PmtuConfig::default().min_dgram_len);
+    }
+
+    #[test]
+    fn test_unknown_address() {
+        let path_mtu = new_path_mtu(1);
+        assert_eq!(path_mtu.max_dgram_len(&3), 512);
+        assert!(path_mtu.poll_probe(&3).is_some());
+        // Waiting for an echo:
+        assert!(path_mtu.poll_probe(&3).is_none());
+        path_mtu.reset(&3);
+        assert!(path_mtu.poll_probe(&3).is_some());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let new_path_mtu_with = |config: PmtuConfig| {
+            let seed: &[_] = &[1,2,3,4,5];
+            PathMtu::<u32>::new(config, SeedableRng::from_seed(seed)).err()
+        };
+        assert_eq!(new_path_mtu_with(PmtuConfig { min_dgram_len: 10, ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::MinDgramTooSmall));
+        assert_eq!(new_path_mtu_with(PmtuConfig { max_dgram_len: 500, ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::InvalidMaxDgram));
+        assert_eq!(new_path_mtu_with(PmtuConfig { max_dgram_len: 1 << 16, 
+                                                  ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::InvalidMaxDgram));
+        assert_eq!(new_path_mtu_with(PmtuConfig { granularity: 0, ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::ZeroLimit));
+        assert_eq!(new_path_mtu_with(PmtuConfig { max_paths: 0, ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::ZeroLimit));
+        assert_eq!(new_path_mtu_with(PmtuConfig { max_echoes: 0, ..PmtuConfig::default() }),
+                   Some(PmtuConfigError::ZeroLimit));
+        assert_eq!(new_path_mtu_with(PmtuConfig::default()), None);
+    }
+
+    #[test]
+    fn test_max_paths() {
+        let config = PmtuConfig {
+            max_paths: 2,
+            ..PmtuConfig::default()
+        };
+        let seed: &[_] = &[1,2,3,4,5];
+        let sender = PathMtu::new(config, SeedableRng::from_seed(seed)).unwrap();
+        let receiver = new_path_mtu(2);
+
+        // Confirm the largest length for the address 1:
+        let probe = sender.poll_probe(&1).unwrap();
+        assert!(receiver.received_control(&probe, &1));
+        let (echo, _) = receiver.pop_echo().unwrap();
+        assert!(sender.received_control(&echo, &1));
+        assert_eq!(sender.max_dgram_len(&1), 1472);
+
+        assert!(sender.poll_probe(&2).is_some());
+        assert!(sender.poll_probe(&1).is_none());
+        // The address 2 was used least recently, and is forgotten:
+        assert!(sender.poll_probe(&3).is_some());
+        assert_eq!(sender.inner.lock().unwrap().paths.len(), 2);
+        assert_eq!(sender.max_dgram_len(&1), 1472);
+        assert!(sender.poll_probe(&2).is_some());
+        // Now the address 1 is forgotten:
+        assert_eq!(sender.max_dgram_len(&1), 512);
+    }
+
+    #[test]
+    fn test_max_echoes() {
+        let config = PmtuConfig {
+            max_echoes: 2,
+            ..PmtuConfig::default()
+        };
+        let seed: &[_] = &[1,2,3,4,5];
+        let receiver = PathMtu::new(config, SeedableRng::from_seed(seed)).unwrap();
+        let sender = new_path_mtu(2);
+
+        for address in 0 .. 3u32 {
+            let probe = sender.poll_probe(&address).unwrap();
+            assert!(receiver.received_control(&probe, &address));
+        }
+        // The last probe was not answered:
+        assert_eq!(receiver.pop_echo().unwrap().1, 0);
+        assert_eq!(receiver.pop_echo().unwrap().1, 1);
+        assert!(receiver.pop_echo().is_none());
+    }
+}
@@ -1,7 +1,5 @@
-use std;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 use std::{io, cmp};
-use std::collections::VecDeque;
 
 use futures::sync::mpsc;
 use futures::{Sink, Future, Poll, Stream, Async, AsyncSink};
@@ -10,39 +8,21 @@ use tokio_core::reactor;
 use tokio_core::reactor::{Timeout, Handle};
 
 
-const MAX_TOKENS_PER_MS: usize = 1 << 32;
-
-/// Something that has length.
-/// This could example, describe chunks of data, 
-/// where length() is the amount of bytes in the chunk.
-pub trait Length {
-    fn len(&self) -> usize;
-}
-
-
-impl Length for (Vec<u8>, std::net::SocketAddr) {
-    fn len(&self) -> usize {
-        self.0.len()
-    }
-}
+pub use ::rate_limit_queue::{Length, QueueItem, RateLimitStats};
+use ::rate_limit_queue::{RateLimitQueue, Queued, Unprioritized};
 
 
+// The spawned rate limiter stops once its timer fails:
 enum RateLimitError {
-    TimeoutError(io::Error),
+    TimeoutError,
 }
 
 
-struct RateLimitFuture<T> {
+struct RateLimitFuture<T,Q> {
     inner_sender: mpsc::Sender<T>,
     inner_receiver_opt: Option<mpsc::Receiver<T>>,
-    pending_items: VecDeque<T>,
+    queue: RateLimitQueue<Q>,
     opt_next_timeout: Option<Timeout>,
-    send_tokens_left: usize,
-    remainder_tokens: usize,
-    queue_len: usize,
-    tokens_per_ms: usize,
-    min_tokens_per_ms: usize,
-    token_shortage: bool,
     handle: Handle,
 }
 
@@ -54,7 +34,7 @@ enum TrySendResult {
     SenderError,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum TryRecvResult {
     NoReceiver,
     ReceiverNotReady,
@@ -62,55 +42,33 @@ enum TryRecvResult {
     QueueFull,
 }
 
-impl<T: Length> RateLimitFuture<T> {
+impl<T, Q: Queued<T>> RateLimitFuture<T,Q> {
     fn new(inner_sender: mpsc::Sender<T>, 
            inner_receiver: mpsc::Receiver<T>,
            queue_len: usize,
            min_tokens_per_ms: usize,
+           stats: RateLimitStats,
            handle: &Handle) -> Self {
 
         RateLimitFuture {
             inner_sender, 
             inner_receiver_opt: Some(inner_receiver), 
-            pending_items: VecDeque::new(),
+            queue: RateLimitQueue::new(queue_len, min_tokens_per_ms, stats),
             opt_next_timeout: None,
-            send_tokens_left: min_tokens_per_ms,
-            remainder_tokens: 0,
-            queue_len,
-            tokens_per_ms: min_tokens_per_ms,
-            min_tokens_per_ms,
-            token_shortage: false,
             handle: handle.clone(),
         }
     }
 
-    fn inspect_and_correct(&mut self) {
-        let new_tokens_per_ms = if self.token_shortage {
-            // We are using all the tokens, we need to increase the speed:
-            (self.tokens_per_ms * 2) + 1
-        } else {
-            // Leave unchanged
-            if self.tokens_per_ms > self.min_tokens_per_ms {
-                self.tokens_per_ms - 1
-            } else {
-                self.min_tokens_per_ms
-            }
-        };
-
-        self.token_shortage = false;
-        self.tokens_per_ms = cmp::min(new_tokens_per_ms, MAX_TOKENS_PER_MS);
-    }
-
     fn try_recv(&mut self) -> TryRecvResult {
         match self.inner_receiver_opt.take() {
             Some(mut inner_receiver) => {
-                while self.pending_items.len() < self.queue_len {
+                while self.queue.has_room() {
                     match inner_receiver.poll() {
                         Ok(Async::NotReady) => {
                             self.inner_receiver_opt = Some(inner_receiver);
                             return TryRecvResult::ReceiverNotReady;
                         },
-                        Ok(Async::Ready(Some(item))) => self.pending_items.push_back(item),
+                        Ok(Async::Ready(Some(item))) => self.queue.push_item(Q::from_item(item)),
                         Ok(Async::Ready(None)) | Err(()) => return TryRecvResult::ReceiverClosed,
                     }
                 }
@@ -121,75 +79,51 @@ impl<T: Length> RateLimitFuture<T> {
         }
     }
 
-    // TODO: This mechanism needs to be tested somehow.
-    // How to separate the logic here from the rest of the code?
     fn try_send(&mut self) -> TrySendResult {
-        while let Some(item) = self.pending_items.pop_front() {
-            // Check if we have enough send tokens to send this element:
-            let item_len = item.len();
-            debug_assert!(self.remainder_tokens <= item_len);
-            if item_len > self.send_tokens_left + self.remainder_tokens {
-                // Put the item back into the queue:
-                self.pending_items.push_front(item);
-                self.remainder_tokens += self.send_tokens_left;
-                self.send_tokens_left = 0;
-                return TrySendResult::NoMoreTokens;
-            }
-            let send_tokens_in_use = item_len - self.remainder_tokens;
-            match self.inner_sender.start_send(item) {
+        while !self.queue.is_empty() {
+            let item = match self.queue.pop_item() {
+                Some(item) => item,
+                None => return TrySendResult::NoMoreTokens,
+            };
+            match self.inner_sender.start_send(item.into_item()) {
                 Err(_send_error) => return TrySendResult::SenderError,
                 Ok(AsyncSink::NotReady(item)) => {
                     // Put the item back into the queue:
-                    self.pending_items.push_front(item);
-                    self.remainder_tokens += send_tokens_in_use;
-                    self.send_tokens_left -= send_tokens_in_use;
+                    self.queue.unpop_item(Q::from_item(item));
                     return TrySendResult::SenderNotReady;
                 },
-                Ok(AsyncSink::Ready) => {
-                    self.remainder_tokens = 0;
-                    self.send_tokens_left -= send_tokens_in_use;
-                },
+                Ok(AsyncSink::Ready) => {},
             }
         }
-        return TrySendResult::NoMoreItems;
+        TrySendResult::NoMoreItems
     }
 }
 
-impl<T: Length> Future for RateLimitFuture<T> {
+impl<T, Q: Queued<T>> Future for RateLimitFuture<T,Q> {
     type Item = ();
     type Error = RateLimitError;
 
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         // TODO: We probably need to add a loop {} to this poll() function.
-        // If timer is ready, we add tokens to the token bucket
-        // by increasing self.send_tokens_left.
+        // If timer is ready, we add tokens to the token bucket.
         match self.opt_next_timeout.take() {
             None => {},
             Some(mut next_timeout) => {
                 match next_timeout.poll() {
-                    Ok(Async::Ready(())) => {
-                        self.inspect_and_correct();
-                        self.send_tokens_left = self.tokens_per_ms;
-                    },
+                    Ok(Async::Ready(())) => self.queue.tick(),
                     Ok(Async::NotReady) => {},
-                    Err(e) => return Err(RateLimitError::TimeoutError(e)),
+                    Err(_) => return Err(RateLimitError::TimeoutError),
                 }
             },
         };
 
-        // println!("Entering loop...");
-        // println!("send_tokens_left = {}", self.send_tokens_left);
-        // println!("tokens_per_ms = {}", self.tokens_per_ms);
-        // println!("self.pending_items.len() = {}", self.pending_items.len());
         loop {
+            self.queue.drop_stale();
             // Send as many messages as possible:
             match self.try_send() {
                 TrySendResult::NoMoreItems => {},
-                TrySendResult::NoMoreTokens => {
-                    self.token_shortage = true;
-                    break;
-                },
+                TrySendResult::NoMoreTokens |
                 TrySendResult::SenderNotReady => break,
                 TrySendResult::SenderError => return Ok(Async::Ready(())),
             }
@@ -202,11 +136,8 @@ impl<T: Length> Future for RateLimitFuture<T> {
                 TryRecvResult::ReceiverClosed => break,
             }
         }
-        // println!("Exiting loop...");
 
-
-
-        if self.pending_items.len() == 0 && self.inner_receiver_opt.is_none() {
+        if self.queue.is_empty() && self.inner_receiver_opt.is_none() {
             // If there are no more pending items to be sent, and the receiver is closed,
             // we have nothing more to do here.
             return Ok(Async::Ready(()));
@@ -214,17 +145,16 @@ impl<T: Length> Future for RateLimitFuture<T> {
 
 
         // If there are any pending items, set the Timer to poll us again later.
-        if self.pending_items.len() > 0 {
+        if !self.queue.is_empty() {
             self.opt_next_timeout = Some(
                 match Timeout::new(Duration::from_millis(1), &self.handle) {
                     Ok(mut timeout) => {
-                        match timeout.poll() {
-                            Err(e) => return Err(RateLimitError::TimeoutError(e)),
-                            _ => {},
-                        };
+                        if timeout.poll().is_err() {
+                            return Err(RateLimitError::TimeoutError);
+                        }
                         timeout
                     }
-                    Err(e) => return Err(RateLimitError::TimeoutError(e)),
+                    Err(_) => return Err(RateLimitError::TimeoutError),
                 }
             );
         }
@@ -233,38 +163,126 @@ impl<T: Length> Future for RateLimitFuture<T> {
 }
 
 
+/// How the datagrams of a message are spread over time.
+/// The reactor timer has a resolution of about a millisecond, so shorter gaps are rounded up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pacing {
+    /// A fixed gap between consecutive datagrams.
+    Gap(Duration),
+    /// The datagrams of every message are spread evenly over the given window.
+    Window(Duration),
+}
+
+/// Makes sure consecutive datagrams are not sent back to back, so that a short burst of
+/// losses (For example, a router queue overflow) only drops a few shares of every message.
+pub struct Pacer {
+    pacing: Pacing,
+    opt_next_send: Option<Instant>,
+    opt_timeout: Option<Timeout>,
+    handle: Handle,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing, handle: &Handle) -> Self {
+        Pacer {
+            pacing,
+            opt_next_send: None,
+            opt_timeout: None,
+            handle: handle.clone(),
+        }
+    }
+
+    /// Check if the next datagram could be sent now.
+    /// If not, the current task is notified when it can.
+    pub fn poll_ready(&mut self) -> Poll<(), io::Error> {
+        let next_send = match self.opt_next_send {
+            None => return Ok(Async::Ready(())),
+            Some(next_send) => next_send,
+        };
+
+        let mut timeout = match self.opt_timeout.take() {
+            Some(timeout) => timeout,
+            None => Timeout::new_at(next_send, &self.handle)?,
+        };
+        match timeout.poll()? {
+            Async::Ready(()) => {
+                self.opt_next_send = None;
+                Ok(Async::Ready(()))
+            },
+            Async::NotReady => {
+                self.opt_timeout = Some(timeout);
+                Ok(Async::NotReady)
+            },
+        }
+    }
+
+    /// Should be called after sending a datagram of a message of num_dgrams datagrams.
+    pub fn sent(&mut self, num_dgrams: usize) {
+        let gap = match self.pacing {
+            Pacing::Gap(gap) => gap,
+            Pacing::Window(window) => window / cmp::max(num_dgrams, 1) as u32,
+        };
+        self.opt_next_send = Some(Instant::now() + gap);
+        self.opt_timeout = None;
+    }
+
+    /// Should be called after sending the last datagram of a message.
+    /// The first datagram of the next message could be sent right away.
+    pub fn reset(&mut self) {
+        self.opt_next_send = None;
+        self.opt_timeout = None;
+    }
+}
+
+
 pub fn rate_limit_channel<T: Length + 'static>(queue_len: usize, min_tokens_per_ms: usize, handle: &reactor::Handle) -> 
     (mpsc::Sender<T>, mpsc::Receiver<T>)  {
 
+    let (rate_limit_sender, rate_limit_receiver, _stats) = 
+        spawn_rate_limit::<T, Unprioritized<T>>(queue_len, min_tokens_per_ms, handle);
+    (rate_limit_sender, rate_limit_receiver)
+}
+
+/// Like rate_limit_channel(), also returning the counters of the rate limiter.
+pub fn rate_limit_channel_stats<T: QueueItem + 'static>(queue_len: usize, 
+                                                        min_tokens_per_ms: usize, 
+                                                        handle: &reactor::Handle) -> 
+    (mpsc::Sender<T>, mpsc::Receiver<T>, RateLimitStats)  {
+
+    spawn_rate_limit::<T, T>(queue_len, min_tokens_per_ms, handle)
+}
+
+fn spawn_rate_limit<T: 'static, Q: Queued<T> + 'static>(queue_len: usize, 
+                                                        min_tokens_per_ms: usize, 
+                                                        handle: &reactor::Handle) -> 
+    (mpsc::Sender<T>, mpsc::Receiver<T>, RateLimitStats)  {
+
+    let stats = RateLimitStats::default();
     let (rate_limit_sender, inner_receiver) = mpsc::channel(0);
     let (inner_sender, rate_limit_receiver) = mpsc::channel(0);
 
-    let rate_limit_future = RateLimitFuture::new(
+    let rate_limit_future = RateLimitFuture::<T,Q>::new(
         inner_sender,
         inner_receiver,
         queue_len,
         min_tokens_per_ms,
+        stats.clone(),
         handle);
 
     // TODO: Add logging for possible errors here:
     handle.spawn(rate_limit_future.map_err(|_e| ()));
 
-    (rate_limit_sender, rate_limit_receiver)
+    (rate_limit_sender, rate_limit_receiver, stats)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::stream;
+    use futures::{future, stream};
     use tokio_core::reactor::Core;
+    use ::test_support::Prioritized;
 
 
-    impl Length for u32 {
-        fn len(&self) -> usize {
-            4
-        }
-    }
-
     #[test]
     fn test_rate_limit_basic() {
         let mut core = Core::new().unwrap();
@@ -292,12 +310,58 @@ mod tests {
         assert_eq!(res_vec, expected_vec);
     }
 
-    impl Length for Vec<u8> {
-        fn len(&self) -> usize {
-            self.len()
+    /// Send num_dgrams datagrams through the pacer, returning the time every datagram was sent.
+    fn pace(pacing: Pacing, num_dgrams: usize) -> Vec<Instant> {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut pacer = Pacer::new(pacing, &handle);
+
+        let mut send_times = Vec::new();
+        core.run(future::poll_fn(|| {
+            while send_times.len() < num_dgrams {
+                if pacer.poll_ready()?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                send_times.push(Instant::now());
+                pacer.sent(num_dgrams);
+            }
+            Ok::<_, io::Error>(Async::Ready(()))
+        })).unwrap();
+        send_times
+    }
+
+    #[test]
+    fn test_pacer_gap() {
+        let send_times = pace(Pacing::Gap(Duration::from_millis(10)), 5);
+        for pair in send_times.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(10));
         }
     }
 
+    #[test]
+    fn test_pacer_window() {
+        let send_times = pace(Pacing::Window(Duration::from_millis(50)), 5);
+        for pair in send_times.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(10));
+        }
+        assert!(send_times[4] - send_times[0] >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_pacer_reset() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let mut pacer = Pacer::new(Pacing::Window(Duration::from_secs(10)), &handle);
+
+        core.run(future::poll_fn(|| {
+            pacer.sent(2);
+            assert!(pacer.poll_ready()?.is_not_ready());
+            // The message is done, the next one is not delayed:
+            pacer.reset();
+            assert!(pacer.poll_ready()?.is_ready());
+            Ok::<_, io::Error>(Async::Ready(()))
+        })).unwrap();
+    }
 
     #[test]
     fn test_rate_limit_variable_len() {
@@ -328,7 +392,105 @@ mod tests {
 
         assert_eq!(res_vec, expected_vec);
     }
-}
 
+    #[test]
+    fn test_rate_limit_full_queue() {
+        let mut core = Core::new().unwrap();
+        let (inner_sender, _receiver) = mpsc::channel(0);
+        let (mut sender, inner_receiver) = mpsc::channel(8);
+        let mut rl_future: RateLimitFuture<_,Prioritized> = 
+            RateLimitFuture::new(inner_sender, inner_receiver, 2, 1,
+                                 RateLimitStats::default(), &core.handle());
+
+        for &(i, priority) in &[(0, 0), (1, 0), (2, 1), (3, 0), (4, 2)] {
+            sender.try_send(Prioritized(i, priority)).unwrap();
+        }
+        let sent = core.run(future::lazy(move || {
+            // 2 has to wait for room in the queue:
+            assert_eq!(rl_future.try_recv(), TryRecvResult::QueueFull);
+            // Send the first queued item every time, letting the next waiting item in:
+            let mut sent = Vec::new();
+            while let Some(item) = rl_future.queue.pending_items.pop_front() {
+                sent.push(item.0);
+                rl_future.try_recv();
+            }
+            Ok::<_, ()>(sent)
+        })).unwrap();
+        // Items overtake only the items queued with them, and none is dropped:
+        assert_eq!(sent, vec![0, 2, 1, 4, 3]);
+    }
+
+    struct Expiring(u32, Option<Instant>);
+
+    impl Length for Expiring {
+        fn len(&self) -> usize {
+            4
+        }
+    }
+
+    impl QueueItem for Expiring {
+        fn opt_deadline(&self) -> Option<Instant> {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_deadline() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (rl_sender, rl_receiver, stats) = rate_limit_channel_stats(5, 1, &handle);
+        // Every even item has already expired:
+        let source_stream = stream::iter_ok((0 .. 100u32).map(|i| match i % 2 {
+            0 => Expiring(i, Some(Instant::now())),
+            _ => Expiring(i, None),
+        }));
+
+        handle.spawn(
+            source_stream.forward(rl_sender)
+            .map_err(|_e: mpsc::SendError<Expiring>| ())
+            .and_then(|_| Ok(()))
+        );
+
+        let res_vec = core.run(rl_receiver.map(|item| item.0).collect()).unwrap();
+        let expected_vec = (0 .. 50).map(|i| 2*i + 1).collect::<Vec<u32>>();
+        assert_eq!(res_vec, expected_vec);
+        assert_eq!(stats.expired_items(), 50);
+    }
+
+    struct Cancellable(u32, bool);
+
+    impl Length for Cancellable {
+        fn len(&self) -> usize {
+            4
+        }
+    }
+
+    impl QueueItem for Cancellable {
+        fn is_cancelled(&self) -> bool {
+            self.1
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_cancel() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
 
+        let (rl_sender, rl_receiver, stats) = rate_limit_channel_stats(5, 1, &handle);
+        // Every item divisible by 3 was cancelled:
+        let source_stream = stream::iter_ok((0 .. 99u32).map(|i| Cancellable(i, i % 3 == 0)));
 
+        handle.spawn(
+            source_stream.forward(rl_sender)
+            .map_err(|_e: mpsc::SendError<Cancellable>| ())
+            .and_then(|_| Ok(()))
+        );
+
+        let res_vec = core.run(rl_receiver.map(|item| item.0).collect()).unwrap();
+        let expected_vec = (0 .. 99).filter(|i| i % 3 != 0).collect::<Vec<u32>>();
+        assert_eq!(res_vec, expected_vec);
+        assert_eq!(stats.cancelled_items(), 33);
+        assert_eq!(stats.expired_items(), 0);
+    }
+}
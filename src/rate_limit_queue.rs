@@ -0,0 +1,293 @@
+//! The queue and token bucket of a rate limiter, without any timer or channel.
+//! The rate limiting futures of every runtime drive it.
+
+use std;
+use std::cmp;
+use std::time::Instant;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+pub(crate) const MAX_TOKENS_PER_MS: usize = 1 << 32;
+
+/// Something that has length.
+/// This could example, describe chunks of data,
+/// where length() is the amount of bytes in the chunk.
+pub trait Length {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl Length for (Vec<u8>, std::net::SocketAddr) {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// An item waiting in the queue of the rate limiter.
+pub trait QueueItem: Length {
+    /// Items still in the queue after their deadline are dropped instead of being sent.
+    fn opt_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Items of higher priority overtake queued items of lower priority.
+    /// Items of the same priority are sent in the order they were received.
+    /// While the queue is full, new items wait outside of it, whatever their priority.
+    fn priority(&self) -> u8 {
+        0
+    }
+
+    /// Cancelled items are dropped instead of being sent.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+impl QueueItem for (Vec<u8>, std::net::SocketAddr) {}
+
+/// An item as it is kept in the queue of the rate limiter,
+/// converted from and back into the item sent through the channel.
+pub(crate) trait Queued<T>: QueueItem {
+    fn from_item(item: T) -> Self;
+    fn into_item(self) -> T;
+}
+
+impl<T: QueueItem> Queued<T> for T {
+    fn from_item(item: T) -> Self {
+        item
+    }
+
+    fn into_item(self) -> T {
+        self
+    }
+}
+
+/// Lets items that only have a length go through the queue:
+/// They never expire, and are sent in the order they were received.
+pub(crate) struct Unprioritized<T>(T);
+
+impl<T: Length> Length for Unprioritized<T> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl<T: Length> QueueItem for Unprioritized<T> {}
+
+impl<T: Length> Queued<T> for Unprioritized<T> {
+    fn from_item(item: T) -> Self {
+        Unprioritized(item)
+    }
+
+    fn into_item(self) -> T {
+        self.0
+    }
+}
+
+/// Counters of a rate limiter. See rate_limit_channel_stats().
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitStats {
+    expired_items: Arc<AtomicUsize>,
+    cancelled_items: Arc<AtomicUsize>,
+}
+
+impl RateLimitStats {
+    /// Amount of items dropped from the queue because their deadline passed.
+    pub fn expired_items(&self) -> usize {
+        self.expired_items.load(Ordering::Relaxed)
+    }
+
+    /// Amount of items dropped from the queue because they were cancelled.
+    pub fn cancelled_items(&self) -> usize {
+        self.cancelled_items.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Queued items, and the tokens available for sending them.
+/// Every millisecond the rate limiter gets tokens_per_ms tokens, and sending an item costs
+/// its length. tokens_per_ms grows while the tokens are not enough for the queued items,
+/// and shrinks back towards min_tokens_per_ms otherwise.
+pub(crate) struct RateLimitQueue<Q> {
+    pub(crate) pending_items: VecDeque<Q>,
+    queue_len: usize,
+    pub(crate) send_tokens_left: usize,
+    // Tokens saved for the first item, which is too long for the tokens of a single tick:
+    pub(crate) remainder_tokens: usize,
+    tokens_per_ms: usize,
+    min_tokens_per_ms: usize,
+    token_shortage: bool,
+    stats: RateLimitStats,
+}
+
+impl<Q: QueueItem> RateLimitQueue<Q> {
+    pub(crate) fn new(queue_len: usize, min_tokens_per_ms: usize,
+                      stats: RateLimitStats) -> Self {
+
+        RateLimitQueue {
+            pending_items: VecDeque::new(),
+            queue_len,
+            send_tokens_left: min_tokens_per_ms,
+            remainder_tokens: 0,
+            tokens_per_ms: min_tokens_per_ms,
+            min_tokens_per_ms,
+            token_shortage: false,
+            stats,
+        }
+    }
+
+    /// Should be called every millisecond: Adjust the rate, and refill the tokens.
+    pub(crate) fn tick(&mut self) {
+        let new_tokens_per_ms = if self.token_shortage {
+            // We are using all the tokens, we need to increase the speed:
+            (self.tokens_per_ms * 2) + 1
+        } else if self.tokens_per_ms > self.min_tokens_per_ms {
+            self.tokens_per_ms - 1
+        } else {
+            self.min_tokens_per_ms
+        };
+
+        self.token_shortage = false;
+        self.tokens_per_ms = cmp::min(new_tokens_per_ms, MAX_TOKENS_PER_MS);
+        self.send_tokens_left = self.tokens_per_ms;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending_items.is_empty()
+    }
+
+    /// Check if another item could be queued.
+    pub(crate) fn has_room(&self) -> bool {
+        self.pending_items.len() < self.queue_len
+    }
+
+    /// Queue an item after all the items of the same or higher priority.
+    pub(crate) fn push_item(&mut self, item: Q) {
+        let priority = item.priority();
+        let index = self.pending_items.iter()
+            .position(|pending_item| pending_item.priority() < priority)
+            .unwrap_or(self.pending_items.len());
+        if index == 0 {
+            // Tokens saved for the item that was first are available for the new first item:
+            self.release_remainder();
+        }
+        self.pending_items.insert(index, item);
+    }
+
+    /// Drop all the queued items that were cancelled or whose deadline has passed.
+    pub(crate) fn drop_stale(&mut self) {
+        let now = Instant::now();
+        let is_expired = |item: &Q| match item.opt_deadline() {
+            Some(deadline) => now >= deadline,
+            None => false,
+        };
+        let is_stale = |item: &Q| item.is_cancelled() || is_expired(item);
+        if !self.pending_items.iter().any(is_stale) {
+            return;
+        }
+        if self.pending_items.front().is_some_and(is_stale) {
+            // Tokens saved for the first item are available for the next one:
+            self.release_remainder();
+        }
+        let mut num_cancelled = 0;
+        let mut num_expired = 0;
+        self.pending_items.retain(|item| {
+            if item.is_cancelled() {
+                num_cancelled += 1;
+                false
+            } else if is_expired(item) {
+                num_expired += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.stats.cancelled_items.fetch_add(num_cancelled, Ordering::Relaxed);
+        self.stats.expired_items.fetch_add(num_expired, Ordering::Relaxed);
+    }
+
+    /// Take the first item out of the queue, if there are enough tokens to send it.
+    /// Otherwise, the tokens left are saved for it.
+    pub(crate) fn pop_item(&mut self) -> Option<Q> {
+        let item_len = self.pending_items.front()?.len();
+        debug_assert!(self.remainder_tokens <= item_len);
+        if item_len > self.send_tokens_left + self.remainder_tokens {
+            self.remainder_tokens += self.send_tokens_left;
+            self.send_tokens_left = 0;
+            self.token_shortage = true;
+            return None;
+        }
+        self.send_tokens_left -= item_len - self.remainder_tokens;
+        self.remainder_tokens = 0;
+        self.pending_items.pop_front()
+    }
+
+    /// Put back an item taken with pop_item() that could not be sent.
+    /// The tokens spent on it are saved for it.
+    pub(crate) fn unpop_item(&mut self, item: Q) {
+        self.remainder_tokens = item.len();
+        self.pending_items.push_front(item);
+    }
+
+    fn release_remainder(&mut self) {
+        self.send_tokens_left += self.remainder_tokens;
+        self.remainder_tokens = 0;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::test_support::Prioritized;
+
+    #[test]
+    fn test_rate_limit_queue_priority() {
+        let mut queue = RateLimitQueue::new(16, 1, RateLimitStats::default());
+
+        queue.push_item(Prioritized(0, 0));
+        // Some tokens were already saved for the first item:
+        queue.remainder_tokens = 1;
+        queue.send_tokens_left = 0;
+        queue.push_item(Prioritized(1, 0));
+        queue.push_item(Prioritized(2, 2));
+        queue.push_item(Prioritized(3, 1));
+        queue.push_item(Prioritized(4, 2));
+        queue.push_item(Prioritized(5, 0));
+
+        let order = queue.pending_items.iter().map(|item| item.0).collect::<Vec<u32>>();
+        assert_eq!(order, vec![2, 4, 3, 0, 1, 5]);
+        assert_eq!(queue.remainder_tokens, 0);
+        assert_eq!(queue.send_tokens_left, 1);
+    }
+
+    #[test]
+    fn test_rate_limit_queue_tokens() {
+        let mut queue = RateLimitQueue::new(16, 3, RateLimitStats::default());
+        queue.push_item(Prioritized(0, 0));
+        queue.push_item(Prioritized(1, 0));
+
+        // 3 tokens are not enough for an item of length 4. They are saved for it:
+        assert!(queue.pop_item().is_none());
+        queue.tick();
+        // There was a shortage, so the rate grows:
+        assert_eq!(queue.send_tokens_left, 7);
+        // Only the tokens missing for the saved ones are spent:
+        assert_eq!(queue.pop_item().unwrap().0, 0);
+        assert_eq!(queue.send_tokens_left, 6);
+
+        // The sender was not ready. The tokens spent are saved for the item:
+        let item = queue.pop_item().unwrap();
+        assert_eq!(queue.send_tokens_left, 2);
+        queue.unpop_item(item);
+        assert_eq!(queue.pop_item().unwrap().0, 1);
+        assert_eq!(queue.send_tokens_left, 2);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use core::ops::Add;
+use core::time::Duration;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use ::state_machine::{FragStateMachine, ExpiredMessage, MESSAGE_ID_TICKS};
+use ::buffer_pool::BufferPool;
+
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReassemblerEvent {
+    /// A full message was reconstructed.
+    Message(Vec<u8>),
+    /// A partially received message was discarded.
+    Expired(ExpiredMessage),
+}
+
+/// Reconstructs messages from received datagrams, without doing any IO.
+/// The caller is responsible for reading datagrams and for telling the Reassembler what time it
+/// is. Partially received messages are discarded after MESSAGE_ID_TICKS (30) ticks.
+///
+/// T is the type of time instants, usually std::time::Instant. Without std, any monotonic clock
+/// could be used.
+pub struct Reassembler<T> {
+    frag_state_machine: FragStateMachine,
+    tick_duration: Duration,
+    opt_last_tick: Option<T>,
+}
+
+impl<T> Reassembler<T>
+where
+    T: Copy + Ord + Add<Duration, Output=T>,
+{
+    /// Create a new Reassembler.
+    /// Incoming messages larger than max_total_message bytes are discarded.
+    pub fn new(max_total_message: usize, tick_duration: Duration) -> Self {
+        Reassembler {
+            frag_state_machine: FragStateMachine::with_max_total_message(max_total_message),
+            tick_duration,
+            opt_last_tick: None,
+        }
+    }
+
+    /// Use the given pool for reassembly buffers.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.frag_state_machine.set_buffer_pool(buffer_pool);
+    }
+
+    /// Process a received datagram.
+    pub fn received(&mut self, dgram: &[u8], now: T) -> Vec<ReassemblerEvent> {
+        let mut events = self.handle_timeout(now);
+        if let Some(msg) = self.frag_state_machine.received_frag_message(dgram) {
+            events.push(ReassemblerEvent::Message(msg));
+        }
+        events
+    }
+
+    /// Let the Reassembler know about the passing time.
+    /// Should be called at next_timeout(), even if no datagrams were received.
+    pub fn handle_timeout(&mut self, now: T) -> Vec<ReassemblerEvent> {
+        let mut events = Vec::new();
+        let mut last_tick = match self.opt_last_tick {
+            Some(last_tick) => last_tick,
+            None => {
+                self.opt_last_tick = Some(now);
+                return events;
+            },
+        };
+
+        // After 2 * MESSAGE_ID_TICKS ticks all the state is gone,
+        // so there is no point in doing more ticks than that:
+        let mut num_ticks = 0;
+        while now >= last_tick + self.tick_duration {
+            if num_ticks >= 2 * MESSAGE_ID_TICKS {
+                last_tick = now;
+                break;
+            }
+            events.extend(self.frag_state_machine.time_tick()
+                          .into_iter()
+                          .map(ReassemblerEvent::Expired));
+            last_tick = last_tick + self.tick_duration;
+            num_ticks += 1;
+        }
+        self.opt_last_tick = Some(last_tick);
+        events
+    }
+
+    /// The next time handle_timeout() should be called.
+    pub fn next_timeout(&self) -> Option<T> {
+        self.opt_last_tick.map(|last_tick| last_tick + self.tick_duration)
+    }
+}
+
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+    use rand;
+    use rand::StdRng;
+
+    use ::fragmenter::{Fragmenter, CompatRng};
+
+    fn fragment(msg: &[u8]) -> Vec<Vec<u8>> {
+        let seed: &[_] = &[1,2,3,4,5];
+        let rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let mut fragmenter = Fragmenter::new(22, CompatRng(rng));
+        fragmenter.fragment(msg).unwrap()
+    }
+
+    #[test]
+    fn test_reassembler_basic() {
+        let orig_message = b"This is some message to be split";
+        let dgrams = fragment(orig_message);
+        let b = dgrams.len().div_ceil(2);
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(1 << 16, Duration::from_secs(1));
+        for dgram in &dgrams[.. b - 1] {
+            assert!(reassembler.received(dgram, now).is_empty());
+        }
+        assert_eq!(reassembler.received(&dgrams[dgrams.len() - 1], now),
+                   vec![ReassemblerEvent::Message(orig_message.to_vec())]);
+    }
+
+    #[test]
+    fn test_reassembler_expired() {
+        let orig_message = b"This is some message to be split";
+        let dgrams = fragment(orig_message);
+        let b = dgrams.len().div_ceil(2);
+
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(1 << 16, Duration::from_secs(1));
+        for dgram in &dgrams[.. b - 1] {
+            assert!(reassembler.received(dgram, start).is_empty());
+        }
+        assert_eq!(reassembler.next_timeout(), Some(start + Duration::from_secs(1)));
+
+        let almost = start + Duration::from_secs(MESSAGE_ID_TICKS as u64 - 1);
+        assert!(reassembler.handle_timeout(almost).is_empty());
+
+        let events = reassembler.handle_timeout(almost + Duration::from_secs(1));
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            ReassemblerEvent::Expired(ref expired) => {
+                assert_eq!(expired.b as usize, b);
+                assert_eq!(expired.num_shares, b - 1);
+            },
+            _ => panic!("Expected an expired message"),
+        };
+
+        // The last datagram arrives too late:
+        let late = almost + Duration::from_secs(2);
+        assert!(reassembler.received(&dgrams[dgrams.len() - 1], late).is_empty());
+    }
+
+    #[test]
+    fn test_reassembler_long_pause() {
+        let mut reassembler = Reassembler::new(1 << 16, Duration::from_millis(1));
+        let start = Instant::now();
+        assert!(reassembler.handle_timeout(start).is_empty());
+
+        // A very long pause doesn't result in millions of ticks:
+        let later = start + Duration::from_secs(3600);
+        assert!(reassembler.handle_timeout(later).is_empty());
+        assert_eq!(reassembler.next_timeout(), Some(later + Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,457 @@
+//! The messages whose datagrams a FragMsgSender is sending, without any sink or timer.
+//! Decides which datagram goes out next: Messages of the highest priority are interleaved
+//! round-robin, expired and cancelled messages are dropped, and destinations the sink is not
+//! ready for wait while sending continues to the others.
+
+use std::{mem, cmp, slice};
+use std::time::Instant;
+use std::collections::VecDeque;
+
+use ::frag_msg_sender::{SendOptions, SenderStats};
+
+// Messages waiting only for stuck destinations, beyond the interleave depth:
+const MAX_BLOCKED_MESSAGES: usize = 64;
+
+
+/// The destinations of a message.
+/// A single destination, the common case, is kept without allocating a vector.
+pub(crate) enum Addresses<A> {
+    One(A),
+    Many(Vec<A>),
+}
+
+impl<A> Addresses<A> {
+    pub(crate) fn as_slice(&self) -> &[A] {
+        match *self {
+            Addresses::One(ref address) => slice::from_ref(address),
+            Addresses::Many(ref addresses) => addresses,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [A] {
+        match *self {
+            Addresses::One(ref mut address) => slice::from_mut(address),
+            Addresses::Many(ref mut addresses) => addresses,
+        }
+    }
+
+    fn map<B, F: FnMut(A) -> B>(self, mut f: F) -> Addresses<B> {
+        match self {
+            Addresses::One(address) => Addresses::One(f(address)),
+            Addresses::Many(addresses) => Addresses::Many(addresses.into_iter().map(f).collect()),
+        }
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<A> {
+        match self {
+            Addresses::One(address) => vec![address],
+            Addresses::Many(addresses) => addresses,
+        }
+    }
+}
+
+impl<A> From<Vec<A>> for Addresses<A> {
+    fn from(mut addresses: Vec<A>) -> Self {
+        if addresses.len() == 1 {
+            Addresses::One(addresses.pop().unwrap())
+        } else {
+            Addresses::Many(addresses)
+        }
+    }
+}
+
+/// One of the addresses of a message, and the next datagram to send it.
+struct Destination<A> {
+    address: A,
+    // Index of the next datagram sent to address:
+    next_dgram: usize,
+    // Cleared when the underlying sink is not ready for a datagram to address,
+    // until the next flush:
+    is_ready: bool,
+    // Set when the underlying sink accepted datagrams to other addresses while not ready for
+    // address, until it accepts a datagram to address:
+    is_stuck: bool,
+}
+
+/// A message whose datagrams are being sent.
+/// All the destinations share the same datagrams. A datagram is copied when it is handed to
+/// the underlying sink, unless no other destination still needs it.
+pub(crate) struct PendingDgrams<A> {
+    dgrams: Vec<Vec<u8>>,
+    destinations: Addresses<Destination<A>>,
+    options: SendOptions,
+    // Index of the destination the next datagram is sent to:
+    next_destination: usize,
+    // Total amount of datagrams sent for the message, to all addresses:
+    num_dgrams: usize,
+}
+
+impl<A> PendingDgrams<A> {
+    fn new(dgrams: Vec<Vec<u8>>, addresses: Addresses<A>, options: SendOptions) -> Self {
+        let num_dgrams = dgrams.len() * addresses.as_slice().len();
+        let destinations = addresses.map(|address| Destination {
+            address,
+            next_dgram: 0,
+            is_ready: true,
+            is_stuck: false,
+        });
+        PendingDgrams {
+            dgrams,
+            destinations,
+            options,
+            next_destination: 0,
+            num_dgrams,
+        }
+    }
+
+    fn has_dgrams_left(&self, destination: &Destination<A>) -> bool {
+        destination.next_dgram < self.dgrams.len()
+    }
+
+    fn can_send(&self, destination: &Destination<A>) -> bool {
+        destination.is_ready && self.has_dgrams_left(destination)
+    }
+
+    /// Amount of datagrams left to send, to all addresses.
+    pub(crate) fn num_left(&self) -> usize {
+        self.destinations.as_slice().iter()
+            .map(|destination| self.dgrams.len() - destination.next_dgram)
+            .sum()
+    }
+
+    /// Check if a datagram could be sent to some destination.
+    fn is_ready(&self) -> bool {
+        self.destinations.as_slice().iter().any(|destination| self.can_send(destination))
+    }
+
+    /// Index of the first destination a datagram could be sent to, starting from start and
+    /// wrapping around.
+    fn find_ready(&self, start: usize) -> Option<usize> {
+        let destinations = self.destinations.as_slice();
+        (0 .. destinations.len())
+            .map(|i| (start + i) % destinations.len())
+            .find(|&index| self.can_send(&destinations[index]))
+    }
+
+    /// Check if a message to addresses should wait for this message: Some datagrams are left
+    /// for a destination that is not stuck, or for one of addresses.
+    fn takes_room_from(&self, addresses: &[A]) -> bool
+    where
+        A: PartialEq,
+    {
+        self.destinations.as_slice().iter()
+            .filter(|destination| self.has_dgrams_left(destination))
+            .any(|destination| !destination.is_stuck || addresses.contains(&destination.address))
+    }
+
+    /// Take the next datagram for the destination at index.
+    /// The datagram is copied, unless this is the last destination it is sent to.
+    fn take_dgram(&mut self, index: usize) -> Vec<u8> {
+        let destinations = self.destinations.as_mut_slice();
+        let position = destinations[index].next_dgram;
+        destinations[index].next_dgram += 1;
+        let is_needed = destinations.iter()
+            .any(|destination| destination.next_dgram <= position);
+        if is_needed {
+            self.dgrams[position].clone()
+        } else {
+            mem::take(&mut self.dgrams[position])
+        }
+    }
+
+    /// Put back a datagram taken with take_dgram() that could not be sent.
+    fn untake_dgram(&mut self, index: usize, dgram: Vec<u8>) {
+        let destination = &mut self.destinations.as_mut_slice()[index];
+        destination.next_dgram -= 1;
+        self.dgrams[destination.next_dgram] = dgram;
+    }
+
+    /// Retry the destinations the underlying sink was not ready for.
+    fn unblock(&mut self) {
+        for destination in self.destinations.as_mut_slice() {
+            destination.is_ready = true;
+        }
+    }
+
+    /// Mark the destinations the underlying sink is not ready for as stuck.
+    fn mark_stuck(&mut self) {
+        for destination in self.destinations.as_mut_slice() {
+            if !destination.is_ready {
+                destination.is_stuck = true;
+            }
+        }
+    }
+}
+
+/// The message of a datagram taken out of a SendQueue, until the datagram is handed to the
+/// underlying sink. See SendQueue::pop_dgram().
+pub(crate) struct Popped<A> {
+    pending_dgrams: PendingDgrams<A>,
+    // Position of the message in the interleaving queue:
+    position: usize,
+    // Index of the destination of the datagram:
+    index: usize,
+}
+
+impl<A> Popped<A> {
+    pub(crate) fn address(&self) -> &A {
+        &self.pending_dgrams.destinations.as_slice()[self.index].address
+    }
+
+    pub(crate) fn options(&self) -> &SendOptions {
+        &self.pending_dgrams.options
+    }
+}
+
+/// Messages whose datagrams are being sent, interleaved.
+/// Datagrams of the messages of the highest priority are sent round-robin, up to
+/// interleave_depth messages at a time. Every message sends a datagram to each of its addresses
+/// before the next message does.
+pub(crate) struct SendQueue<A> {
+    pub(crate) pending: VecDeque<PendingDgrams<A>>,
+    interleave_depth: usize,
+    // Some destination was blocked since datagrams were last sent:
+    was_blocked: bool,
+}
+
+impl<A> SendQueue<A> {
+    pub(crate) fn new() -> Self {
+        SendQueue {
+            pending: VecDeque::new(),
+            interleave_depth: 1,
+            was_blocked: false,
+        }
+    }
+
+    pub(crate) fn set_interleave_depth(&mut self, depth: usize) {
+        self.interleave_depth = cmp::max(depth, 1);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Total amount of datagrams of the queued messages, to all their addresses.
+    /// Round-robin spreads every message over the time it takes to send this many datagrams.
+    pub(crate) fn num_dgrams(&self) -> usize {
+        self.pending.iter()
+            .map(|pending_dgrams| pending_dgrams.num_dgrams)
+            .sum()
+    }
+
+    /// Check if another message of the given priority to the given addresses could be queued.
+    /// Only messages of the same or higher priority take room.
+    /// Messages waiting only for stuck destinations, which the underlying sink is not ready
+    /// for while accepting datagrams to other addresses, take room only from messages to the
+    /// same destinations, up to MAX_BLOCKED_MESSAGES.
+    pub(crate) fn has_room(&self, priority: u8, addresses: &[A]) -> bool 
+    where
+        A: PartialEq,
+    {
+        if self.pending.len() >= self.interleave_depth + MAX_BLOCKED_MESSAGES {
+            return false;
+        }
+        let num_pending = self.pending.iter()
+            .filter(|pending_dgrams| pending_dgrams.options.priority >= priority &&
+                    pending_dgrams.takes_room_from(addresses))
+            .count();
+        num_pending < self.interleave_depth
+    }
+
+    /// Queue the datagrams of a message, to be sent to all the given addresses.
+    pub(crate) fn push(&mut self, dgrams: Vec<Vec<u8>>, addresses: Addresses<A>,
+                       options: SendOptions) {
+        if !dgrams.is_empty() && !addresses.as_slice().is_empty() {
+            self.pending.push_back(PendingDgrams::new(dgrams, addresses, options));
+        }
+    }
+
+    /// Retry the destinations the underlying sink was not ready for.
+    /// Should be called once every time the queue is flushed.
+    pub(crate) fn unblock(&mut self) {
+        self.was_blocked = false;
+        for pending_dgrams in self.pending.iter_mut() {
+            pending_dgrams.unblock();
+        }
+    }
+
+    /// Take the next datagram to send out of the queue, together with its message:
+    /// The next datagram of the first message of the highest priority that has a destination
+    /// ready. Messages whose deadline has passed and cancelled messages are dropped, and counted
+    /// in stats.
+    /// The message should be given back with sent() or unsent().
+    pub(crate) fn pop_dgram(&mut self, now: Instant, stats: &mut SenderStats)
+        -> Option<(Vec<u8>, Popped<A>)> {
+
+        loop {
+            let mut opt_best: Option<(usize, u8)> = None;
+            for (position, pending_dgrams) in self.pending.iter().enumerate() {
+                if !pending_dgrams.is_ready() {
+                    continue;
+                }
+                let priority = pending_dgrams.options.priority;
+                match opt_best {
+                    Some((_, best_priority)) if best_priority >= priority => {},
+                    _ => opt_best = Some((position, priority)),
+                }
+            }
+            let (position, _) = opt_best?;
+            let mut pending_dgrams = self.pending.remove(position)?;
+
+            if pending_dgrams.options.is_cancelled() {
+                stats.cancelled_messages += 1;
+                stats.cancelled_dgrams += pending_dgrams.num_left();
+                continue;
+            }
+            if pending_dgrams.options.is_expired(now) {
+                stats.expired_messages += 1;
+                stats.expired_dgrams += pending_dgrams.num_left();
+                continue;
+            }
+
+            let index = pending_dgrams.find_ready(pending_dgrams.next_destination).unwrap();
+            let dgram = pending_dgrams.take_dgram(index);
+            return Some((dgram, Popped { pending_dgrams, position, index }));
+        }
+    }
+
+    /// The datagram taken with pop_dgram() was handed to the underlying sink.
+    /// The next datagram of the message is sent after a datagram of every other message in the
+    /// queue, once each of its addresses got a datagram.
+    pub(crate) fn sent(&mut self, popped: Popped<A>) {
+        let Popped { mut pending_dgrams, position, index } = popped;
+        pending_dgrams.destinations.as_mut_slice()[index].is_stuck = false;
+        if self.was_blocked {
+            // The sink is not ready only for the blocked destinations,
+            // which should not hold back messages to other addresses:
+            self.was_blocked = false;
+            pending_dgrams.mark_stuck();
+            for other_dgrams in self.pending.iter_mut() {
+                other_dgrams.mark_stuck();
+            }
+        }
+
+        match pending_dgrams.find_ready(index + 1) {
+            Some(next_index) if next_index > index => {
+                pending_dgrams.next_destination = next_index;
+                self.pending.insert(position, pending_dgrams);
+            },
+            _ => {
+                pending_dgrams.next_destination = 0;
+                if pending_dgrams.num_left() > 0 {
+                    self.pending.push_back(pending_dgrams);
+                }
+            },
+        }
+    }
+
+    /// The underlying sink was not ready for the datagram taken with pop_dgram().
+    /// Its destination waits until the queue is unblocked, while sending continues to the
+    /// other destinations.
+    pub(crate) fn unsent(&mut self, popped: Popped<A>, dgram: Vec<u8>) {
+        let Popped { mut pending_dgrams, position, index } = popped;
+        pending_dgrams.untake_dgram(index, dgram);
+        pending_dgrams.destinations.as_mut_slice()[index].is_ready = false;
+        pending_dgrams.next_destination = index;
+        self.was_blocked = true;
+        self.pending.insert(position, pending_dgrams);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Datagrams of a message, starting with the message number and then their index.
+    fn new_dgrams(message: u8, num_dgrams: u8) -> Vec<Vec<u8>> {
+        (0 .. num_dgrams).map(|i| vec![message, i]).collect()
+    }
+
+    /// Send all the queued datagrams, returning them with their addresses.
+    fn send_all(send_queue: &mut SendQueue<u32>, now: Instant, stats: &mut SenderStats)
+        -> Vec<(Vec<u8>, u32)> {
+
+        let mut sent = Vec::new();
+        send_queue.unblock();
+        while let Some((dgram, popped)) = send_queue.pop_dgram(now, stats) {
+            sent.push((dgram, *popped.address()));
+            send_queue.sent(popped);
+        }
+        sent
+    }
+
+    #[test]
+    fn test_send_queue_interleave() {
+        let mut send_queue = SendQueue::new();
+        send_queue.set_interleave_depth(2);
+        let mut stats = SenderStats::default();
+
+        send_queue.push(new_dgrams(0, 2), Addresses::Many(vec![1, 2]), SendOptions::default());
+        assert!(send_queue.has_room(0, &[3]));
+        send_queue.push(new_dgrams(1, 2), Addresses::One(3), SendOptions::default());
+        assert!(!send_queue.has_room(0, &[3]));
+        // Messages of a higher priority take no room from messages of a lower priority:
+        assert!(send_queue.has_room(1, &[3]));
+
+        // Every message sends a datagram to each of its addresses before the next message does:
+        let sent = send_all(&mut send_queue, Instant::now(), &mut stats);
+        assert_eq!(sent, vec![(vec![0, 0], 1), (vec![0, 0], 2), (vec![1, 0], 3),
+                              (vec![0, 1], 1), (vec![0, 1], 2), (vec![1, 1], 3)]);
+        assert!(send_queue.is_empty());
+        assert_eq!(stats, SenderStats::default());
+    }
+
+    #[test]
+    fn test_send_queue_deadline() {
+        let mut send_queue = SendQueue::new();
+        send_queue.set_interleave_depth(2);
+        let mut stats = SenderStats::default();
+        let start = Instant::now();
+
+        let stale_options = SendOptions {
+            opt_deadline: Some(start + Duration::from_millis(10)),
+            ..SendOptions::default()
+        };
+        send_queue.push(new_dgrams(0, 3), Addresses::One(1), stale_options);
+        send_queue.unblock();
+        let (dgram, popped) = send_queue.pop_dgram(start, &mut stats).unwrap();
+        assert_eq!(dgram, vec![0, 0]);
+        send_queue.sent(popped);
+
+        // The deadline passes while the datagrams left wait behind another message:
+        send_queue.push(new_dgrams(1, 2), Addresses::One(2), SendOptions::default());
+        let sent = send_all(&mut send_queue, start + Duration::from_millis(10), &mut stats);
+        assert_eq!(sent, vec![(vec![1, 0], 2), (vec![1, 1], 2)]);
+        assert_eq!(stats, SenderStats {
+            expired_messages: 1,
+            expired_dgrams: 2,
+            ..SenderStats::default()
+        });
+    }
+
+    #[test]
+    fn test_pending_dgrams_shared() {
+        let dgrams = vec![vec![1, 2], vec![3, 4]];
+        let mut pending_dgrams = PendingDgrams::new(dgrams.clone(), Addresses::Many(vec![1u32, 2]),
+                                                    SendOptions::default());
+        assert_eq!(pending_dgrams.num_left(), 4);
+
+        // The first destination gets copies, which the second one still needs:
+        assert_eq!(pending_dgrams.take_dgram(0), dgrams[0]);
+        assert_eq!(pending_dgrams.take_dgram(0), dgrams[1]);
+        assert_eq!(pending_dgrams.dgrams, dgrams);
+
+        // A datagram the sink was not ready for is put back:
+        let dgram = pending_dgrams.take_dgram(1);
+        pending_dgrams.untake_dgram(1, dgram);
+        assert_eq!(pending_dgrams.num_left(), 2);
+
+        // The last destination takes the datagrams themselves:
+        assert_eq!(pending_dgrams.take_dgram(1), dgrams[0]);
+        assert_eq!(pending_dgrams.take_dgram(1), dgrams[1]);
+        assert!(pending_dgrams.dgrams.iter().all(Vec::is_empty));
+        assert_eq!(pending_dgrams.num_left(), 0);
+    }
+}
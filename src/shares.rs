@@ -1,5 +1,10 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
 use reed_solomon_erasure;
-use reed_solomon_erasure::{ReedSolomon, option_shards_into_shards};
+use reed_solomon_erasure::galois_8::ReedSolomon;
 
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq)]
@@ -46,16 +51,14 @@ pub fn split_data(data: &[u8], b: u8) -> Result<Vec<DataShare>,SplitDataError> {
         Err(e) => return Err(SplitDataError::ReedSolomonInitFailed(e)),
     };
 
-    let block_size = (data.len() + (num_blocks - 1)) / num_blocks;
+    let block_size = data.len().div_ceil(num_blocks);
 
     // Add zero padding in case block_size is not a divisor of data.len():
     let padding_len = num_blocks * block_size - data.len();
     debug_assert!(padding_len < block_size);
 
     let mut cdata = data.to_vec();
-    for _ in 0 .. padding_len {
-        cdata.push(0);
-    }
+    cdata.resize(data.len() + padding_len, 0);
 
     let mut shards: Vec<Box<[u8]>> = Vec::new();
 
@@ -70,7 +73,7 @@ pub fn split_data(data: &[u8], b: u8) -> Result<Vec<DataShare>,SplitDataError> {
         shards.push(vec![0u8; block_size].into_boxed_slice());
     }
 
-    match reed_solomon.encode_shards(&mut shards) {
+    match reed_solomon.encode(&mut shards) {
         Ok(()) => {},
         Err(e) => return Err(SplitDataError::ReedSolomonEncodeFailed(e)),
     };
@@ -86,70 +89,135 @@ pub fn split_data(data: &[u8], b: u8) -> Result<Vec<DataShare>,SplitDataError> {
         }).collect::<Vec<DataShare>>())
 }
 
+/// Calculate parity shares in place.
+/// shards should contain slices of equal length. The first b slices contain the data.
+/// The data is left untouched, and the parity is written into the rest of the slices.
+/// The total amount of slices must be smaller or equal to 256.
+pub fn encode_in_place(shards: &mut [&mut [u8]], b: u8) -> Result<(),SplitDataError> {
+    let num_blocks = b as usize;
+
+    if num_blocks == 0 {
+        return Err(SplitDataError::NumBlocksIsZero);
+    }
+
+    if shards.len() > 256 || shards.len() < num_blocks {
+        return Err(SplitDataError::NumBlocksTooLarge);
+    }
+
+    // No parity shares are required. Note that we will get an error if we try to use the 
+    // reed solomon encoder with amount of parity shards = 0
+    if shards.len() == num_blocks {
+        return Ok(());
+    }
+
+    let reed_solomon = match ReedSolomon::new(num_blocks, shards.len() - num_blocks) {
+        Ok(reed_solomon) => reed_solomon,
+        Err(e) => return Err(SplitDataError::ReedSolomonInitFailed(e)),
+    };
+
+    match reed_solomon.encode(shards) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(SplitDataError::ReedSolomonEncodeFailed(e)),
+    }
+}
+
 #[derive(Debug)]
 pub enum UniteDataError {
     NumBlocksIsZero,
     NumBlocksTooLarge,
     ReedSolomonInitFailed(reed_solomon_erasure::Error),
     ReedSolomonDecodeFailed(reed_solomon_erasure::Error),
+    InvalidShare,
 }
 
-/// Reconstruct original data using given b data shares
-/// Reconstructed data might contain trailing zero padding bytes.
-pub fn unite_data(data_shares: &[DataShare]) -> Result<Vec<u8>, UniteDataError> {
+/// Reconstruct missing data shares in place.
+/// shards should contain slices of equal length, where the first b slices are data shares.
+/// present[i] is true if shards[i] contains a received share. At least b shares must be present.
+/// After a successful reconstruction, the first b slices contain the original data.
+pub fn reconstruct_in_place(shards: &mut [&mut [u8]], present: &[bool], b: u8) 
+    -> Result<(), UniteDataError> {
 
-    let num_blocks = data_shares.len();
+    let num_blocks = b as usize;
 
     if num_blocks == 0 {
         return Err(UniteDataError::NumBlocksIsZero);
     }
 
     // Limit due to the amount of elements in the field.
-    if (2 * num_blocks - 1) > 256 {
+    if shards.len() > 256 || shards.len() < num_blocks || shards.len() != present.len() {
         return Err(UniteDataError::NumBlocksTooLarge);
     }
 
-    // Special case of just one block. We don't need to use reed-solomon decoder.
-    if num_blocks == 1 {
-        return Ok(data_shares[0].data.clone());
+    // Nothing to do if all the data shares are present.
+    // We don't need to use reed-solomon decoder.
+    if present[0 .. num_blocks].iter().all(|&is_present| is_present) {
+        return Ok(());
     }
 
-    let reed_solomon = match ReedSolomon::new(num_blocks, num_blocks - 1) {
+    let reed_solomon = match ReedSolomon::new(num_blocks, shards.len() - num_blocks) {
         Ok(reed_solomon) => reed_solomon,
         Err(e) => return Err(UniteDataError::ReedSolomonInitFailed(e)),
     };
 
-    // Convert data_shares into shards format:
-    let mut option_shards: Vec<Option<Box<[u8]>>> = vec![None; 2*num_blocks - 1];
-    for data_share in data_shares {
-        let cloned_share_data = data_share.data.clone();
-        option_shards[data_share.input as usize] = Some(cloned_share_data.into_boxed_slice());
+    // Only the data shares are needed, missing parity shares are left as is:
+    let mut slices = shards.iter_mut()
+        .zip(present.iter())
+        .map(|(shard, &is_present)| (&mut **shard, is_present))
+        .collect::<Vec<_>>();
+
+    match reed_solomon.reconstruct_data(&mut slices) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(UniteDataError::ReedSolomonDecodeFailed(e)),
     }
+}
 
-    match reed_solomon.reconstruct_shards(&mut option_shards) {
-        Ok(()) => {},
-        Err(e) => return Err(UniteDataError::ReedSolomonDecodeFailed(e)),
-    };
+/// Reconstruct original data using given b data shares
+/// Reconstructed data might contain trailing zero padding bytes.
+pub fn unite_data(data_shares: &[DataShare]) -> Result<Vec<u8>, UniteDataError> {
 
+    let num_blocks = data_shares.len();
 
-    let shards = option_shards_into_shards(option_shards);
+    if num_blocks == 0 {
+        return Err(UniteDataError::NumBlocksIsZero);
+    }
 
-    // Reconstruct original data (Possibly with trailing zero padding):
-    let mut res_data = Vec::new();
-    for i in 0 .. num_blocks {
-        res_data.extend_from_slice(&shards[i]);
+    // Limit due to the amount of elements in the field.
+    if (2 * num_blocks - 1) > 256 {
+        return Err(UniteDataError::NumBlocksTooLarge);
     }
 
-    Ok(res_data)
+    // Copy all data shares into their place inside one contiguous buffer:
+    let num_shards = 2*num_blocks - 1;
+    let block_size = data_shares[0].data.len();
+    if block_size == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut buffer = vec![0u8; num_shards * block_size];
+    let mut present = vec![false; num_shards];
+    for data_share in data_shares {
+        let input = data_share.input as usize;
+        if input >= num_shards || data_share.data.len() != block_size {
+            return Err(UniteDataError::InvalidShare);
+        }
+        buffer[input * block_size .. (input + 1) * block_size]
+            .copy_from_slice(&data_share.data);
+        present[input] = true;
+    }
+
+    {
+        let mut shards = buffer.chunks_mut(block_size).collect::<Vec<&mut [u8]>>();
+        reconstruct_in_place(&mut shards, &present, num_blocks as u8)?;
+    }
 
+    // Original data (Possibly with trailing zero padding):
+    buffer.truncate(num_blocks * block_size);
+    Ok(buffer)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand;
-    use rand::{StdRng, Rng};
-    use test::Bencher;
 
     /*
     #[test]
@@ -162,6 +230,7 @@ mod tests {
     */
 
     #[test]
+    #[allow(clippy::manual_div_ceil)]
     fn split_unite_data() {
         let my_data = &[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20];
 
@@ -179,17 +248,25 @@ mod tests {
 
     }
 
-    #[bench]
-    fn bench_unite_data(bencher: &mut Bencher) {
-        let seed: &[_] = &[1,2,3,4,5];
-        let mut rng: StdRng = rand::SeedableRng::from_seed(seed);
-        let mut my_data = vec![0; 2500];
-        rng.fill_bytes(&mut my_data);
+    #[test]
+    fn encode_in_place_matches_split_data() {
+        let my_data = &[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20];
 
-        let b: usize = 5;
-        let data_shares = split_data(&my_data, b as u8).unwrap();
+        for b in 1 .. 5_usize {
+            let data_shares = split_data(my_data, b as u8).unwrap();
+            let block_size = data_shares[0].data.len();
 
-        bencher.iter(|| unite_data(&data_shares[0 .. b]).unwrap());
+            let mut buffer = vec![0u8; (2*b - 1) * block_size];
+            buffer[.. my_data.len()].copy_from_slice(my_data);
+            {
+                let mut shards = buffer.chunks_mut(block_size).collect::<Vec<&mut [u8]>>();
+                encode_in_place(&mut shards, b as u8).unwrap();
+            }
+
+            for (i, chunk) in buffer.chunks(block_size).enumerate() {
+                assert_eq!(chunk, &data_shares[i].data[..]);
+            }
+        }
     }
 
 
@@ -0,0 +1,444 @@
+//! A simulated network, for testing the protocol under datagram loss, duplication,
+//! reordering, corruption and delay. All the impairments are driven by a seeded random
+//! generator, so that runs are reproducible.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Sink, Stream, Poll, StartSend, AsyncSink, Async, Future};
+use futures::task::{self, Task};
+use rand::{Rng, SeedableRng, StdRng};
+use tokio_core::reactor::{Handle, Timeout};
+
+
+/// How datagrams are lost.
+#[derive(Debug, Clone)]
+pub enum LossModel {
+    /// No datagrams are lost.
+    NoLoss,
+    /// Every datagram is lost independently with probability p.
+    Bernoulli { p: f64 },
+    /// Burst loss. The channel moves between a good and a bad state,
+    /// and loses datagrams with a different probability in every state.
+    GilbertElliott {
+        /// Probability of moving from the good state to the bad state, for every datagram.
+        p_good_to_bad: f64,
+        /// Probability of moving from the bad state to the good state, for every datagram.
+        p_bad_to_good: f64,
+        loss_good: f64,
+        loss_bad: f64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub loss: LossModel,
+    /// Probability of a datagram being delivered twice.
+    pub duplicate: f64,
+    /// A delivered datagram is picked at random out of the first reorder_window datagrams
+    /// ready to be delivered. 0 or 1 means no reordering.
+    /// Reordering doesn't affect which datagrams are lost, duplicated or corrupted.
+    pub reorder_window: usize,
+    /// Probability of flipping a single random bit of a datagram.
+    pub corrupt: f64,
+    /// Time it takes every datagram to arrive.
+    pub latency: Duration,
+    /// A random extra delay, between 0 and jitter, added to the latency of every datagram.
+    pub jitter: Duration,
+}
+
+impl Default for SimConfig {
+    /// A perfect network.
+    fn default() -> Self {
+        SimConfig {
+            loss: LossModel::NoLoss,
+            duplicate: 0.0,
+            reorder_window: 0,
+            corrupt: 0.0,
+            latency: Duration::new(0,0),
+            jitter: Duration::new(0,0),
+        }
+    }
+}
+
+/// Counters of what happened to the datagrams sent through the simulated network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub corrupted: usize,
+    pub delivered: usize,
+}
+
+/// The state of a loss model between datagrams.
+pub(crate) struct LossProcess {
+    model: LossModel,
+    in_bad_state: bool,
+}
+
+impl LossProcess {
+    pub fn new(model: LossModel) -> Self {
+        LossProcess {
+            model,
+            in_bad_state: false,
+        }
+    }
+
+    /// Decide whether the next datagram is lost.
+    pub fn is_lost<R: Rng>(&mut self, rng: &mut R) -> bool {
+        match self.model {
+            LossModel::NoLoss => false,
+            LossModel::Bernoulli { p } => rng.gen::<f64>() < p,
+            LossModel::GilbertElliott { p_good_to_bad, p_bad_to_good, loss_good, loss_bad } => {
+                let p_switch = if self.in_bad_state { p_bad_to_good } else { p_good_to_bad };
+                if rng.gen::<f64>() < p_switch {
+                    self.in_bad_state = !self.in_bad_state;
+                }
+                let loss = if self.in_bad_state { loss_bad } else { loss_good };
+                rng.gen::<f64>() < loss
+            },
+        }
+    }
+}
+
+struct SimInner<T> {
+    config: SimConfig,
+    rng: StdRng,
+    // Used only for picking reordered datagrams on delivery. Which datagrams are ready depends
+    // on timing, so this keeps the fate of every sent datagram independent of it:
+    reorder_rng: StdRng,
+    loss_process: LossProcess,
+    // Datagrams on the way, sorted by delivery time:
+    in_flight: VecDeque<(Instant, Vec<u8>, T)>,
+    stats: SimStats,
+    sink_closed: bool,
+    opt_stream_task: Option<Task>,
+}
+
+impl<T: Clone> SimInner<T> {
+    fn delivery_time(&mut self, now: Instant) -> Instant {
+        let jitter_nanos = duration_nanos(self.config.jitter);
+        let extra = if jitter_nanos == 0 {
+            0
+        } else {
+            self.rng.gen_range(0, jitter_nanos + 1)
+        };
+        now + self.config.latency + nanos_duration(extra)
+    }
+
+    fn push_in_flight(&mut self, deliver_at: Instant, dgram: Vec<u8>, address: T) {
+        // Keep in_flight sorted by delivery time. Datagrams with the same delivery time keep
+        // the order they were sent in:
+        let index = self.in_flight.iter()
+            .position(|&(other_deliver_at, _, _)| other_deliver_at > deliver_at)
+            .unwrap_or(self.in_flight.len());
+        self.in_flight.insert(index, (deliver_at, dgram, address));
+    }
+
+    fn send(&mut self, mut dgram: Vec<u8>, address: T) {
+        self.stats.sent += 1;
+        if self.loss_process.is_lost(&mut self.rng) {
+            self.stats.lost += 1;
+            return;
+        }
+
+        if !dgram.is_empty() && self.rng.gen::<f64>() < self.config.corrupt {
+            let bit = self.rng.gen_range(0, dgram.len() * 8);
+            dgram[bit / 8] ^= 1 << (bit % 8);
+            self.stats.corrupted += 1;
+        }
+
+        let now = Instant::now();
+        if self.rng.gen::<f64>() < self.config.duplicate {
+            self.stats.duplicated += 1;
+            let deliver_at = self.delivery_time(now);
+            self.push_in_flight(deliver_at, dgram.clone(), address.clone());
+        }
+        let deliver_at = self.delivery_time(now);
+        self.push_in_flight(deliver_at, dgram, address);
+
+        if let Some(task) = self.opt_stream_task.take() {
+            task.notify();
+        }
+    }
+
+    /// Take a datagram that is ready to be delivered, if any.
+    fn recv(&mut self, now: Instant) -> Option<(Vec<u8>, T)> {
+        let num_ready = self.in_flight.iter()
+            .take_while(|&&(deliver_at, _, _)| deliver_at <= now)
+            .count();
+        if num_ready == 0 {
+            return None;
+        }
+        let window = cmp::min(cmp::max(self.config.reorder_window, 1), num_ready);
+        let index = self.reorder_rng.gen_range(0, window);
+        let (_, dgram, address) = self.in_flight.remove(index).unwrap();
+        self.stats.delivered += 1;
+        Some((dgram, address))
+    }
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+}
+
+fn nanos_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+/// Sending side of the simulated network.
+pub struct SimSink<T> {
+    inner: Arc<Mutex<SimInner<T>>>,
+}
+
+/// Receiving side of the simulated network.
+/// Ends after the SimSink was dropped and all the datagrams in flight were delivered.
+pub struct SimStream<T> {
+    inner: Arc<Mutex<SimInner<T>>>,
+    handle: Handle,
+    opt_timeout: Option<Timeout>,
+}
+
+/// Create a simulated network, with impairments driven by a random generator seeded with seed.
+/// Every item sent into the returned sink is a datagram together with an address, which is
+/// delivered as is.
+pub fn sim_channel<T>(config: SimConfig, seed: &[usize], handle: &Handle)
+    -> (SimSink<T>, SimStream<T>) {
+
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let reorder_seed = (0 .. 4).map(|_| rng.gen()).collect::<Vec<usize>>();
+    let inner = Arc::new(Mutex::new(SimInner {
+        loss_process: LossProcess::new(config.loss.clone()),
+        config,
+        rng,
+        reorder_rng: SeedableRng::from_seed(&reorder_seed[..]),
+        in_flight: VecDeque::new(),
+        stats: SimStats::default(),
+        sink_closed: false,
+        opt_stream_task: None,
+    }));
+
+    (SimSink { inner: inner.clone() },
+     SimStream { inner, handle: handle.clone(), opt_timeout: None })
+}
+
+impl<T> SimSink<T> {
+    pub fn stats(&self) -> SimStats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+}
+
+impl<T> SimStream<T> {
+    pub fn stats(&self) -> SimStats {
+        self.inner.lock().unwrap().stats.clone()
+    }
+}
+
+impl<T> Drop for SimSink<T> {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sink_closed = true;
+        if let Some(task) = inner.opt_stream_task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl<T: Clone> Sink for SimSink<T> {
+    type SinkItem = (Vec<u8>, T);
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+
+        let (dgram, address) = item;
+        self.inner.lock().unwrap().send(dgram, address);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T: Clone> Stream for SimStream<T> {
+    type Item = (Vec<u8>, T);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let opt_next_delivery = {
+                let mut inner = self.inner.lock().unwrap();
+                if let Some(item) = inner.recv(Instant::now()) {
+                    return Ok(Async::Ready(Some(item)));
+                }
+                if inner.in_flight.is_empty() && inner.sink_closed {
+                    return Ok(Async::Ready(None));
+                }
+                inner.opt_stream_task = Some(task::current());
+                inner.in_flight.front().map(|&(deliver_at, _, _)| deliver_at)
+            };
+
+            let deliver_at = match opt_next_delivery {
+                Some(deliver_at) => deliver_at,
+                // Wait for the sink to send something:
+                None => return Ok(Async::NotReady),
+            };
+
+            // Wait until the next datagram is due:
+            let mut timeout = match self.opt_timeout.take() {
+                Some(mut timeout) => {
+                    timeout.reset(deliver_at);
+                    timeout
+                },
+                None => Timeout::new_at(deliver_at, &self.handle).map_err(|_| ())?,
+            };
+            match timeout.poll().map_err(|_| ())? {
+                Async::Ready(()) => {},
+                Async::NotReady => {
+                    self.opt_timeout = Some(timeout);
+                    return Ok(Async::NotReady);
+                },
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    fn run_sim(config: SimConfig, num_dgrams: usize) -> (Vec<(Vec<u8>, u32)>, SimStats) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let seed: &[_] = &[1,2,3,4,5];
+        let (sink, stream) = sim_channel::<u32>(config, seed, &handle);
+
+        let dgrams = (0 .. num_dgrams)
+            .map(|i| (vec![i as u8; 16], i as u32))
+            .collect::<Vec<_>>();
+        let sink = core.run(sink.send_all(::futures::stream::iter_ok(dgrams))).unwrap().0;
+        let stats = sink.stats();
+        drop(sink);
+        let received = core.run(stream.collect()).unwrap();
+        assert_eq!(stats.sent, num_dgrams);
+        (received, stats)
+    }
+
+    #[test]
+    fn test_sim_perfect() {
+        let (received, stats) = run_sim(SimConfig::default(), 100);
+        assert_eq!(received, (0 .. 100).map(|i| (vec![i as u8; 16], i as u32))
+                   .collect::<Vec<_>>());
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn test_sim_bernoulli_loss() {
+        let config = SimConfig {
+            loss: LossModel::Bernoulli { p: 0.3 },
+            ..SimConfig::default()
+        };
+        let (received, stats) = run_sim(config, 1000);
+        assert_eq!(received.len() + stats.lost, 1000);
+        assert!(stats.lost > 200 && stats.lost < 400);
+        // The remaining datagrams arrive in order:
+        assert!(received.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn test_sim_gilbert_elliott_loss() {
+        let config = SimConfig {
+            loss: LossModel::GilbertElliott {
+                p_good_to_bad: 0.05,
+                p_bad_to_good: 0.2,
+                loss_good: 0.0,
+                loss_bad: 1.0,
+            },
+            ..SimConfig::default()
+        };
+        let (received, stats) = run_sim(config, 1000);
+        assert_eq!(received.len() + stats.lost, 1000);
+        assert!(stats.lost > 0);
+
+        // Losses come in bursts. The expected length of a burst is 1 / p_bad_to_good = 5:
+        let mut num_bursts = 0;
+        let mut prev = None;
+        for &(_, i) in &received {
+            if let Some(prev) = prev {
+                if i != prev + 1 {
+                    num_bursts += 1;
+                }
+            }
+            prev = Some(i);
+        }
+        assert!(stats.lost > 2 * num_bursts);
+    }
+
+    #[test]
+    fn test_sim_duplicate_corrupt_reorder() {
+        let config = SimConfig {
+            duplicate: 0.2,
+            corrupt: 0.2,
+            reorder_window: 8,
+            ..SimConfig::default()
+        };
+        let (received, stats) = run_sim(config, 500);
+        assert_eq!(received.len(), 500 + stats.duplicated);
+        assert!(stats.duplicated > 0);
+        assert!(stats.corrupted > 0);
+        // Duplicates of corrupted datagrams are also corrupted:
+        let num_corrupted = received.iter()
+            .filter(|&&(ref dgram, i)| dgram != &vec![i as u8; 16])
+            .count();
+        assert!(num_corrupted >= stats.corrupted);
+        assert!(received.windows(2).any(|w| w[0].1 > w[1].1));
+    }
+
+    #[test]
+    fn test_sim_reproducible() {
+        let config = SimConfig {
+            loss: LossModel::Bernoulli { p: 0.2 },
+            duplicate: 0.1,
+            corrupt: 0.1,
+            reorder_window: 4,
+            ..SimConfig::default()
+        };
+        let (mut received, stats) = run_sim(config.clone(), 200);
+
+        // Receive while sending, so that datagrams are picked for delivery at other times:
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let seed: &[_] = &[1,2,3,4,5];
+        let (sink, stream) = sim_channel::<u32>(config, seed, &handle);
+        let dgrams = (0 .. 200)
+            .map(|i| (vec![i as u8; 16], i as u32))
+            .collect::<Vec<_>>();
+        handle.spawn(sink.send_all(::futures::stream::iter_ok(dgrams)).then(|_| Ok(())));
+        let mut other_received = core.run(stream.collect()).unwrap();
+
+        // The same datagrams are lost, duplicated and corrupted:
+        assert!(stats.lost > 0 && stats.duplicated > 0 && stats.corrupted > 0);
+        received.sort();
+        other_received.sort();
+        assert_eq!(received, other_received);
+    }
+
+    #[test]
+    fn test_sim_latency() {
+        let config = SimConfig {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+            ..SimConfig::default()
+        };
+        let start = Instant::now();
+        let (received, _) = run_sim(config, 10);
+        assert_eq!(received.len(), 10);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
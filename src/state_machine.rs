@@ -1,21 +1,121 @@
-use std::collections::HashMap;
-
-use ::shares::{DataShare};
-use ::messages::{MESSAGE_ID_LEN, ECC_LEN,
-    unite_message, verify_frag_message};
-
-const MESSAGE_ID_TICKS: usize = 30;
+use core::mem;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::collections::HashMap as Map;
+// Without std there is no HashMap. The keys here are random, so a BTreeMap does just as well:
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as Map;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use ::messages::{MESSAGE_ID_LEN, ECC_LEN, NONCE_LEN, MAX_B,
+    unite_message_in_place, verify_frag_message};
+use ::blocks::{PARENT_ID_LEN, BLOCK_HEADER_LEN, parse_block};
+use ::buffer_pool::BufferPool;
+
+pub const MESSAGE_ID_TICKS: usize = 30;
+// Maximum amount of shares of a single Fragmentos message:
+const MAX_SHARES: usize = 2 * MAX_B - 1;
+/// Messages larger than this amount of bytes are discarded, unless another limit is given.
+pub const DEFAULT_MAX_TOTAL_MESSAGE: usize = 1 << 20;
 
 struct CurMessage {
     ticks_to_live: usize,
     b: u8,
     share_length: usize,
-    data_shares: HashMap<u8, Vec<u8>>, // input -> data
+    // Slots for all the 2b - 1 shares, share_length bytes each:
+    buffer: Vec<u8>,
+    // share_index -> was share received. A fixed array, so that no allocation is needed:
+    present: [bool; MAX_SHARES],
+    num_shares: usize,
+}
+
+struct CurParent {
+    ticks_to_live: usize,
+    block_count: u32,
+    total_len: usize,
+    blocks: Map<u32, UnitedBlock>, // block_index -> block (ranging over the block data only)
 }
 
 pub struct FragStateMachine {
-    used_message_ids: HashMap<[u8; MESSAGE_ID_LEN], usize>,
-    cur_messages: HashMap<[u8; MESSAGE_ID_LEN], CurMessage>,
+    max_total_message: usize,
+    used_message_ids: Map<[u8; MESSAGE_ID_LEN], usize>,
+    cur_messages: Map<[u8; MESSAGE_ID_LEN], CurMessage>,
+    used_parent_ids: Map<[u8; PARENT_ID_LEN], usize>,
+    cur_parents: Map<[u8; PARENT_ID_LEN], CurParent>,
+    opt_buffer_pool: Option<BufferPool>,
+}
+
+/// A partially received Fragmentos message that was discarded, because not enough of its shares
+/// arrived in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiredMessage {
+    pub message_id: [u8; MESSAGE_ID_LEN],
+    /// Amount of shares needed to reconstruct the message.
+    pub b: u8,
+    /// Amount of shares that were received.
+    pub num_shares: usize,
+}
+
+/// Enough shares of a message were received. 
+/// The reconstruction of the message could be done separately from the FragStateMachine, 
+/// possibly on another thread.
+pub struct UniteJob {
+    message_id: [u8; MESSAGE_ID_LEN],
+    b: u8,
+    share_length: usize,
+    buffer: Vec<u8>,
+    present: [bool; MAX_SHARES],
+}
+
+/// A reconstructed block, left in place inside the buffer it was reconstructed in.
+pub struct UnitedBlock {
+    buffer: Vec<u8>,
+    range: Range<usize>,
+    // False if the block is a whole message, without a block header:
+    is_block: bool,
+}
+
+impl UnitedBlock {
+    fn data(&self) -> &[u8] {
+        &self.buffer[self.range.clone()]
+    }
+}
+
+impl UniteJob {
+    /// The messageId of the Fragmentos message being reconstructed.
+    #[cfg(feature = "std")]
+    pub fn message_id(&self) -> &[u8; MESSAGE_ID_LEN] {
+        &self.message_id
+    }
+
+    /// Size in bytes of the reconstructed data.
+    #[cfg(feature = "std")]
+    pub fn len(&self) -> usize {
+        self.b as usize * self.share_length
+    }
+
+    /// Reconstruct the block.
+    /// On failure, the no longer needed buffer is returned.
+    pub fn unite(mut self) -> Result<UnitedBlock, Vec<u8>> {
+        let num_shares_total = 2 * (self.b as usize) - 1;
+        match unite_message_in_place(&self.message_id, self.b, self.share_length,
+                                     &mut self.buffer, &self.present[.. num_shares_total]) {
+            Ok((range, is_block)) => Ok(UnitedBlock {
+                buffer: self.buffer,
+                range,
+                is_block,
+            }),
+            Err(_) => Err(self.buffer),
+        }
+    }
+}
+
+/// Return a buffer that is no longer used into the buffer pool, if there is one.
+fn recycle(opt_buffer_pool: &Option<BufferPool>, buffer: Vec<u8>) {
+    if let Some(ref buffer_pool) = *opt_buffer_pool {
+        buffer_pool.give(buffer);
+    }
 }
 
 
@@ -28,29 +128,72 @@ pub struct FragStateMachine {
 */
 
 
+impl Default for FragStateMachine {
+    fn default() -> Self {
+        FragStateMachine::new()
+    }
+}
+
 impl FragStateMachine {
+    /// Create a new state machine.
+    /// Messages larger than DEFAULT_MAX_TOTAL_MESSAGE bytes will be discarded.
     pub fn new() -> Self {
+        FragStateMachine::with_max_total_message(DEFAULT_MAX_TOTAL_MESSAGE)
+    }
+
+    /// Create a new state machine.
+    /// Messages larger than max_total_message bytes will be discarded.
+    pub fn with_max_total_message(max_total_message: usize) -> Self {
         FragStateMachine {
-            used_message_ids: HashMap::new(),
-            cur_messages: HashMap::new(),
+            max_total_message,
+            used_message_ids: Map::new(),
+            cur_messages: Map::new(),
+            used_parent_ids: Map::new(),
+            cur_parents: Map::new(),
+            opt_buffer_pool: None,
         }
     }
 
+    /// Take reassembly buffers from the given pool, and return them when they are no longer
+    /// needed.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+
     /// Process a newly received Fragmentos message.
     /// Possibly return a reconstructed message.
     pub fn received_frag_message(&mut self, frag_message: &[u8]) -> Option<Vec<u8>> {
+        let unite_job = self.received_share(frag_message)?;
+        self.united(unite_job.unite())
+    }
+
+    /// Process a newly received Fragmentos message, without reconstructing the message.
+    /// Returns a UniteJob once enough shares were received. The result of the job should be
+    /// passed to united().
+    pub fn received_share(&mut self, frag_message: &[u8]) -> Option<UniteJob> {
+        self.accept_share(frag_message).unwrap_or(None)
+    }
+
+    /// Like received_share(), but tells whether the share was accepted: Its shortHash
+    /// verified, and it was kept for a message still being received. Returns Err(()) if it
+    /// was discarded.
+    pub(crate) fn accept_share(&mut self, frag_message: &[u8]) -> Result<Option<UniteJob>, ()> {
         // Use the error correcting code to try to correct the error if possible.
         match verify_frag_message(frag_message) {
             true => {},
-            false => {return None},
+            false => return Err(()),
         };
 
+        if frag_message.len() <= MESSAGE_ID_LEN + 1 + 1 + ECC_LEN {
+            return Err(());
+        }
+
         let message_id = array_ref![frag_message, 0, MESSAGE_ID_LEN];
 
         if self.used_message_ids.contains_key(message_id) {
             // Refresh message_id entry inside used_message_ids:
-            self.used_message_ids.insert(message_id.clone(), MESSAGE_ID_TICKS);
-            return None;
+            self.used_message_ids.insert(*message_id, MESSAGE_ID_TICKS);
+            return Err(());
         }
 
         let share_length = 
@@ -60,24 +203,48 @@ impl FragStateMachine {
         let share_index = frag_message[MESSAGE_ID_LEN + 1];
         let share_data = &frag_message[MESSAGE_ID_LEN + 1 + 1 ..  frag_message.len() - ECC_LEN];
 
+        // Discard fragments with illegal b or shareIndex values:
+        if b == 0 || b as usize > MAX_B {
+            return Err(());
+        }
+        let num_shares_total = 2 * (b as usize) - 1;
+        if share_index as usize >= num_shares_total {
+            return Err(());
+        }
+
         match self.cur_messages.contains_key(message_id) {
             true =>  {
                 let cur_m = self.cur_messages.get(message_id).unwrap();
                 // If there is already cur_m with the given message_id, make sure that it
                 // matches the received fragment metadata:
                 if cur_m.b != b {
-                    return None;
+                    return Err(());
                 }
                 if cur_m.share_length != share_length {
-                    return None;
+                    return Err(());
                 }
             },
             false => {
-                self.cur_messages.insert(message_id.clone(), CurMessage {
+                // Don't allocate space for a message that we are going to discard anyway:
+                let max_t_len = self.max_total_message + NONCE_LEN + 1 + 
+                    BLOCK_HEADER_LEN + (b as usize - 1);
+                if (b as usize) * share_length > max_t_len {
+                    return Err(());
+                }
+
+                let mut buffer = match self.opt_buffer_pool {
+                    Some(ref buffer_pool) => buffer_pool.take(),
+                    None => Vec::new(),
+                };
+                buffer.resize(num_shares_total * share_length, 0);
+
+                self.cur_messages.insert(*message_id, CurMessage {
                     ticks_to_live: MESSAGE_ID_TICKS,
                     b,
                     share_length,
-                    data_shares: HashMap::new(),
+                    buffer,
+                    present: [false; MAX_SHARES],
+                    num_shares: 0,
                 });
             }
         };
@@ -86,41 +253,156 @@ impl FragStateMachine {
             let cur_m = self.cur_messages.get_mut(message_id).unwrap();
 
             // If we already have this share, we discard the message:
-            if cur_m.data_shares.contains_key(&share_index) {
-                return None;
+            if cur_m.present[share_index as usize] {
+                return Err(());
             }
 
-            // Insert the new share we have received:
-            cur_m.data_shares.insert(share_index, share_data.to_vec());
+            // Write the new share we have received into its slot:
+            let slot_start = share_index as usize * share_length;
+            cur_m.buffer[slot_start .. slot_start + share_length].copy_from_slice(share_data);
+            cur_m.present[share_index as usize] = true;
+            cur_m.num_shares += 1;
 
-            if cur_m.data_shares.len() < b as usize {
-                return None;
+            if cur_m.num_shares < b as usize {
+                return Ok(None);
             }
 
             // We got b shares. This should be enough to try and reconstruct the full message.
-            self.used_message_ids.insert(message_id.clone(), MESSAGE_ID_TICKS);
+            self.used_message_ids.insert(*message_id, MESSAGE_ID_TICKS);
         }
 
         let cur_m = self.cur_messages.remove(message_id).unwrap();
+        Ok(Some(UniteJob {
+            message_id: *message_id,
+            b,
+            share_length,
+            buffer: cur_m.buffer,
+            present: cur_m.present,
+        }))
+    }
+
+    /// Process the result of a UniteJob.
+    /// Possibly return a full message, if all of its blocks were received.
+    pub fn united(&mut self, unite_res: Result<UnitedBlock, Vec<u8>>) -> Option<Vec<u8>> {
+        match unite_res {
+            Ok(united_block) => self.received_block(united_block),
+            Err(buffer) => {
+                recycle(&self.opt_buffer_pool, buffer);
+                None
+            },
+        }
+    }
+
+    /// Process a newly reconstructed block.
+    /// Possibly return a full message, if all of its blocks were received.
+    fn received_block(&mut self, mut block: UnitedBlock) -> Option<Vec<u8>> {
+        if !block.is_block {
+            // A small message, sent without a block header:
+            return self.single_block(block);
+        }
+        let (parent_id, block_index, block_count, data_len) = match parse_block(block.data()) {
+            Some(parsed) => (*parsed.parent_id, parsed.block_index,
+                             parsed.block_count, parsed.block_data.len()),
+            None => {
+                recycle(&self.opt_buffer_pool, block.buffer);
+                return None;
+            },
+        };
+        // From here on the block ranges over its data only:
+        block.range.start += BLOCK_HEADER_LEN;
+
+        if block_count == 1 {
+            // The message is contained in a single block:
+            return self.single_block(block);
+        }
+
+        if self.used_parent_ids.contains_key(&parent_id) {
+            recycle(&self.opt_buffer_pool, block.buffer);
+            return None;
+        }
+
+        // All blocks except the last one have the same length. 
+        // We can discard messages that are too large early:
+        if block_index < block_count - 1 &&
+            data_len.saturating_mul(block_count as usize - 1) > self.max_total_message {
+
+            self.used_parent_ids.insert(parent_id, MESSAGE_ID_TICKS);
+            recycle(&self.opt_buffer_pool, block.buffer);
+            return None;
+        }
+
+        let is_complete = {
+            let opt_buffer_pool = &self.opt_buffer_pool;
+            let cur_parent = self.cur_parents.entry(parent_id).or_insert_with(|| CurParent {
+                ticks_to_live: MESSAGE_ID_TICKS,
+                block_count,
+                total_len: 0,
+                blocks: Map::new(),
+            });
+
+            if cur_parent.block_count != block_count || 
+                cur_parent.blocks.contains_key(&block_index) {
+
+                recycle(opt_buffer_pool, block.buffer);
+                return None;
+            }
+
+            // The block is kept inside its own buffer until all the blocks arrive:
+            cur_parent.total_len += data_len;
+            cur_parent.blocks.insert(block_index, block);
+
+            cur_parent.total_len > self.max_total_message || 
+                cur_parent.blocks.len() == block_count as usize
+        };
+
+        if !is_complete {
+            return None;
+        }
 
-        let mut data_shares = cur_m.data_shares.into_iter()
-                              .map(|(input, data)| DataShare {
-                                  input, 
-                                  data,
-                              }).collect::<Vec<DataShare>>();
+        let mut cur_parent = self.cur_parents.remove(&parent_id).unwrap();
+        self.used_parent_ids.insert(parent_id, MESSAGE_ID_TICKS);
 
-        // Avoid non determinism by sorting:
-        data_shares.sort();
-        Some(match unite_message(message_id, &data_shares) {
-            Ok(m) => m,
-            Err(_) => {return None;}
-        })
+        if cur_parent.total_len > self.max_total_message {
+            for (_, block) in cur_parent.blocks {
+                recycle(&self.opt_buffer_pool, block.buffer);
+            }
+            return None;
+        }
+
+        // Copy the data of every block once, directly into a buffer of the full message size:
+        let mut m = match self.opt_buffer_pool {
+            Some(ref buffer_pool) => buffer_pool.take(),
+            None => Vec::new(),
+        };
+        m.reserve_exact(cur_parent.total_len);
+        for block_index in 0 .. block_count {
+            let block = cur_parent.blocks.remove(&block_index).unwrap();
+            m.extend_from_slice(block.data());
+            recycle(&self.opt_buffer_pool, block.buffer);
+        }
+        Some(m)
+    }
 
+    /// Return the data of a block that contains a whole message.
+    fn single_block(&mut self, block: UnitedBlock) -> Option<Vec<u8>> {
+        if block.range.len() > self.max_total_message {
+            recycle(&self.opt_buffer_pool, block.buffer);
+            return None;
+        }
+        // Move the data to the beginning of the buffer. This is the only copy of the
+        // message after its reconstruction:
+        let mut m = block.buffer;
+        m.truncate(block.range.end);
+        m.drain(.. block.range.start);
+        Some(m)
     }
 
     /// A notice about the passing time.
     /// Possibly use this to clean up old entries.
-    pub fn time_tick(&mut self) {
+    /// Returns the partially received messages that have expired.
+    pub fn time_tick(&mut self) -> Vec<ExpiredMessage> {
+        let mut expired = Vec::new();
+
         // Decrease the ticks_to_live for all cur_messages:
         for cur_message in self.cur_messages.values_mut() {
             if cur_message.ticks_to_live > 0 {
@@ -139,11 +421,19 @@ impl FragStateMachine {
         // For any such cleaned up message, move its message_id to used_message_ids.
         {
             let used_message_ids = &mut self.used_message_ids;
+            let opt_buffer_pool = &self.opt_buffer_pool;
+            let expired = &mut expired;
             self.cur_messages.retain(|message_id, cur_message| {
                 if cur_message.ticks_to_live > 0 {
                     true
                 } else {
-                    used_message_ids.insert(message_id.clone(), MESSAGE_ID_TICKS);
+                    expired.push(ExpiredMessage {
+                        message_id: *message_id,
+                        b: cur_message.b,
+                        num_shares: cur_message.num_shares,
+                    });
+                    used_message_ids.insert(*message_id, MESSAGE_ID_TICKS);
+                    recycle(opt_buffer_pool, mem::take(&mut cur_message.buffer));
                     false
                 }
             });
@@ -153,14 +443,70 @@ impl FragStateMachine {
         self.used_message_ids.retain(|_, &mut ticks_to_live| {
             ticks_to_live > 0 
         });
+
+        // Do the same for partially received parent messages:
+        for cur_parent in self.cur_parents.values_mut() {
+            if cur_parent.ticks_to_live > 0 {
+                cur_parent.ticks_to_live -= 1;
+            }
+        }
+
+        for ticks_to_live in self.used_parent_ids.values_mut() {
+            if *ticks_to_live > 0 {
+                *ticks_to_live -= 1;
+            }
+        }
+
+        {
+            let used_parent_ids = &mut self.used_parent_ids;
+            let opt_buffer_pool = &self.opt_buffer_pool;
+            self.cur_parents.retain(|parent_id, cur_parent| {
+                if cur_parent.ticks_to_live > 0 {
+                    true
+                } else {
+                    used_parent_ids.insert(*parent_id, MESSAGE_ID_TICKS);
+                    for (_, block) in mem::take(&mut cur_parent.blocks) {
+                        recycle(opt_buffer_pool, block.buffer);
+                    }
+                    false
+                }
+            });
+        }
+
+        self.used_parent_ids.retain(|_, &mut ticks_to_live| {
+            ticks_to_live > 0 
+        });
+
+        expired
     }
 }
 
 
 #[cfg(test)]
+// The baseline tests walk the frags by index:
+#[allow(clippy::needless_range_loop, clippy::manual_div_ceil)]
 mod tests {
     use super::*;
-    use ::messages::split_message;
+    use ::messages::{split_message, split_message_parts};
+    use ::blocks::split_blocks;
+
+    const MAX_TOTAL_MESSAGE: usize = 1 << 16;
+
+    /// Split a message into Fragmentos messages, going through the blocks layer.
+    fn split_large_message(m: &[u8], max_block_data: usize, max_dgram_len: usize) 
+        -> Vec<Vec<Vec<u8>>> {
+
+        split_blocks(m, b"parentid", max_block_data)
+            .unwrap()
+            .into_iter()
+            .map(|block| {
+                let mut frags = Vec::new();
+                split_message_parts(&[&block], true, b"nonce123", max_dgram_len, usize::MAX,
+                                    &mut frags).unwrap();
+                frags
+            })
+            .collect::<Vec<_>>()
+    }
 
     #[test]
     fn test_time_tick_basic() {
@@ -295,4 +641,132 @@ mod tests {
         // removed, and message id was moved to used_message_ids.
         assert_eq!(fsm.received_frag_message(&frags[frags.len() - 1]), None);
     }
+
+    #[test]
+    fn test_time_tick_expired() {
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
+
+        let orig_message = b"This is some message to be split";
+        let frags = split_message(orig_message, b"nonce123", 22).unwrap();
+        let b = frags.len().div_ceil(2);
+        for frag in &frags[.. b - 1] {
+            assert_eq!(fsm.received_frag_message(frag), None);
+        }
+
+        for _ in 0 .. MESSAGE_ID_TICKS - 1 {
+            assert!(fsm.time_tick().is_empty());
+        }
+        let expired = fsm.time_tick();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].message_id[..], frags[0][.. MESSAGE_ID_LEN]);
+        assert_eq!(expired[0].b as usize, b);
+        assert_eq!(expired[0].num_shares, b - 1);
+    }
+
+    #[test]
+    fn test_received_multiple_blocks() {
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
+
+        let orig_message = (0 .. 1000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let blocks_frags = split_large_message(&orig_message, 300, 100);
+        assert_eq!(blocks_frags.len(), 4);
+
+        // Send the blocks in reverse order:
+        let mut opt_united = None;
+        for frags in blocks_frags.iter().rev() {
+            assert_eq!(opt_united, None);
+            let b = frags.len().div_ceil(2);
+            for frag in &frags[frags.len() - b ..] {
+                opt_united = fsm.received_frag_message(frag);
+            }
+        }
+
+        assert_eq!(opt_united.unwrap(), orig_message);
+    }
+
+    #[test]
+    fn test_received_multiple_blocks_buffer_pool() {
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
+        let buffer_pool = BufferPool::new(16);
+        fsm.set_buffer_pool(buffer_pool.clone());
+
+        let orig_message = (0 .. 1000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let blocks_frags = split_large_message(&orig_message, 300, 100);
+
+        let mut opt_united = None;
+        for frags in &blocks_frags {
+            assert_eq!(opt_united, None);
+            for frag in frags {
+                if let Some(united) = fsm.received_frag_message(frag) {
+                    opt_united = Some(united);
+                }
+            }
+        }
+        assert_eq!(opt_united.unwrap(), orig_message);
+
+        // Every block was reconstructed inside its own buffer, which was returned once its data
+        // was copied into the full message:
+        let stats = buffer_pool.stats();
+        assert_eq!(stats.allocated, blocks_frags.len() + 1);
+        assert_eq!(stats.returned, blocks_frags.len());
+    }
+
+    #[test]
+    fn test_received_single_block_with_header() {
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
+
+        // A message of a single block, sent with a block header:
+        let orig_message = b"This is some message to be split";
+        let mut blocks_frags = split_large_message(orig_message, orig_message.len(), 22);
+        assert_eq!(blocks_frags.len(), 1);
+        let frags = blocks_frags.pop().unwrap();
+        let united = frags.iter()
+            .filter_map(|frag| fsm.received_frag_message(frag))
+            .next();
+        assert_eq!(united.unwrap(), orig_message);
+    }
+
+    #[test]
+    fn test_received_blocks_too_large() {
+        let mut fsm = FragStateMachine::with_max_total_message(500);
+
+        let orig_message = vec![0x55; 1000];
+        let blocks_frags = split_large_message(&orig_message, 300, 100);
+
+        for frags in &blocks_frags {
+            for frag in frags {
+                assert_eq!(fsm.received_frag_message(frag), None);
+            }
+        }
+
+        // A single block message that is too large is also discarded:
+        let frags = split_message(&orig_message, b"nonce123", 100).unwrap();
+        for frag in &frags {
+            assert_eq!(fsm.received_frag_message(frag), None);
+        }
+    }
+
+    #[test]
+    fn test_received_blocks_timeout() {
+        let mut fsm = FragStateMachine::with_max_total_message(MAX_TOTAL_MESSAGE);
+
+        let orig_message = vec![0x55; 1000];
+        let blocks_frags = split_large_message(&orig_message, 300, 100);
+
+        for frags in &blocks_frags[.. blocks_frags.len() - 1] {
+            for frag in frags {
+                assert_eq!(fsm.received_frag_message(frag), None);
+            }
+        }
+
+        // A lot of time has passed...
+        for _ in 0 .. MESSAGE_ID_TICKS + 1 {
+            fsm.time_tick();
+        }
+
+        // The last block is too late:
+        for frag in &blocks_frags[blocks_frags.len() - 1] {
+            assert_eq!(fsm.received_frag_message(frag), None);
+        }
+    }
 }
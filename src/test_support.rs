@@ -0,0 +1,31 @@
+//! Implementations shared by the tests of several modules.
+
+use ::rate_limit::{Length, QueueItem};
+
+
+impl Length for u32 {
+    fn len(&self) -> usize {
+        4
+    }
+}
+
+impl Length for Vec<u8> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// An item of the given priority.
+pub struct Prioritized(pub u32, pub u8);
+
+impl Length for Prioritized {
+    fn len(&self) -> usize {
+        4
+    }
+}
+
+impl QueueItem for Prioritized {
+    fn priority(&self) -> u8 {
+        self.1
+    }
+}
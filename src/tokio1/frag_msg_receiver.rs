@@ -0,0 +1,122 @@
+use std::pin::Pin;
+use std::marker::PhantomData;
+
+use futures_03::Stream;
+use futures_03::task::{Context, Poll};
+use tokio_1::time::Interval;
+
+use ::state_machine::FragStateMachine;
+use ::buffer_pool::BufferPool;
+
+
+/// Reconstructs messages from the datagrams received from recv_stream.
+pub struct FragMsgReceiver<A,R> {
+    frag_state_machine: FragStateMachine,
+    opt_buffer_pool: Option<BufferPool>,
+    recv_stream: R,
+    recv_time_tick: Interval,
+    phantom_a: PhantomData<A>,
+}
+
+// We never create a pinned reference to any of the fields, except recv_stream:
+impl<A,R: Unpin> Unpin for FragMsgReceiver<A,R> {}
+
+impl<A,R,E> FragMsgReceiver<A,R>
+where
+    R: Stream<Item=Result<(Vec<u8>, A), E>> + Unpin,
+{
+    /// Create a new receiver.
+    /// Partially received messages are discarded after 30 ticks of recv_time_tick.
+    /// Incoming messages larger than max_total_message bytes are discarded.
+    pub fn new(recv_stream: R, recv_time_tick: Interval, max_total_message: usize) -> Self {
+        FragMsgReceiver {
+            frag_state_machine: FragStateMachine::with_max_total_message(max_total_message),
+            opt_buffer_pool: None,
+            recv_stream,
+            recv_time_tick,
+            phantom_a: PhantomData,
+        }
+    }
+
+    /// Use the given pool for reassembly buffers.
+    /// Received datagrams are returned into the pool after being processed.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.frag_state_machine.set_buffer_pool(buffer_pool.clone());
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+}
+
+impl<A,R,E> Stream for FragMsgReceiver<A,R>
+where
+    R: Stream<Item=Result<(Vec<u8>, A), E>> + Unpin,
+{
+    type Item = Result<(Vec<u8>, A), E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while this.recv_time_tick.poll_tick(cx).is_ready() {
+            this.frag_state_machine.time_tick();
+        }
+
+        loop {
+            let (dgram, address) = match Pin::new(&mut this.recv_stream).poll_next(cx) {
+                Poll::Ready(Some(Ok((dgram, address)))) => (dgram, address),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // Add fragment to state machine, possibly getting enough shares
+            // to reconstruct a full message:
+            let opt_msg = this.frag_state_machine.received_frag_message(&dgram);
+            if let Some(ref buffer_pool) = this.opt_buffer_pool {
+                buffer_pool.give(dgram);
+            }
+
+            if let Some(msg) = opt_msg {
+                return Poll::Ready(Some(Ok((msg, address))));
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+    use futures_03::{StreamExt};
+    use futures_03::stream;
+    use tokio_1::runtime;
+    use tokio_1::time::interval;
+
+    use ::messages::split_message;
+
+    #[test]
+    fn test_frag_msg_receiver_basic() {
+        const MAX_DGRAM_LEN: usize = 22;
+        const ADDRESS: u32 = 0x12345678;
+
+        let orig_message = b"This is some message to be split";
+        let frags = split_message(orig_message, b"nonce123", MAX_DGRAM_LEN).unwrap();
+        let b = frags.len().div_ceil(2);
+
+        let items = frags.into_iter()
+            .take(b)
+            .map(|frag| Ok::<_, ()>((frag, ADDRESS)))
+            .collect::<Vec<_>>();
+
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+
+        let fmr = FragMsgReceiver::new(stream::iter(items),
+                                       interval(Duration::from_secs(1)), 1 << 16);
+        let received = rt.block_on(fmr.collect::<Vec<_>>());
+        assert_eq!(received, vec![Ok((orig_message.to_vec(), ADDRESS))]);
+    }
+}
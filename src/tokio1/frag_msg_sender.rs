@@ -0,0 +1,179 @@
+use std::pin::Pin;
+use std::collections::VecDeque;
+
+use futures_03::Sink;
+use futures_03::task::{Context, Poll};
+use rand::Rng;
+
+use ::fragmenter::{Fragmenter, CompatRng, FragmentError};
+use ::buffer_pool::BufferPool;
+
+
+#[derive(Debug)]
+pub enum FragMsgSenderError<E> {
+    /// A message could not be split into datagrams. The sender can still be used.
+    Fragment(FragmentError),
+    /// The underlying sink failed.
+    Sink(E),
+}
+
+
+
+/// Splits every message sent into it into datagrams, and sends them to send_sink.
+pub struct FragMsgSender<A,R,SK> {
+    send_sink: SK,
+    fragmenter: Fragmenter<CompatRng<R>>,
+    // Datagrams of the current message, waiting to be sent:
+    pending_dgrams: VecDeque<Vec<u8>>,
+    opt_address: Option<A>,
+}
+
+// We never create a pinned reference to any of the fields, except send_sink:
+impl<A,R,SK: Unpin> Unpin for FragMsgSender<A,R,SK> {}
+
+impl<A,R,SK> FragMsgSender<A,R,SK>
+where
+    R: Rng,
+    SK: Sink<(Vec<u8>, A)> + Unpin,
+{
+    pub fn new(send_sink: SK, max_dgram_len: usize, rng: R) -> Self {
+        FragMsgSender {
+            send_sink,
+            fragmenter: Fragmenter::new(max_dgram_len, CompatRng(rng)),
+            pending_dgrams: VecDeque::new(),
+            opt_address: None,
+        }
+    }
+
+    /// Take buffers for outgoing datagrams from the given pool, instead of allocating them.
+    /// Buffers could be returned into the pool once sent, see UdpDgramSink::set_buffer_pool.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.fragmenter.set_buffer_pool(buffer_pool);
+    }
+
+    /// Get the original inner send_sink
+    pub fn into_inner(self) -> SK {
+        self.send_sink
+    }
+}
+
+impl<A,R,SK> FragMsgSender<A,R,SK>
+where
+    A: Clone,
+    R: Rng,
+    SK: Sink<(Vec<u8>, A)> + Unpin,
+{
+    /// Send as many pending datagrams as possible.
+    fn poll_send_pending(&mut self, cx: &mut Context) -> Poll<Result<(), SK::Error>> {
+        while !self.pending_dgrams.is_empty() {
+            match Pin::new(&mut self.send_sink).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {},
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let dgram = self.pending_dgrams.pop_front().unwrap();
+            let address = self.opt_address.clone().unwrap();
+            Pin::new(&mut self.send_sink).start_send((dgram, address))?;
+        }
+        self.opt_address = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<A,R,SK> Sink<(Vec<u8>, A)> for FragMsgSender<A,R,SK>
+where
+    A: Clone,
+    R: Rng,
+    SK: Sink<(Vec<u8>, A)> + Unpin,
+{
+    type Error = FragMsgSenderError<SK::Error>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_send_pending(cx).map_err(FragMsgSenderError::Sink)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Vec<u8>, A)) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let (msg, address) = item;
+        debug_assert!(this.pending_dgrams.is_empty());
+
+        let dgrams = this.fragmenter.fragment(&msg)
+            .map_err(FragMsgSenderError::Fragment)?;
+        this.pending_dgrams.extend(dgrams);
+        this.opt_address = Some(address);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        match this.poll_send_pending(cx) {
+            Poll::Ready(Ok(())) => {},
+            other => return other.map_err(FragMsgSenderError::Sink),
+        };
+        Pin::new(&mut this.send_sink).poll_flush(cx).map_err(FragMsgSenderError::Sink)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        match this.poll_send_pending(cx) {
+            Poll::Ready(Ok(())) => {},
+            other => return other.map_err(FragMsgSenderError::Sink),
+        };
+        Pin::new(&mut this.send_sink).poll_close(cx).map_err(FragMsgSenderError::Sink)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand;
+    use rand::StdRng;
+    use futures_03::{SinkExt, StreamExt};
+    use futures_03::channel::mpsc;
+    use futures_03::executor::block_on;
+
+    use ::state_machine::FragStateMachine;
+
+    #[test]
+    fn test_frag_msg_sender_basic() {
+        let orig_message: Vec<u8> = b"This is some message to be split".to_vec();
+
+        const MAX_DGRAM_LEN: usize = 22;
+        const ADDRESS: u32 = 0x12345678;
+
+        let seed: &[_] = &[1,2,3,4,5];
+        let rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        let (send_sink, stream) = mpsc::unbounded();
+        let mut fms = FragMsgSender::new(send_sink, MAX_DGRAM_LEN, rng);
+        block_on(fms.send((orig_message.clone(), ADDRESS))).unwrap();
+        drop(fms);
+
+        let sent_dgrams = block_on(stream.collect::<Vec<_>>());
+        assert!(sent_dgrams.iter().all(|&(_, address)| address == ADDRESS));
+
+        let mut fsm = FragStateMachine::new();
+        let b = sent_dgrams.len().div_ceil(2);
+        let mut opt_united = None;
+        for (dgram, _) in &sent_dgrams[sent_dgrams.len() - b ..] {
+            assert_eq!(opt_united, None);
+            opt_united = fsm.received_frag_message(dgram);
+        }
+        assert_eq!(opt_united.unwrap(), orig_message);
+    }
+
+    #[test]
+    fn test_frag_msg_sender_dgram_too_small() {
+        let seed: &[_] = &[1,2,3,4,5];
+        let rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+        let (send_sink, _stream) = mpsc::unbounded::<(Vec<u8>, u32)>();
+        let mut fms = FragMsgSender::new(send_sink, 10, rng);
+        match block_on(fms.send((b"Some message".to_vec(), 1))) {
+            Err(FragMsgSenderError::Fragment(FragmentError::DgramTooSmall)) => {},
+            other => panic!("Unexpected result: {:?}", other),
+        };
+    }
+}
@@ -0,0 +1,14 @@
+//! Fragmentos on top of futures 0.3 and tokio 1.x.
+//!
+//! This layer mirrors FragMsgSender, FragMsgReceiver, rate_limit_channel and DgramCodec, but
+//! implements the futures 0.3 Sink and Stream traits. It is only available with the tokio1
+//! feature.
+
+mod frag_msg_sender;
+mod frag_msg_receiver;
+mod udp;
+pub mod rate_limit;
+
+pub use self::frag_msg_sender::{FragMsgSender, FragMsgSenderError};
+pub use self::frag_msg_receiver::FragMsgReceiver;
+pub use self::udp::{udp_dgrams, UdpDgramSink, UdpDgramStream};
@@ -0,0 +1,205 @@
+use std::pin::Pin;
+use std::future::Future;
+use std::time::Duration;
+
+use futures_03::{Sink, Stream};
+use futures_03::channel::mpsc;
+use futures_03::task::{Context, Poll};
+use tokio_1::time::{sleep, Sleep};
+
+pub use ::rate_limit_queue::Length;
+use ::rate_limit_queue::{RateLimitQueue, RateLimitStats, Queued, Unprioritized};
+
+
+/// Drives a RateLimitQueue with the tokio 1.x timer.
+struct RateLimitFuture<T> {
+    inner_sender: mpsc::Sender<T>,
+    inner_receiver_opt: Option<mpsc::Receiver<T>>,
+    queue: RateLimitQueue<Unprioritized<T>>,
+    opt_next_timeout: Option<Pin<Box<Sleep>>>,
+}
+
+// Items are never pinned:
+impl<T> Unpin for RateLimitFuture<T> {}
+
+enum TrySendResult {
+    NoMoreTokens,
+    NoMoreItems,
+    SenderNotReady,
+    SenderError,
+}
+
+enum TryRecvResult {
+    NoReceiver,
+    ReceiverNotReady,
+    ReceiverClosed,
+    QueueFull,
+}
+
+impl<T: Length> RateLimitFuture<T> {
+    fn new(inner_sender: mpsc::Sender<T>,
+           inner_receiver: mpsc::Receiver<T>,
+           queue_len: usize,
+           min_tokens_per_ms: usize) -> Self {
+
+        RateLimitFuture {
+            inner_sender,
+            inner_receiver_opt: Some(inner_receiver),
+            queue: RateLimitQueue::new(queue_len, min_tokens_per_ms, RateLimitStats::default()),
+            opt_next_timeout: None,
+        }
+    }
+
+    fn try_recv(&mut self, cx: &mut Context) -> TryRecvResult {
+        match self.inner_receiver_opt.take() {
+            Some(mut inner_receiver) => {
+                while self.queue.has_room() {
+                    match Pin::new(&mut inner_receiver).poll_next(cx) {
+                        Poll::Pending => {
+                            self.inner_receiver_opt = Some(inner_receiver);
+                            return TryRecvResult::ReceiverNotReady;
+                        },
+                        Poll::Ready(Some(item)) => 
+                            self.queue.push_item(Unprioritized::from_item(item)),
+                        Poll::Ready(None) => return TryRecvResult::ReceiverClosed,
+                    }
+                }
+                self.inner_receiver_opt = Some(inner_receiver);
+                TryRecvResult::QueueFull
+            },
+            None => TryRecvResult::NoReceiver,
+        }
+    }
+
+    fn try_send(&mut self, cx: &mut Context) -> TrySendResult {
+        while !self.queue.is_empty() {
+            let item = match self.queue.pop_item() {
+                Some(item) => item,
+                None => return TrySendResult::NoMoreTokens,
+            };
+            match Pin::new(&mut self.inner_sender).poll_ready(cx) {
+                Poll::Ready(Err(_)) => return TrySendResult::SenderError,
+                Poll::Pending => {
+                    // Put the item back into the queue:
+                    self.queue.unpop_item(item);
+                    return TrySendResult::SenderNotReady;
+                },
+                Poll::Ready(Ok(())) => {
+                    if Pin::new(&mut self.inner_sender).start_send(item.into_item()).is_err() {
+                        return TrySendResult::SenderError;
+                    }
+                },
+            }
+        }
+        TrySendResult::NoMoreItems
+    }
+}
+
+impl<T: Length> Future for RateLimitFuture<T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        loop {
+            // If timer is ready, we add tokens to the token bucket.
+            let timeout_ready = match this.opt_next_timeout {
+                Some(ref mut next_timeout) => next_timeout.as_mut().poll(cx).is_ready(),
+                None => false,
+            };
+            if timeout_ready {
+                this.opt_next_timeout = None;
+                this.queue.tick();
+            }
+
+            loop {
+                // Send as many messages as possible:
+                match this.try_send(cx) {
+                    TrySendResult::NoMoreItems => {},
+                    TrySendResult::NoMoreTokens |
+                    TrySendResult::SenderNotReady => break,
+                    TrySendResult::SenderError => return Poll::Ready(()),
+                }
+
+                // Try to receive as many messages as possible:
+                match this.try_recv(cx) {
+                    TryRecvResult::QueueFull => {},
+                    TryRecvResult::NoReceiver |
+                    TryRecvResult::ReceiverNotReady |
+                    TryRecvResult::ReceiverClosed => break,
+                }
+            }
+
+            if this.queue.is_empty() && this.inner_receiver_opt.is_none() {
+                // If there are no more pending items to be sent, and the receiver is closed,
+                // we have nothing more to do here.
+                return Poll::Ready(());
+            }
+
+            // If there are any pending items, set the timer to poll us again later.
+            if !this.queue.is_empty() && this.opt_next_timeout.is_none() {
+                this.opt_next_timeout = Some(Box::pin(sleep(Duration::from_millis(1))));
+                continue;
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+
+/// Create a rate limited channel.
+/// The rate limiting task is spawned on the current tokio runtime, so this function must be
+/// called from within a runtime context.
+pub fn rate_limit_channel<T>(queue_len: usize, min_tokens_per_ms: usize)
+    -> (mpsc::Sender<T>, mpsc::Receiver<T>)
+where
+    T: Length + Send + 'static,
+{
+    let (rate_limit_sender, inner_receiver) = mpsc::channel(0);
+    let (inner_sender, rate_limit_receiver) = mpsc::channel(0);
+
+    let rate_limit_future = RateLimitFuture::new(
+        inner_sender,
+        inner_receiver,
+        queue_len,
+        min_tokens_per_ms);
+
+    tokio_1::spawn(rate_limit_future);
+
+    (rate_limit_sender, rate_limit_receiver)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_03::StreamExt;
+    use futures_03::future::join;
+    use futures_03::stream;
+    use tokio_1::runtime;
+
+    #[test]
+    fn test_rate_limit_variable_len() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = rt.enter();
+
+        // Length is implemented for Vec<u8> in ::test_support.
+        let (rl_sender, rl_receiver) = rate_limit_channel(5, 1);
+        let source_stream = stream::iter((0 .. 400)
+            .map(|i| Ok(vec![i as u8; (i % 17) as usize])));
+
+        // forward() closes rl_sender once all the items were sent:
+        let (send_res, res_vec) = rt.block_on(join(source_stream.forward(rl_sender),
+                                                   rl_receiver.collect::<Vec<_>>()));
+        assert!(send_res.is_ok());
+
+        let expected_vec = (0 .. 400)
+            .map(|i| vec![i as u8; (i % 17) as usize])
+            .collect::<Vec<Vec<u8>>>();
+
+        assert_eq!(res_vec, expected_vec);
+    }
+}
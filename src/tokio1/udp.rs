@@ -0,0 +1,119 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::net::SocketAddr;
+
+use futures_03::{Sink, Stream};
+use futures_03::task::{Context, Poll};
+use tokio_1::io::ReadBuf;
+use tokio_1::net::UdpSocket;
+
+use ::buffer_pool::BufferPool;
+
+// Maximum size of a UDP datagram:
+const MAX_UDP_DGRAM: usize = 1 << 16;
+
+
+/// Split a UDP socket into a sink of (datagram, destination) pairs, and a stream of
+/// (datagram, source) pairs. This is the tokio 1.x counterpart of framing a tokio-core UDP
+/// socket with DgramCodec.
+pub fn udp_dgrams(socket: UdpSocket) -> (UdpDgramSink, UdpDgramStream) {
+    let socket = Arc::new(socket);
+    let sink = UdpDgramSink {
+        socket: socket.clone(),
+        opt_pending: None,
+        opt_buffer_pool: None,
+    };
+    let stream = UdpDgramStream {
+        socket,
+        recv_buffer: vec![0; MAX_UDP_DGRAM],
+        opt_buffer_pool: None,
+    };
+    (sink, stream)
+}
+
+pub struct UdpDgramSink {
+    socket: Arc<UdpSocket>,
+    opt_pending: Option<(Vec<u8>, SocketAddr)>,
+    opt_buffer_pool: Option<BufferPool>,
+}
+
+impl UdpDgramSink {
+    /// Return sent datagram buffers into the given pool.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let Some((ref dgram, address)) = self.opt_pending {
+            match self.socket.poll_send_to(cx, dgram, address) {
+                Poll::Ready(Ok(_)) => {},
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+        }
+        if let Some((dgram, _)) = self.opt_pending.take() {
+            if let Some(ref buffer_pool) = self.opt_buffer_pool {
+                buffer_pool.give(dgram);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Sink<(Vec<u8>, SocketAddr)> for UdpDgramSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Vec<u8>, SocketAddr)) -> io::Result<()> {
+        let this = self.get_mut();
+        debug_assert!(this.opt_pending.is_none());
+        this.opt_pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+}
+
+pub struct UdpDgramStream {
+    socket: Arc<UdpSocket>,
+    recv_buffer: Vec<u8>,
+    opt_buffer_pool: Option<BufferPool>,
+}
+
+impl UdpDgramStream {
+    /// Take received datagram buffers from the given pool.
+    pub fn set_buffer_pool(&mut self, buffer_pool: BufferPool) {
+        self.opt_buffer_pool = Some(buffer_pool);
+    }
+}
+
+impl Stream for UdpDgramStream {
+    type Item = io::Result<(Vec<u8>, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut read_buf = ReadBuf::new(&mut this.recv_buffer);
+        let address = match this.socket.poll_recv_from(cx, &mut read_buf) {
+            Poll::Ready(Ok(address)) => address,
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let mut dgram = match this.opt_buffer_pool {
+            Some(ref buffer_pool) => buffer_pool.take(),
+            None => Vec::new(),
+        };
+        dgram.extend_from_slice(read_buf.filled());
+        Poll::Ready(Some(Ok((dgram, address))))
+    }
+}
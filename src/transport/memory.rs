@@ -0,0 +1,141 @@
+use std::io;
+
+use futures::{Sink, Stream, Poll, StartSend, AsyncSink};
+use futures::sync::mpsc;
+
+use super::DatagramTransport;
+
+
+/// One end of an in-memory transport, created by mem_transport_pair().
+/// Useful for testing without any sockets.
+/// The address of the first end is 0, and the address of the second end is 1.
+pub struct MemTransport {
+    local_addr: u32,
+    max_dgram_len: usize,
+    sender: mpsc::Sender<(Vec<u8>, u32)>,
+    receiver: mpsc::Receiver<(Vec<u8>, u32)>,
+}
+
+/// Create two connected in-memory transports.
+/// Every end can buffer up to queue_len datagrams on the way to the other end.
+pub fn mem_transport_pair(max_dgram_len: usize, queue_len: usize) -> (MemTransport, MemTransport) {
+    let (sender_a, receiver_b) = mpsc::channel(queue_len);
+    let (sender_b, receiver_a) = mpsc::channel(queue_len);
+
+    (MemTransport { local_addr: 0, max_dgram_len, sender: sender_a, receiver: receiver_a },
+     MemTransport { local_addr: 1, max_dgram_len, sender: sender_b, receiver: receiver_b })
+}
+
+/// Sending half of a MemTransport.
+pub struct MemSink {
+    local_addr: u32,
+    max_dgram_len: usize,
+    sender: mpsc::Sender<(Vec<u8>, u32)>,
+}
+
+impl Sink for MemSink {
+    type SinkItem = (Vec<u8>, u32);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem)
+        -> StartSend<Self::SinkItem, Self::SinkError> {
+
+        let (dgram, address) = item;
+        if dgram.len() > self.max_dgram_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Datagram is too large"));
+        }
+        // Like with a real network, datagrams sent to unknown addresses are lost:
+        if address != 1 - self.local_addr {
+            return Ok(AsyncSink::Ready);
+        }
+
+        match self.sender.start_send((dgram, self.local_addr)) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady((dgram, _))) => Ok(AsyncSink::NotReady((dgram, address))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Remote end was dropped")),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.sender.poll_complete()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Remote end was dropped"))
+    }
+}
+
+/// Receiving half of a MemTransport.
+pub struct MemStream {
+    receiver: mpsc::Receiver<(Vec<u8>, u32)>,
+}
+
+impl Stream for MemStream {
+    type Item = (Vec<u8>, u32);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Receiving from an mpsc::Receiver never fails:
+        Ok(self.receiver.poll().unwrap())
+    }
+}
+
+impl DatagramTransport for MemTransport {
+    type Address = u32;
+    type Error = io::Error;
+    type Sink = MemSink;
+    type Stream = MemStream;
+
+    fn max_dgram_len(&self) -> usize {
+        self.max_dgram_len
+    }
+
+    fn local_addr(&self) -> io::Result<u32> {
+        Ok(self.local_addr)
+    }
+
+    fn split(self) -> (MemSink, MemStream) {
+        (MemSink {
+            local_addr: self.local_addr,
+            max_dgram_len: self.max_dgram_len,
+            sender: self.sender,
+         },
+         MemStream {
+            receiver: self.receiver,
+         })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn test_mem_transport_send_recv() {
+        let (transport_a, transport_b) = mem_transport_pair(8, 4);
+        assert_eq!(transport_a.local_addr().unwrap(), 0);
+        assert_eq!(transport_b.local_addr().unwrap(), 1);
+        assert_eq!(transport_a.max_dgram_len(), 8);
+
+        let (sink_a, _stream_a) = transport_a.split();
+        let (_sink_b, stream_b) = transport_b.split();
+
+        // Lost, as there is no such address:
+        let sink_a = sink_a.send((b"lost".to_vec(), 7)).wait().unwrap();
+        let sink_a = sink_a.send((b"hello".to_vec(), 1)).wait().unwrap();
+        let (opt_item, _stream_b) = stream_b.into_future().wait().map_err(|(e,_)| e).unwrap();
+        assert_eq!(opt_item, Some((b"hello".to_vec(), 0)));
+
+        // Too large:
+        assert!(sink_a.send((vec![0; 9], 1)).wait().is_err());
+    }
+
+    #[test]
+    fn test_mem_transport_remote_dropped() {
+        let (transport_a, transport_b) = mem_transport_pair(8, 4);
+        let (sink_a, _stream_a) = transport_a.split();
+        drop(transport_b);
+
+        let err = sink_a.send((b"hello".to_vec(), 1)).wait().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
@@ -0,0 +1,114 @@
+//! Datagram transports Fragmentos messages can be sent over.
+
+use std::io;
+
+use futures::{Sink, Stream};
+use rand::Rng;
+
+use ::frag_msg_sender::FragMsgSender;
+use ::frag_msg_receiver::FragMsgReceiver;
+use ::blocks::max_block_data;
+
+mod udp;
+mod memory;
+#[cfg(unix)]
+mod unix;
+
+pub use self::udp::{UdpTransport, UDP_DEFAULT_MAX_DGRAM};
+pub use self::memory::{MemTransport, MemSink, MemStream, mem_transport_pair};
+#[cfg(unix)]
+pub use self::unix::{UnixTransport, unix_max_dgram};
+
+
+/// A datagram based transport: unreliable, unordered delivery of datagrams of bounded size.
+pub trait DatagramTransport {
+    /// Address of a remote side.
+    type Address;
+    type Error;
+    /// Sends datagrams to remote addresses.
+    type Sink: Sink<SinkItem=(Vec<u8>, Self::Address), SinkError=Self::Error>;
+    /// Received datagrams, together with the address of the sender.
+    type Stream: Stream<Item=(Vec<u8>, Self::Address), Error=Self::Error>;
+
+    /// Maximum size in bytes of a datagram sent over this transport.
+    fn max_dgram_len(&self) -> usize;
+
+    fn local_addr(&self) -> Result<Self::Address, Self::Error>;
+
+    /// Split the transport into its sending and receiving halves.
+    fn split(self) -> (Self::Sink, Self::Stream);
+}
+
+/// Make sure that datagrams of max_dgram_len bytes could carry the Fragmentos fields, 
+/// a block header and some data.
+fn check_min_dgram_len(max_dgram_len: usize) -> io::Result<()> {
+    match max_block_data(max_dgram_len) {
+        Ok(_) => Ok(()),
+        Err(()) => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "max_dgram_len is too small to carry messages")),
+    }
+}
+
+/// A FragMsgSender sending over the transport T.
+pub type TransportSender<T, R> = FragMsgSender<<T as DatagramTransport>::Address, R,
+    <T as DatagramTransport>::Sink, <T as DatagramTransport>::Error>;
+
+/// A FragMsgReceiver receiving from the transport T.
+pub type TransportReceiver<T, K> = FragMsgReceiver<<T as DatagramTransport>::Address,
+    <T as DatagramTransport>::Stream, <T as DatagramTransport>::Error, K>;
+
+/// Create a sender and a receiver of Fragmentos messages over the given transport.
+/// The size of sent datagrams is the transport's max_dgram_len().
+/// Incoming messages larger than max_total_message bytes are discarded.
+pub fn frag_transport<T,R,K>(transport: T, rng: R, recv_time_tick: K, max_total_message: usize)
+    -> (TransportSender<T, R>, TransportReceiver<T, K>)
+where
+    T: DatagramTransport,
+    T::Address: 'static,
+    R: Rng,
+    K: Stream<Item=(), Error=()>,
+{
+    let max_dgram_len = transport.max_dgram_len();
+    let (sink, stream) = transport.split();
+    (FragMsgSender::new(sink, max_dgram_len, rng),
+     FragMsgReceiver::with_max_total_message(stream, recv_time_tick, max_total_message))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+    use futures::{stream, Future};
+    use tokio_core::reactor::{Core, Interval};
+    use rand;
+    use rand::StdRng;
+
+    #[test]
+    fn test_frag_transport_mem() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let (transport_a, transport_b) = mem_transport_pair(22, 0);
+        let addr_b = transport_b.local_addr().unwrap();
+
+        let seed: &[_] = &[1,2,3,4,5];
+        let rng: StdRng = rand::SeedableRng::from_seed(seed);
+        let time_tick = || Interval::new(Duration::new(1,0), &handle).unwrap().map_err(|_| ());
+
+        let (sender_a, _receiver_a) = frag_transport(transport_a, rng, time_tick(), 1 << 16);
+        let (_sender_b, receiver_b) = frag_transport(transport_b, rng, time_tick(), 1 << 16);
+
+        let messages = vec![
+            (b"This is some message to be split".to_vec(), addr_b),
+            (b"And this is another one".to_vec(), addr_b),
+        ];
+        handle.spawn(sender_a.send_all(stream::iter_ok(messages.clone()))
+                     .then(|_| Ok(())));
+
+        let received = core.run(receiver_b.take(2).collect().map_err(|_| ())).unwrap();
+        assert_eq!(received.into_iter().map(|(msg, _)| msg).collect::<Vec<_>>(),
+                   messages.into_iter().map(|(msg, _)| msg).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures::Stream;
+use futures::stream::{SplitSink, SplitStream};
+use tokio_core::net::{UdpSocket, UdpFramed};
+use tokio_core::reactor::Handle;
+
+use ::utils::DgramCodec;
+use super::{DatagramTransport, check_min_dgram_len};
+
+/// Default maximum size of sent UDP datagrams.
+/// Datagrams of this size are very unlikely to be fragmented on the way.
+pub const UDP_DEFAULT_MAX_DGRAM: usize = 512;
+// Largest possible payload of a UDP datagram over IPv4:
+const UDP_MAX_PAYLOAD: usize = 65507;
+
+
+/// A transport over a UDP socket.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    max_dgram_len: usize,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<Self> {
+        Ok(UdpTransport::from_socket(UdpSocket::bind(addr, handle)?))
+    }
+
+    pub fn from_socket(socket: UdpSocket) -> Self {
+        UdpTransport {
+            socket,
+            max_dgram_len: UDP_DEFAULT_MAX_DGRAM,
+        }
+    }
+
+    /// Set the maximum size of sent datagrams,
+    /// for example when the path MTU is known to be larger than the default.
+    pub fn set_max_dgram_len(&mut self, max_dgram_len: usize) -> io::Result<()> {
+        if max_dgram_len > UDP_MAX_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "max_dgram_len is larger than a UDP datagram"));
+        }
+        check_min_dgram_len(max_dgram_len)?;
+        self.max_dgram_len = max_dgram_len;
+        Ok(())
+    }
+}
+
+impl DatagramTransport for UdpTransport {
+    type Address = SocketAddr;
+    type Error = io::Error;
+    type Sink = SplitSink<UdpFramed<DgramCodec>>;
+    type Stream = SplitStream<UdpFramed<DgramCodec>>;
+
+    fn max_dgram_len(&self) -> usize {
+        self.max_dgram_len
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn split(self) -> (Self::Sink, Self::Stream) {
+        self.socket.framed(DgramCodec).split()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Sink;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_udp_transport_send_recv() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let transport_a = UdpTransport::bind(&local_addr, &handle).unwrap();
+        let mut transport_b = UdpTransport::bind(&local_addr, &handle).unwrap();
+        assert_eq!(transport_b.max_dgram_len(), UDP_DEFAULT_MAX_DGRAM);
+        assert!(transport_b.set_max_dgram_len(1 << 16).is_err());
+        assert!(transport_b.set_max_dgram_len(0).is_err());
+        // No room for share data after the Fragmentos fields:
+        assert!(transport_b.set_max_dgram_len(18).is_err());
+        transport_b.set_max_dgram_len(1200).unwrap();
+        assert_eq!(transport_b.max_dgram_len(), 1200);
+
+        let addr_a = transport_a.local_addr().unwrap();
+        let addr_b = transport_b.local_addr().unwrap();
+        let (sink_a, _stream_a) = transport_a.split();
+        let (_sink_b, stream_b) = transport_b.split();
+
+        core.run(sink_a.send((b"hello".to_vec(), addr_b))).unwrap();
+        let (opt_item, _) = core.run(stream_b.into_future()).map_err(|(e,_)| e).unwrap();
+        assert_eq!(opt_item, Some((b"hello".to_vec(), addr_a)));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{io, mem, cmp};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+
+use futures::Stream;
+use futures::stream::{SplitSink, SplitStream};
+use libc;
+use tokio_core::reactor::Handle;
+use tokio_uds::{UnixDatagram, UnixDatagramFramed};
+
+use ::utils::UnixDgramCodec;
+use super::{DatagramTransport, check_min_dgram_len};
+
+// Size of the receive buffer of UnixDatagramFramed. Larger datagrams are truncated:
+const FRAMED_RECV_BUFFER: usize = 64 * 1024;
+// Linux rejects datagrams larger than the send buffer minus this overhead:
+#[cfg(target_os = "linux")]
+const SNDBUF_OVERHEAD: usize = 32;
+#[cfg(not(target_os = "linux"))]
+const SNDBUF_OVERHEAD: usize = 0;
+
+
+/// The largest datagram that can be sent through the given socket,
+/// and received on the other side by a UnixTransport.
+/// The size of Unix datagrams is limited by the send buffer of the socket.
+pub fn unix_max_dgram<S: AsRawFd>(socket: &S) -> io::Result<usize> {
+    let mut sndbuf: libc::c_int = 0;
+    let mut optlen = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_SNDBUF,
+                         &mut sndbuf as *mut libc::c_int as *mut libc::c_void, &mut optlen)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let max_dgram = (sndbuf as usize).saturating_sub(SNDBUF_OVERHEAD);
+    Ok(cmp::min(max_dgram, FRAMED_RECV_BUFFER))
+}
+
+/// A transport over a Unix datagram socket. Addresses are socket paths.
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    max_dgram_len: usize,
+}
+
+impl UnixTransport {
+    /// Bind a new socket to the given path.
+    /// The maximum datagram size is the largest the socket allows, see unix_max_dgram().
+    pub fn bind<P: AsRef<Path>>(path: P, handle: &Handle) -> io::Result<Self> {
+        UnixTransport::from_socket(UnixDatagram::bind(path, handle)?)
+    }
+
+    pub fn from_socket(socket: UnixDatagram) -> io::Result<Self> {
+        let max_dgram_len = unix_max_dgram(&socket)?;
+        Ok(UnixTransport {
+            socket,
+            max_dgram_len,
+        })
+    }
+
+    /// Send smaller datagrams than the socket allows.
+    /// Useful when the remote side has a smaller send buffer,
+    /// as both sides should use the same datagram size.
+    pub fn set_max_dgram_len(&mut self, max_dgram_len: usize) -> io::Result<()> {
+        if max_dgram_len > unix_max_dgram(&self.socket)? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "max_dgram_len is larger than the socket allows"));
+        }
+        check_min_dgram_len(max_dgram_len)?;
+        self.max_dgram_len = max_dgram_len;
+        Ok(())
+    }
+}
+
+impl DatagramTransport for UnixTransport {
+    type Address = PathBuf;
+    type Error = io::Error;
+    type Sink = SplitSink<UnixDatagramFramed<UnixDgramCodec>>;
+    type Stream = SplitStream<UnixDatagramFramed<UnixDgramCodec>>;
+
+    fn max_dgram_len(&self) -> usize {
+        self.max_dgram_len
+    }
+
+    fn local_addr(&self) -> io::Result<PathBuf> {
+        let addr = self.socket.local_addr()?;
+        addr.as_pathname()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| io::Error::other("Socket is not bound to a path"))
+    }
+
+    fn split(self) -> (Self::Sink, Self::Stream) {
+        self.socket.framed(UnixDgramCodec).split()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use futures::Sink;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_unix_transport_send_recv() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let dir = env::temp_dir().join(format!("fragmentos_unix_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path_a = dir.join("a.sock");
+        let path_b = dir.join("b.sock");
+
+        let transport_a = UnixTransport::bind(&path_a, &handle).unwrap();
+        let transport_b = UnixTransport::bind(&path_b, &handle).unwrap();
+        let max_dgram_len = transport_a.max_dgram_len();
+        assert!(max_dgram_len > 0 && max_dgram_len <= FRAMED_RECV_BUFFER);
+        assert_eq!(transport_b.local_addr().unwrap(), path_b);
+
+        let (sink_a, _stream_a) = transport_a.split();
+        let (_sink_b, stream_b) = transport_b.split();
+
+        // A datagram of the maximum size arrives in one piece:
+        let dgram = (0 .. max_dgram_len).map(|i| i as u8).collect::<Vec<u8>>();
+        core.run(sink_a.send((dgram.clone(), path_b.clone()))).unwrap();
+        let (opt_item, _) = core.run(stream_b.into_future()).map_err(|(e,_)| e).unwrap();
+        assert_eq!(opt_item, Some((dgram, path_a)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_unix_transport_set_max_dgram_len() {
+        let core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let dir = env::temp_dir().join(format!("fragmentos_unix_max_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut transport = UnixTransport::bind(dir.join("a.sock"), &handle).unwrap();
+        let max_dgram_len = transport.max_dgram_len();
+        assert!(transport.set_max_dgram_len(max_dgram_len + 1).is_err());
+        assert!(transport.set_max_dgram_len(0).is_err());
+        transport.set_max_dgram_len(512).unwrap();
+        assert_eq!(transport.max_dgram_len(), 512);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,14 @@
 use std::io;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::net;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use tokio_core::net::{UdpCodec};
+#[cfg(unix)]
+use tokio_uds::UnixDatagramCodec;
+
+use ::buffer_pool::BufferPool;
 
 pub struct DgramCodec;
 
@@ -12,7 +20,7 @@ impl UdpCodec for DgramCodec {
     type Out = (Vec<u8>, SocketAddr);
 
     fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
-        Ok((buf.to_vec(), src.clone()))
+        Ok((buf.to_vec(), *src))
     }
     
     fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
@@ -22,3 +30,58 @@ impl UdpCodec for DgramCodec {
     }
 
 }
+
+/// A UDP codec that works like DgramCodec, 
+/// but takes received datagram buffers from a BufferPool, 
+/// and returns sent datagram buffers into the pool.
+pub struct PooledDgramCodec {
+    buffer_pool: BufferPool,
+}
+
+impl PooledDgramCodec {
+    pub fn new(buffer_pool: BufferPool) -> Self {
+        PooledDgramCodec {
+            buffer_pool,
+        }
+    }
+}
+
+impl UdpCodec for PooledDgramCodec {
+    type In = (Vec<u8>, SocketAddr);
+    type Out = (Vec<u8>, SocketAddr);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        let mut dgram = self.buffer_pool.take();
+        dgram.extend_from_slice(buf);
+        Ok((dgram, *src))
+    }
+    
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        let (dgram, address) = msg;
+        buf.extend_from_slice(&dgram);
+        self.buffer_pool.give(dgram);
+        address
+    }
+}
+
+/// A Unix datagram socket codec, like DgramCodec.
+/// Addresses are socket paths. Datagrams from unbound sockets have an empty path.
+#[cfg(unix)]
+pub struct UnixDgramCodec;
+
+#[cfg(unix)]
+impl UnixDatagramCodec for UnixDgramCodec {
+    type In = (Vec<u8>, PathBuf);
+    type Out = (Vec<u8>, PathBuf);
+
+    fn decode(&mut self, src: &net::SocketAddr, buf: &[u8]) -> io::Result<Self::In> {
+        let path = src.as_pathname().map(Path::to_path_buf).unwrap_or_default();
+        Ok((buf.to_vec(), path))
+    }
+
+    fn encode(&mut self, msg: Self::Out, buf: &mut Vec<u8>) -> io::Result<PathBuf> {
+        let (dgram, path) = msg;
+        buf.extend_from_slice(&dgram);
+        Ok(path)
+    }
+}
@@ -1,6 +1,7 @@
 extern crate rand;
 extern crate futures;
 extern crate tokio_core;
+extern crate futures_cpupool;
 extern crate fragmentos;
 
 use std::time::{Duration};
@@ -8,15 +9,58 @@ use std::time::{Duration};
 use rand::StdRng;
 use futures::{stream, Future, Stream, Sink};
 use futures::sync::mpsc;
+use futures_cpupool::CpuPool;
 
 use fragmentos::FragMsgReceiver;
 use fragmentos::FragMsgSender;
+use fragmentos::{max_message, BufferPool};
+use fragmentos::sim::{sim_channel, SimConfig, LossModel};
+use fragmentos::multipath::{MultipathSink, PeerMap};
 
-use tokio_core::reactor::{Core, Interval};
+use tokio_core::reactor::{Core, Handle, Interval};
 
 // A maximum size of underlying datagram:
 const MAX_DGRAM_LEN: usize = 22;
 
+type TimeTick = Box<dyn Stream<Item=(), Error=()>>;
+
+fn new_rng() -> StdRng {
+    let seed: &[_] = &[1,2,3,4,5];
+    rand::SeedableRng::from_seed(seed)
+}
+
+fn new_time_tick(handle: &Handle) -> TimeTick {
+    Box::new(Interval::new(Duration::new(1,0), handle)
+        .unwrap()
+        .map_err(|_| ()))
+}
+
+/// Send messages with a FragMsgSender over sink, and collect the messages a FragMsgReceiver 
+/// over stream reconstructs until stream is closed.
+/// set_up configures the sender and the receiver before anything is sent.
+fn send_receive<SK,ST,F>(core: &mut Core, sink: SK, stream: ST, messages: &[(Vec<u8>, u32)], 
+                         set_up: F) -> Vec<(Vec<u8>, u32)>
+where
+    SK: Sink<SinkItem=(Vec<u8>, u32)> + 'static,
+    ST: Stream<Item=(Vec<u8>, u32)>,
+    F: FnOnce(&mut FragMsgSender<u32, StdRng, SK, SK::SinkError>, 
+              &mut FragMsgReceiver<u32, ST, ST::Error, TimeTick>),
+{
+    let handle = core.handle();
+
+    let mut frag_sender = FragMsgSender::new(sink, MAX_DGRAM_LEN, new_rng());
+    let mut frag_receiver = FragMsgReceiver::new(stream, new_time_tick(&handle));
+    set_up(&mut frag_sender, &mut frag_receiver);
+
+    let send_all = frag_sender.send_all(stream::iter_ok(messages.to_vec()));
+    handle.spawn(send_all.then(|_| Ok(())));
+
+    match core.run(frag_receiver.collect()) {
+        Ok(incoming_messages) => incoming_messages,
+        Err(_) => panic!("Receiving failed"),
+    }
+}
+
 
 #[test]
 fn basic_test_sender_receiver() {
@@ -73,3 +117,127 @@ fn basic_test_sender_receiver() {
 
 }
 
+#[test]
+fn large_messages_sender_receiver() {
+    let mut core = Core::new().unwrap();
+    let (sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+
+    // Messages larger than max_message(MAX_DGRAM_LEN) are split into a few blocks:
+    let max_msg_len = max_message(MAX_DGRAM_LEN).unwrap();
+    let messages: Vec<(Vec<u8>, u32)> = vec![
+        (vec![0x11; max_msg_len + 1], 0x12345678),
+        (b"A small message in the middle".to_vec(), 0x87654321),
+        ((0 .. 2 * max_msg_len).map(|i| i as u8).collect::<Vec<u8>>(), 0xabcdef12),
+    ];
+
+    let incoming_messages = send_receive(&mut core, sink, stream, &messages, |_, _| {});
+    assert_eq!(incoming_messages, messages);
+}
+
+#[test]
+fn pooled_sender_receiver() {
+    let mut core = Core::new().unwrap();
+    let (sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    let buffer_pool = BufferPool::new(64);
+
+    let messages = (0 .. 50u32)
+        .map(|i| (format!("This is message number {}", i).into_bytes(), i))
+        .collect::<Vec<(Vec<u8>, u32)>>();
+
+    let incoming_messages = send_receive(&mut core, sink, stream, &messages, |frag_sender, frag_receiver| {
+        frag_sender.set_buffer_pool(buffer_pool.clone());
+        frag_receiver.set_buffer_pool(buffer_pool.clone());
+    });
+    assert_eq!(incoming_messages, messages);
+
+    // Datagram buffers were passed back from the receiver to the sender:
+    let stats = buffer_pool.stats();
+    assert!(stats.reused > 0);
+    assert!(stats.allocated < stats.reused);
+}
+
+#[test]
+fn parallel_sender_receiver() {
+    let mut core = Core::new().unwrap();
+    let (sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    let cpu_pool = CpuPool::new(4);
+
+    // Interleave small and large messages, to make sure the order is kept:
+    let messages = (0 .. 20u32)
+        .map(|i| match i % 2 {
+            0 => (vec![i as u8; 200], 0x12345678),
+            _ => (vec![i as u8; 10], 0x12345678),
+        })
+        .collect::<Vec<(Vec<u8>, u32)>>();
+
+    // Messages of at least 100 bytes are encoded and decoded on the thread pool:
+    let incoming_messages = send_receive(&mut core, sink, stream, &messages, |frag_sender, frag_receiver| {
+        frag_sender.set_cpu_pool(cpu_pool.clone(), 100, 8);
+        frag_receiver.set_cpu_pool(cpu_pool.clone(), 100, 8);
+    });
+    assert_eq!(incoming_messages, messages);
+}
+
+#[test]
+fn lossy_sender_receiver() {
+    let seed: &[_] = &[1,2,3,4,5];
+    let mut core = Core::new().unwrap();
+
+    let config = SimConfig {
+        loss: LossModel::Bernoulli { p: 0.1 },
+        duplicate: 0.05,
+        reorder_window: 4,
+        corrupt: 0.02,
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(2),
+    };
+    let (sink, stream) = sim_channel::<u32>(config, seed, &core.handle());
+
+    let messages = (0 .. 30u32)
+        .map(|i| (format!("This is message number {}, which is long enough to be split \
+                           into many datagrams", i).into_bytes(), i))
+        .collect::<Vec<(Vec<u8>, u32)>>();
+
+    let mut incoming_messages = send_receive(&mut core, sink, stream, &messages, |_, _| {});
+
+    // Which datagrams are lost depends only on the seed. With this seed no message loses more
+    // than b - 1 out of its 2b - 1 datagrams, so every message arrives exactly once, without
+    // changes, though possibly out of order:
+    incoming_messages.sort_by_key(|&(_, address)| address);
+    assert_eq!(incoming_messages, messages);
+}
+
+#[test]
+fn multipath_sender_receiver() {
+    let mut core = Core::new().unwrap();
+
+    // The peer 1 is reachable through three paths, at the addresses 1, 2 and 3.
+    // All the datagrams sent over the third path are lost:
+    let (sink, stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    let (other_sink, other_stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    let (lost_sink, lost_stream) = mpsc::channel::<(Vec<u8>, u32)>(0);
+    core.handle().spawn(lost_stream.for_each(|_| Ok(())));
+
+    let mut multipath_sink = MultipathSink::new();
+    let index = multipath_sink.add_sink(sink);
+    let other_index = multipath_sink.add_sink(other_sink);
+    let lost_index = multipath_sink.add_sink(lost_sink);
+    multipath_sink.add_path(1u32, index, 1, 2).unwrap();
+    multipath_sink.add_path(1u32, other_index, 2, 1).unwrap();
+    multipath_sink.add_path(1u32, lost_index, 3, 1).unwrap();
+
+    let messages: Vec<(Vec<u8>, u32)> = (0 .. 8u32)
+        .map(|i| (format!("Message number {} goes over two paths", i).into_bytes(), 1))
+        .collect();
+
+    let incoming_messages = send_receive(&mut core, multipath_sink, stream.select(other_stream), 
+                                         &messages, |_, frag_receiver| {
+        let mut peer_map = PeerMap::new();
+        peer_map.add_address(2u32, 1u32);
+        peer_map.add_address(3u32, 1u32);
+        frag_receiver.set_peer_map(peer_map);
+    });
+
+    // A quarter of the shares is lost, but every message still arrives:
+    assert_eq!(incoming_messages, messages);
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "tokio1")]
+
+extern crate rand;
+extern crate futures_03;
+extern crate tokio_1;
+extern crate fragmentos;
+
+use std::net;
+use std::time::{Duration};
+
+use rand::StdRng;
+use futures_03::{stream, StreamExt};
+use futures_03::future::join;
+use tokio_1::net::UdpSocket;
+use tokio_1::runtime;
+use tokio_1::time::interval;
+
+use fragmentos::tokio1::{FragMsgReceiver, FragMsgSender, udp_dgrams};
+
+// Maximum size of UDP datagram we are willing to send:
+const MAX_DGRAM_LEN: usize = 512;
+// Maximum size of a message the receiver accepts:
+const MAX_TOTAL_MESSAGE: usize = 1 << 16;
+
+
+fn bind_local() -> UdpSocket {
+    let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    UdpSocket::from_std(socket).unwrap()
+}
+
+#[test]
+fn udp_sender_receiver() {
+    let seed: &[_] = &[1,2,3,4,5];
+    let rng: StdRng = rand::SeedableRng::from_seed(seed);
+
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let _guard = rt.enter();
+
+    let client_socket = bind_local();
+    let server_socket = bind_local();
+    let server_addr = server_socket.local_addr().unwrap();
+
+    let (client_sink, _client_stream) = udp_dgrams(client_socket);
+    let (_server_sink, server_stream) = udp_dgrams(server_socket);
+
+    let frag_sender = FragMsgSender::new(client_sink, MAX_DGRAM_LEN, rng);
+    let frag_receiver = FragMsgReceiver::new(server_stream, interval(Duration::from_secs(1)),
+                                             MAX_TOTAL_MESSAGE);
+
+    let messages: Vec<Vec<u8>> = vec![
+        b"How are you today?".to_vec(),
+        (0 .. 2000u32).map(|i| i as u8).collect::<Vec<u8>>(),
+        b"".to_vec(),
+    ];
+
+    let source_stream = stream::iter(messages.clone()
+        .into_iter()
+        .map(|msg| Ok((msg, server_addr))));
+
+    let (send_res, received) = rt.block_on(join(
+            source_stream.forward(frag_sender),
+            frag_receiver.take(messages.len()).collect::<Vec<_>>()));
+    send_res.unwrap();
+
+    let received = received.into_iter()
+        .map(|res| res.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(received, messages);
+}